@@ -6,6 +6,8 @@ use crossterm::terminal;
 
 use crate::command::Command;
 use crate::database::{self, Database};
+use crate::dependency::format_cycle;
+use crate::formulas::{Iteration, NumericMode};
 use crate::home::Home;
 use crate::spreadsheet::{self, Spreadsheet};
 
@@ -62,19 +64,102 @@ impl AppState {
         self.clear_screen(out);
     }
 
-    pub fn handle_command(&mut self) {
-        let command: &str = &self.command.input;
+    pub fn handle_command<W: Write>(&mut self, out: &mut W) {
+        let input = self.command.input.clone();
+        let command: Vec<&str> = input.split_whitespace().collect();
 
-        match command {
-            ":w" => {
+        match command.as_slice() {
+            [":w"] => {
                 let _result = self
                     .database
                     .write_all_cells(&self.spreadsheet.id, self.spreadsheet.cells.clone())
                     .unwrap();
             }
+            [":set", "decimal"] => {
+                self.spreadsheet.numeric_mode = NumericMode::Decimal;
+                self.spreadsheet.recalculate_all();
+            }
+            [":set", "float"] => {
+                self.spreadsheet.numeric_mode = NumericMode::Float;
+                self.spreadsheet.recalculate_all();
+            }
+            // :set iterative [max iterations] [epsilon]
+            [":set", "iterative", settings @ ..] => {
+                let mut iteration = Iteration::default();
+                if let Some(max_iterations) = settings.first().and_then(|n| n.parse().ok()) {
+                    iteration.max_iterations = max_iterations;
+                }
+                if let Some(epsilon) = settings.get(1).and_then(|n| n.parse().ok()) {
+                    iteration.epsilon = epsilon;
+                }
+                self.spreadsheet.iteration = Some(iteration);
+                self.spreadsheet.recalculate_all();
+            }
+            [":set", "noiterative"] => {
+                self.spreadsheet.iteration = None;
+                self.spreadsheet.recalculate_all();
+            }
+            [":goto", reference] => self.spreadsheet.goto(out, reference),
+            [":cycles"] => {
+                let cycles = &self.spreadsheet.cycles;
+                self.spreadsheet.status_message = Some(if cycles.is_empty() {
+                    "No circular references".to_string()
+                } else {
+                    cycles
+                        .iter()
+                        .map(|cycle| format_cycle(cycle))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                });
+            }
+            // :fill down|right [count], copies the active cell into the next cells
+            [":fill", direction, count @ ..] if count.len() <= 1 => {
+                let count = count.first().and_then(|n| n.parse().ok()).unwrap_or(1);
+                match *direction {
+                    "down" => self.spreadsheet.fill(count, 0),
+                    "right" => self.spreadsheet.fill(0, count),
+                    _ => {
+                        self.spreadsheet.status_message =
+                            Some(format!("Can't fill {}, use down or right", direction))
+                    }
+                }
+            }
+            [":trace"] => self.spreadsheet.tracing = !self.spreadsheet.tracing,
+            [":name", "define", name, definition @ ..] if !definition.is_empty() => {
+                let result = self.spreadsheet.define_name(name, &definition.join(" "));
+                self.report(result, format!("Defined {}", name));
+            }
+            [":name", "rename", old, new] => {
+                let result = self.spreadsheet.rename_name(old, new);
+                self.report(result, format!("Renamed {} to {}", old, new));
+            }
+            [":name", "delete", name] => {
+                let result = self.spreadsheet.delete_name(name);
+                self.report(result, format!("Deleted {}", name));
+            }
+            [":name", "list"] | [":names"] => {
+                let names = self
+                    .spreadsheet
+                    .names
+                    .list()
+                    .map(|name| format!("{} = {}", name.name, name.definition))
+                    .collect::<Vec<String>>();
+                self.spreadsheet.status_message = Some(if names.is_empty() {
+                    "No names defined".to_string()
+                } else {
+                    names.join(", ")
+                });
+            }
             _ => {}
         }
     }
+
+    fn report(&mut self, result: Result<(), String>, success: String) {
+        self.spreadsheet.status_message = Some(match result {
+            Ok(()) => success,
+            Err(error) => error,
+        });
+    }
 }
 #[derive(PartialEq)]
 pub enum AppMode {
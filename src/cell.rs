@@ -1,8 +1,12 @@
+use std::fmt;
 use std::io::Write;
+use std::rc::Rc;
 
 use crossterm::style::Color;
 
-use crate::spreadsheet::{self, Spreadsheet};
+use crate::date::{format_date, parse_date};
+use crate::formulas::{round_to, FormulaError, Lambda};
+use crate::spreadsheet::Spreadsheet;
 
 pub struct ActiveCell {
     pub row: usize,
@@ -36,10 +40,164 @@ impl ActiveCell {
 #[derive(Clone)]
 pub struct Cell {
     pub value: String,
+    pub computed: CellValue,
     pub color: Color,
     pub formula: bool,
+    // Rows and columns of the array a formula returned, see spill.rs
+    pub spill: Option<(usize, usize)>,
 }
 
-// impl Cell {
-//     fn formula(&self, )
-// }
+impl Cell {
+    pub fn empty() -> Self {
+        Self {
+            value: String::new(),
+            computed: CellValue::Empty,
+            color: Color::Red,
+            formula: false,
+            spill: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CellValue {
+    Empty,
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Error(FormulaError),
+    Date(f64),
+    // Rows of values returned by array formulas like SEQUENCE
+    Array(Vec<Vec<CellValue>>),
+    // Function made by LAMBDA, it can be called but not shown in a cell
+    Lambda(Rc<Lambda>),
+}
+
+impl CellValue {
+    // Typed value of a literal (non formula) input
+    pub fn parse(input: &str) -> Self {
+        // Long input gets wrapped with newlines while typing
+        let input = input.replace('\n', "");
+        let trimmed = input.trim();
+
+        if trimmed.is_empty() {
+            return CellValue::Empty;
+        }
+        if trimmed.eq_ignore_ascii_case("TRUE") {
+            return CellValue::Bool(true);
+        }
+        if trimmed.eq_ignore_ascii_case("FALSE") {
+            return CellValue::Bool(false);
+        }
+        match trimmed.parse::<f64>() {
+            Ok(n) if n.is_finite() => CellValue::Number(n),
+            _ => match parse_date(trimmed) {
+                Some(serial) => CellValue::Date(serial),
+                None => CellValue::Text(input),
+            },
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            CellValue::Empty => Some(0.0),
+            CellValue::Number(n) | CellValue::Date(n) => Some(*n),
+            CellValue::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+            CellValue::Text(text) => text.trim().parse().ok().or_else(|| parse_date(text)),
+            CellValue::Error(_) | CellValue::Array(_) | CellValue::Lambda(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for CellValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CellValue::Empty => Ok(()),
+            CellValue::Number(n) => write!(f, "{}", format_general(*n)),
+            CellValue::Date(serial) => write!(f, "{}", format_date(*serial)),
+            CellValue::Text(text) => write!(f, "{}", text),
+            CellValue::Bool(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            CellValue::Error(error) => write!(f, "{}", error),
+            // Shown in the cell the array spills from
+            CellValue::Array(rows) => match rows.first().and_then(|row| row.first()) {
+                Some(value) => write!(f, "{}", value),
+                None => Ok(()),
+            },
+            CellValue::Lambda(_) => write!(f, "{}", FormulaError::Calc),
+        }
+    }
+}
+
+// Like Excel, show at most 15 significant digits so 0.1 + 0.2 displays as 0.3
+pub fn format_general(n: f64) -> String {
+    let rounded: f64 = format!("{:.14e}", n).parse().unwrap_or(n);
+    rounded.to_string()
+}
+
+// Formats a number with an Excel style pattern such as "0.00", "#,##0",
+// "0%" or "$#,##0.00". Cells render with "General", TEXT takes any pattern
+pub fn format_number(n: f64, pattern: &str) -> String {
+    if pattern.is_empty() || pattern.eq_ignore_ascii_case("General") {
+        return format_general(n);
+    }
+
+    let is_digit = |c: char| c == '0' || c == '#';
+    let (Some(start), Some(end)) = (pattern.find(is_digit), pattern.rfind(is_digit)) else {
+        return pattern.to_string();
+    };
+    let prefix = pattern[..start].replace('"', "");
+    let suffix = pattern[end + 1..].replace('"', "");
+    let (int_pattern, frac_pattern) = pattern[start..=end]
+        .split_once('.')
+        .unwrap_or((&pattern[start..=end], ""));
+
+    let n = if prefix.contains('%') || suffix.contains('%') {
+        n * 100.0
+    } else {
+        n
+    };
+    let decimals = frac_pattern.chars().filter(|c| is_digit(*c)).count();
+    let min_decimals = frac_pattern.chars().filter(|c| *c == '0').count();
+    let min_digits = int_pattern.chars().filter(|c| *c == '0').count();
+
+    let rounded = round_to(n.abs(), decimals as i32);
+    let text = format!("{:.*}", decimals, rounded);
+    let (int_digits, frac_digits) = text.split_once('.').unwrap_or((&text, ""));
+
+    // # placeholders only show significant digits
+    let mut frac = frac_digits.to_string();
+    while frac.len() > min_decimals && frac.ends_with('0') {
+        frac.pop();
+    }
+    let mut int = int_digits.trim_start_matches('0').to_string();
+    while int.len() < min_digits {
+        int.insert(0, '0');
+    }
+    if int_pattern.contains(',') {
+        int = group_thousands(&int);
+    }
+
+    let mut result = String::new();
+    if n < 0.0 && rounded != 0.0 {
+        result.push('-');
+    }
+    result.push_str(&prefix);
+    result.push_str(&int);
+    if !frac.is_empty() {
+        result.push('.');
+        result.push_str(&frac);
+    }
+    result.push_str(&suffix);
+    result
+}
+
+fn group_thousands(digits: &str) -> String {
+    let mut grouped = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
@@ -2,7 +2,7 @@ use std::collections::VecDeque;
 
 use redis::Commands;
 
-use crate::{cell::Cell, home::Sheet};
+use crate::{cell::Cell, home::Sheet, names::Name};
 
 pub struct Database {
     conn: Option<redis::Connection>,
@@ -20,6 +20,15 @@ impl Database {
         })
     }
 
+    // Connects on first use, for tests that never get that far
+    #[cfg(test)]
+    pub fn offline() -> Self {
+        Self {
+            client: redis::Client::open("redis://127.0.0.1/").unwrap(),
+            conn: None,
+        }
+    }
+
     pub fn get_connection(&mut self) -> redis::RedisResult<&mut redis::Connection> {
         if self.conn.is_none() {
             self.conn = Some(self.client.get_connection().unwrap());
@@ -65,6 +74,69 @@ impl Database {
         Ok(result)
     }
 
+    // Names are stored as two hashes, name to definition and name to the
+    // sheet it was defined on
+    pub fn get_names(&mut self) -> redis::RedisResult<Vec<Name>> {
+        let conn = self.get_connection().unwrap();
+        let definitions: Vec<(String, String)> = conn.hgetall("names")?;
+        let sheets: Vec<(String, String)> = conn.hgetall("name_sheets")?;
+
+        Ok(definitions
+            .into_iter()
+            .map(|(name, definition)| {
+                let sheet = sheets
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, sheet)| sheet.clone())
+                    .unwrap_or_default();
+                Name {
+                    name,
+                    sheet,
+                    definition,
+                }
+            })
+            .collect())
+    }
+
+    pub fn write_name(&mut self, name: &Name) -> redis::RedisResult<()> {
+        let mut conn = self.get_connection().unwrap();
+        let mut pipe = redis::Pipeline::new();
+        pipe.hset("names", &name.name, &name.definition);
+        pipe.hset("name_sheets", &name.name, &name.sheet);
+        pipe.exec(&mut conn)
+    }
+
+    pub fn delete_name(&mut self, name: &str) -> redis::RedisResult<()> {
+        let mut conn = self.get_connection().unwrap();
+        let mut pipe = redis::Pipeline::new();
+        pipe.hdel("names", name);
+        pipe.hdel("name_sheets", name);
+        pipe.exec(&mut conn)
+    }
+
+    // Replaces the old name with the renamed names and saves the formulas
+    // using them, given as sheet, row:col key and formula, all at once
+    pub fn rename_name(
+        &mut self,
+        old: &str,
+        names: &[Name],
+        formulas: &[(String, String, String)],
+    ) -> redis::RedisResult<()> {
+        let mut conn = self.get_connection().unwrap();
+        let mut pipe = redis::Pipeline::new();
+        pipe.atomic();
+        pipe.hdel("names", old);
+        pipe.hdel("name_sheets", old);
+        for name in names {
+            pipe.hset("names", &name.name, &name.definition);
+            pipe.hset("name_sheets", &name.name, &name.sheet);
+        }
+        for (sheet, key, formula) in formulas {
+            pipe.hset(format!("spreadsheet:{}", sheet), key, formula);
+        }
+        pipe.exec(&mut conn)
+    }
+
     pub fn write_all_cells(
         &mut self,
         sheet_id: &str,
@@ -73,13 +145,17 @@ impl Database {
         let mut conn = self.get_connection().unwrap();
 
         let mut pipe = redis::Pipeline::new();
+        let sheet_id = format!("spreadsheet:{}", sheet_id);
         for row in 0..cells.len() {
             for col in 0..cells[0].len() {
-                let value = cells[row][col].value.clone();
-                if value.len() > 0 {
-                    let key = format!("{}:{}", row, col);
-                    let sheet_id = format!("spreadsheet:{}", sheet_id);
-                    pipe.hset(sheet_id, key, value);
+                let cell = &cells[row][col];
+                let key = format!("{}:{}", row, col);
+                if cell.value.is_empty() {
+                    // Cleared cells and cells an array spilled into are
+                    // removed instead of stored as blanks
+                    pipe.hdel(&sheet_id, key);
+                } else {
+                    pipe.hset(&sheet_id, key, cell.value.clone());
                 }
             }
         }
@@ -0,0 +1,327 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Dates are serial numbers like in Excel's 1900 date system: 1 is 1900-01-01,
+// the whole part counts days and the fraction is the time of day.
+// Serial 60 is the 1900-02-29 that never existed, Excel keeps it so
+// everything after it lines up with Lotus 1-2-3
+const LEAP_BUG_SERIAL: i64 = 60;
+// Serial of 1970-01-01
+const UNIX_EPOCH_SERIAL: f64 = 25569.0;
+// Serial of 9999-12-31, the last date there is like in Excel
+pub const MAX_SERIAL: f64 = 2958465.0;
+// Years and days DATE arguments can go up to before the serial is checked,
+// far past 9999 but also far from overflowing the day arithmetic
+const MAX_YEARS: i64 = 100_000;
+const MAX_DAYS: i64 = MAX_YEARS * 366;
+
+// Source of the current date and time for NOW and TODAY
+pub trait Clock {
+    fn now(&self) -> f64;
+}
+
+// Current UTC time from the operating system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs_f64())
+            .unwrap_or(0.0);
+        UNIX_EPOCH_SERIAL + seconds / 86400.0
+    }
+}
+
+// Always the same serial, so NOW and TODAY give the same value in tests
+#[cfg(test)]
+pub struct FixedClock(pub f64);
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> f64 {
+        self.0
+    }
+}
+
+// Days between 1970-01-01 and a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day as u32)
+}
+
+// Serial of a date, months and days outside their range roll over like
+// in DATE(2024, 14, 0). None before 1900-01-01 and after 9999-12-31
+pub fn serial_from_date(year: i64, month: i64, day: i64) -> Option<f64> {
+    let month = month.checked_sub(1)?;
+    let year = year.checked_add(month.div_euclid(12))?;
+    let month = month.rem_euclid(12) + 1;
+    if !(-MAX_YEARS..=MAX_YEARS).contains(&year) || !(-MAX_DAYS..=MAX_DAYS).contains(&day) {
+        return None;
+    }
+
+    let serial = days_from_civil(year, month, 1) + day - 1 - days_from_civil(1899, 12, 30);
+    // Dates before March 1900 come before the phantom leap day
+    let serial = if serial <= LEAP_BUG_SERIAL {
+        serial - 1
+    } else {
+        serial
+    };
+    (1..=MAX_SERIAL as i64)
+        .contains(&serial)
+        .then_some(serial as f64)
+}
+
+// Year, month and day of a serial, None for negative serials and ones after 9999
+pub fn date_from_serial(serial: f64) -> Option<(i64, u32, u32)> {
+    if !(0.0..MAX_SERIAL + 1.0).contains(&serial) {
+        return None;
+    }
+
+    let days = serial.floor() as i64;
+    let epoch = days_from_civil(1899, 12, 30);
+    Some(match days {
+        0 => (1900, 1, 0),
+        LEAP_BUG_SERIAL => (1900, 2, 29),
+        days if days < LEAP_BUG_SERIAL => civil_from_days(epoch + days + 1),
+        days => civil_from_days(epoch + days),
+    })
+}
+
+pub fn days_in_month(year: i64, month: u32) -> u32 {
+    let next = days_from_civil(year + month as i64 / 12, month as i64 % 12 + 1, 1);
+    (next - days_from_civil(year, month as i64, 1)) as u32
+}
+
+// 0 for Sunday through 6 for Saturday
+pub fn day_of_week(serial: f64) -> u32 {
+    // Serial 1 was a Sunday in Excel's calendar
+    ((serial.floor() as i64 - 1).rem_euclid(7)) as u32
+}
+
+// ISO dates typed into a cell, "2024-01-15", "2024-01-15 13:45" or "2024-01-15T13:45:30"
+pub fn parse_date(input: &str) -> Option<f64> {
+    let input = input.trim();
+    let (date, time) = match input.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time.trim())),
+        None => (input, None),
+    };
+
+    let parts: Vec<&str> = date.split('-').collect();
+    let [year, month, day] = parts.as_slice() else {
+        return None;
+    };
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let year: i64 = year.parse().ok()?;
+    let month: u32 = month.parse().ok()?;
+    let day: u32 = day.parse().ok()?;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    let serial = serial_from_date(year, month as i64, day as i64)?;
+    match time {
+        Some(time) => Some(serial + parse_time(time)?),
+        None => Some(serial),
+    }
+}
+
+// Fraction of a day from "HH:MM" or "HH:MM:SS"
+fn parse_time(input: &str) -> Option<f64> {
+    let parts: Vec<u32> = input
+        .split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [hours, minutes] => (*hours, *minutes, 0),
+        [hours, minutes, seconds] => (*hours, *minutes, *seconds),
+        _ => return None,
+    };
+    if hours > 23 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+
+    Some((hours * 3600 + minutes * 60 + seconds) as f64 / 86400.0)
+}
+
+// Date, time of day in seconds and weekday of a serial. The time is
+// rounded to the second first, so 23:59:59.6 is midnight of the next day
+fn date_and_time(serial: f64) -> Option<((i64, u32, u32), u32, u32)> {
+    let seconds = (serial * 86400.0).round();
+    let days = (seconds / 86400.0).floor();
+    let date = date_from_serial(days)?;
+    Some((date, (seconds - days * 86400.0) as u32, day_of_week(days)))
+}
+
+// ISO text of a serial, with the time when it isn't midnight
+pub fn format_date(serial: f64) -> String {
+    let Some(((year, month, day), seconds, _)) = date_and_time(serial) else {
+        return "#NUM!".to_string();
+    };
+    let date = format!("{:04}-{:02}-{:02}", year, month, day);
+
+    if seconds == 0 {
+        return date;
+    }
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        date,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+enum DatePart {
+    Text(String),
+    // A run of one of the letters y, m, d, h or s and its length
+    Code(char, usize),
+    AmPm,
+}
+
+// Splits a pattern like "d mmm yyyy" into codes and the text between them,
+// quoted text and characters after a backslash are kept as they are
+fn date_parts(pattern: &str) -> Vec<DatePart> {
+    let mut parts = Vec::new();
+    let mut rest = pattern;
+
+    while let Some(c) = rest.chars().next() {
+        let lower = c.to_ascii_lowercase();
+        if rest
+            .get(..5)
+            .is_some_and(|code| code.eq_ignore_ascii_case("AM/PM"))
+        {
+            parts.push(DatePart::AmPm);
+            rest = &rest[5..];
+        } else if "ymdhs".contains(lower) {
+            let len = rest
+                .chars()
+                .take_while(|c| c.to_ascii_lowercase() == lower)
+                .count();
+            parts.push(DatePart::Code(lower, len));
+            rest = &rest[len..];
+        } else if c == '"' {
+            let text = rest[1..].split('"').next().unwrap();
+            parts.push(DatePart::Text(text.to_string()));
+            let rest_after = &rest[1 + text.len()..];
+            rest = rest_after.strip_prefix('"').unwrap_or(rest_after);
+        } else if c == '\\' {
+            let escaped = rest[1..].chars().next().map_or(0, char::len_utf8);
+            parts.push(DatePart::Text(rest[1..1 + escaped].to_string()));
+            rest = &rest[1 + escaped..];
+        } else {
+            parts.push(DatePart::Text(c.to_string()));
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    parts
+}
+
+// Whether TEXT should format a value as a date or time with this pattern
+pub fn is_date_pattern(pattern: &str) -> bool {
+    date_parts(pattern)
+        .iter()
+        .any(|part| !matches!(part, DatePart::Text(_)))
+}
+
+// Serial formatted with date and time codes, like TEXT(A1, "d mmm yyyy h:mm AM/PM").
+// m is minutes right after an hour or before seconds and the month otherwise.
+// None for serials that aren't dates
+pub fn format_date_pattern(serial: f64, pattern: &str) -> Option<String> {
+    let ((year, month, day), seconds, weekday) = date_and_time(serial)?;
+    let parts = date_parts(pattern);
+    let twelve_hour = parts.iter().any(|part| matches!(part, DatePart::AmPm));
+    let hour = seconds / 3600;
+    let code_at = |index: usize| match parts.get(index) {
+        Some(DatePart::Code(code, _)) => Some(*code),
+        _ => None,
+    };
+    let number = |n: u32, len: usize| match len {
+        1 => n.to_string(),
+        _ => format!("{:02}", n),
+    };
+
+    let mut result = String::new();
+    for (index, part) in parts.iter().enumerate() {
+        let (code, len) = match part {
+            DatePart::Text(text) => {
+                result.push_str(text);
+                continue;
+            }
+            DatePart::AmPm => {
+                result.push_str(if hour < 12 { "AM" } else { "PM" });
+                continue;
+            }
+            DatePart::Code(code, len) => (*code, *len),
+        };
+        let previous = (0..index).rev().find_map(code_at);
+        let next = (index + 1..parts.len()).find_map(code_at);
+
+        let text = match code {
+            'y' if len <= 2 => format!("{:02}", year.rem_euclid(100)),
+            'y' => format!("{:04}", year),
+            'm' if len <= 2 && (previous == Some('h') || next == Some('s')) => {
+                number(seconds / 60 % 60, len)
+            }
+            'm' if len <= 2 => number(month, len),
+            'm' if len == 3 => MONTHS[month as usize - 1][..3].to_string(),
+            'm' => MONTHS[month as usize - 1].to_string(),
+            'd' if len <= 2 => number(day, len),
+            'd' if len == 3 => WEEKDAYS[weekday as usize][..3].to_string(),
+            'd' => WEEKDAYS[weekday as usize].to_string(),
+            'h' if twelve_hour => number((hour + 11) % 12 + 1, len),
+            'h' => number(hour, len),
+            _ => number(seconds % 60, len),
+        };
+        result.push_str(&text);
+    }
+    Some(result)
+}
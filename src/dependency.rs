@@ -0,0 +1,430 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::reference::cell_name;
+
+pub type Position = (usize, usize);
+
+// Areas with more cells than this, like A:A, aren't indexed cell by cell but
+// checked one by one when looking up the formulas reading a cell
+const MAX_INDEXED_CELLS: usize = 1024;
+
+// Rectangle of cells read by a formula, from the top left to the bottom right
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Area {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Area {
+    pub fn new(a: Position, b: Position) -> Self {
+        Self {
+            start: (a.0.min(b.0), a.1.min(b.1)),
+            end: (a.0.max(b.0), a.1.max(b.1)),
+        }
+    }
+
+    pub fn contains(&self, (row, col): Position) -> bool {
+        (self.start.0..=self.end.0).contains(&row) && (self.start.1..=self.end.1).contains(&col)
+    }
+
+    pub fn intersect(&self, other: &Area) -> Option<Area> {
+        let start = (
+            self.start.0.max(other.start.0),
+            self.start.1.max(other.start.1),
+        );
+        let end = (self.end.0.min(other.end.0), self.end.1.min(other.end.1));
+
+        (start.0 <= end.0 && start.1 <= end.1).then_some(Area { start, end })
+    }
+
+    pub fn rows(&self) -> usize {
+        self.end.0 - self.start.0 + 1
+    }
+
+    pub fn cols(&self) -> usize {
+        self.end.1 - self.start.1 + 1
+    }
+
+    fn cells(self) -> impl Iterator<Item = Position> {
+        (self.start.0..=self.end.0)
+            .flat_map(move |row| (self.start.1..=self.end.1).map(move |col| (row, col)))
+    }
+}
+
+// Tracks which cells every formula reads so an edit only recalculates
+// the formulas that (transitively) depend on the edited cell
+#[derive(Default)]
+pub struct DependencyGraph {
+    precedents: HashMap<Position, Vec<Area>>,
+    // The other way around, formulas reading each cell of the small areas
+    readers: HashMap<Position, HashSet<Position>>,
+    // and the large areas with the formula reading them
+    wide_readers: HashMap<Position, Vec<Area>>,
+    // Every formula, ordered by row then column to find the ones in an area
+    formulas: BTreeSet<Position>,
+    // Formulas recalculated after every edit, like the ones calling NOW
+    volatile: HashSet<Position>,
+    // Other sheets each formula reads, lowercase
+    sheets: HashMap<Position, Vec<String>>,
+}
+
+impl DependencyGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn clear(&mut self) {
+        self.precedents.clear();
+        self.readers.clear();
+        self.wide_readers.clear();
+        self.formulas.clear();
+        self.volatile.clear();
+        self.sheets.clear();
+    }
+
+    pub fn set_precedents(&mut self, cell: Position, areas: Vec<Area>) {
+        self.unlink(cell);
+
+        let (small, wide): (Vec<Area>, Vec<Area>) = areas
+            .iter()
+            .partition(|area| area.rows() * area.cols() <= MAX_INDEXED_CELLS);
+        for precedent in small.into_iter().flat_map(Area::cells) {
+            self.readers.entry(precedent).or_default().insert(cell);
+        }
+        if !wide.is_empty() {
+            self.wide_readers.insert(cell, wide);
+        }
+        self.formulas.insert(cell);
+        self.precedents.insert(cell, areas);
+    }
+
+    pub fn remove(&mut self, cell: Position) {
+        self.unlink(cell);
+        self.formulas.remove(&cell);
+        self.volatile.remove(&cell);
+        self.sheets.remove(&cell);
+    }
+
+    pub fn set_volatile(&mut self, cell: Position, volatile: bool) {
+        if volatile {
+            self.volatile.insert(cell);
+        } else {
+            self.volatile.remove(&cell);
+        }
+    }
+
+    pub fn set_sheets(&mut self, cell: Position, sheets: Vec<String>) {
+        let sheets: Vec<String> = sheets.iter().map(|sheet| sheet.to_lowercase()).collect();
+        if sheets.is_empty() {
+            self.sheets.remove(&cell);
+        } else {
+            self.sheets.insert(cell, sheets);
+        }
+    }
+
+    // Drops the formula from the index of the cells it read
+    fn unlink(&mut self, cell: Position) {
+        let Some(areas) = self.precedents.remove(&cell) else {
+            return;
+        };
+        let small = areas
+            .into_iter()
+            .filter(|area| area.rows() * area.cols() <= MAX_INDEXED_CELLS);
+        for precedent in small.flat_map(Area::cells) {
+            if let Some(readers) = self.readers.get_mut(&precedent) {
+                readers.remove(&cell);
+                if readers.is_empty() {
+                    self.readers.remove(&precedent);
+                }
+            }
+        }
+        self.wide_readers.remove(&cell);
+    }
+
+    // Formulas reading another sheet directly
+    pub fn sheet_dependents(&self, sheet: &str) -> Vec<Position> {
+        let sheet = sheet.to_lowercase();
+        let mut dependents: Vec<Position> = self
+            .sheets
+            .iter()
+            .filter(|(_, sheets)| sheets.contains(&sheet))
+            .map(|(dependent, _)| *dependent)
+            .collect();
+        dependents.sort();
+        dependents
+    }
+
+    // Areas the formula reads
+    pub fn precedents(&self, cell: Position) -> &[Area] {
+        self.precedents.get(&cell).map_or(&[], Vec::as_slice)
+    }
+
+    // Formulas that read the cell directly
+    pub fn dependents(&self, cell: Position) -> Vec<Position> {
+        let wide = self
+            .wide_readers
+            .iter()
+            .filter(|(_, areas)| areas.iter().any(|area| area.contains(cell)))
+            .map(|(dependent, _)| *dependent);
+        let mut dependents: Vec<Position> = self
+            .readers
+            .get(&cell)
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain(wide)
+            .collect();
+        dependents.sort();
+        dependents.dedup();
+        dependents
+    }
+
+    // Formulas inside the area, i.e. the ones a reader of the area has to wait for
+    fn formulas_in(&self, area: &Area) -> Vec<Position> {
+        self.formulas
+            .range(area.start..=area.end)
+            .filter(|cell| area.contains(**cell))
+            .copied()
+            .collect()
+    }
+
+    // Every formula depending on the cell or on a volatile formula, each one
+    // after all its precedents
+    pub fn recalc_order(&self, cell: Position) -> Vec<Position> {
+        let mut order = self.order_from(&[cell]);
+        order.retain(|formula| *formula != cell);
+        order
+    }
+
+    // Formulas reading another sheet that changed and everything depending on them
+    pub fn sheet_recalc_order(&self, sheet: &str) -> Vec<Position> {
+        self.order_from(&self.sheet_dependents(sheet))
+    }
+
+    // The cells, the volatile formulas and their dependents, each one after all its precedents
+    pub fn order_from(&self, cells: &[Position]) -> Vec<Position> {
+        let mut volatile: Vec<Position> = self.volatile.iter().copied().collect();
+        volatile.sort();
+
+        let mut visited = HashSet::new();
+        let mut order = Vec::new();
+        for &cell in cells.iter().chain(&volatile) {
+            if visited.insert(cell) {
+                self.visit_dependents(cell, &mut visited, &mut order);
+            }
+        }
+
+        // Reverse post order is a topological order
+        order.reverse();
+        order
+    }
+
+    fn visit_dependents(
+        &self,
+        cell: Position,
+        visited: &mut HashSet<Position>,
+        order: &mut Vec<Position>,
+    ) {
+        for dependent in self.dependents(cell) {
+            if visited.insert(dependent) {
+                self.visit_dependents(dependent, visited, order);
+            }
+        }
+        order.push(cell);
+    }
+
+    // All formulas of the sheet, each one after all its precedents
+    pub fn full_order(&self) -> Vec<Position> {
+        let mut visited = HashSet::new();
+        let mut order = Vec::new();
+        for &cell in &self.formulas {
+            if visited.insert(cell) {
+                self.visit_precedents(cell, &mut visited, &mut order);
+            }
+        }
+        order
+    }
+
+    fn visit_precedents(
+        &self,
+        cell: Position,
+        visited: &mut HashSet<Position>,
+        order: &mut Vec<Position>,
+    ) {
+        for area in self.precedents.get(&cell).into_iter().flatten() {
+            for precedent in self.formulas_in(area) {
+                if visited.insert(precedent) {
+                    self.visit_precedents(precedent, visited, order);
+                }
+            }
+        }
+        order.push(cell);
+    }
+
+    // Formulas a cell reads that are themselves formulas
+    fn formula_precedents(&self, cell: Position) -> Vec<Position> {
+        let mut precedents: Vec<Position> = self
+            .precedents
+            .get(&cell)
+            .into_iter()
+            .flatten()
+            .flat_map(|area| self.formulas_in(area))
+            .collect();
+        precedents.sort();
+        precedents.dedup();
+        precedents
+    }
+
+    // Circular references as paths that start and end on the same cell,
+    // e.g. [A1, B1, A1] when A1 reads B1 and B1 reads A1
+    pub fn find_cycles(&self) -> Vec<Vec<Position>> {
+        let mut tarjan = Tarjan::default();
+        for &cell in &self.formulas {
+            if !tarjan.index.contains_key(&cell) {
+                tarjan.connect(self, cell);
+            }
+        }
+
+        let mut cycles: Vec<Vec<Position>> = tarjan
+            .components
+            .into_iter()
+            .filter(|component| {
+                component.len() > 1
+                    || self
+                        .formula_precedents(component[0])
+                        .contains(&component[0])
+            })
+            .map(|component| self.cycle_path(&component))
+            .collect();
+        cycles.sort();
+        cycles
+    }
+
+    // Walks from the first cell of a strongly connected component back to itself
+    fn cycle_path(&self, component: &[Position]) -> Vec<Position> {
+        let members: HashSet<Position> = component.iter().copied().collect();
+        let start = *component.iter().min().unwrap();
+
+        let mut path = vec![start];
+        let mut visited = HashSet::from([start]);
+        self.extend_path(start, &members, &mut visited, &mut path);
+        path
+    }
+
+    fn extend_path(
+        &self,
+        cell: Position,
+        members: &HashSet<Position>,
+        visited: &mut HashSet<Position>,
+        path: &mut Vec<Position>,
+    ) -> bool {
+        for next in self.formula_precedents(cell) {
+            if !members.contains(&next) {
+                continue;
+            }
+            if next == path[0] {
+                path.push(next);
+                return true;
+            }
+            if visited.insert(next) {
+                path.push(next);
+                if self.extend_path(next, members, visited, path) {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+        false
+    }
+}
+
+// Tarjan's strongly connected components over the formula -> precedent edges
+#[derive(Default)]
+struct Tarjan {
+    index: HashMap<Position, usize>,
+    low: HashMap<Position, usize>,
+    stack: Vec<Position>,
+    on_stack: HashSet<Position>,
+    components: Vec<Vec<Position>>,
+}
+
+impl Tarjan {
+    fn connect(&mut self, graph: &DependencyGraph, cell: Position) {
+        let index = self.index.len();
+        self.index.insert(cell, index);
+        self.low.insert(cell, index);
+        self.stack.push(cell);
+        self.on_stack.insert(cell);
+
+        for next in graph.formula_precedents(cell) {
+            if !self.index.contains_key(&next) {
+                self.connect(graph, next);
+                let low = self.low[&cell].min(self.low[&next]);
+                self.low.insert(cell, low);
+            } else if self.on_stack.contains(&next) {
+                let low = self.low[&cell].min(self.index[&next]);
+                self.low.insert(cell, low);
+            }
+        }
+
+        if self.low[&cell] == self.index[&cell] {
+            let mut component = Vec::new();
+            while let Some(member) = self.stack.pop() {
+                self.on_stack.remove(&member);
+                component.push(member);
+                if member == cell {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+pub fn format_cycle(cycle: &[Position]) -> String {
+    cycle
+        .iter()
+        .map(|cell| cell_name(*cell))
+        .collect::<Vec<String>>()
+        .join(" → ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dependents_follow_edits() {
+        let mut graph = DependencyGraph::new();
+        // B1 reads A1:A3, C1 reads column A, D1 reads B1
+        graph.set_precedents((0, 1), vec![Area::new((0, 0), (2, 0))]);
+        graph.set_precedents((0, 2), vec![Area::new((0, 0), (1_048_575, 0))]);
+        graph.set_precedents((0, 3), vec![Area::new((0, 1), (0, 1))]);
+
+        assert_eq!(graph.dependents((1, 0)), vec![(0, 1), (0, 2)]);
+        assert_eq!(graph.dependents((5, 0)), vec![(0, 2)]);
+        assert_eq!(graph.recalc_order((1, 0)), vec![(0, 2), (0, 1), (0, 3)]);
+
+        // B1 now reads E1 instead
+        graph.set_precedents((0, 1), vec![Area::new((0, 4), (0, 4))]);
+        assert_eq!(graph.dependents((1, 0)), vec![(0, 2)]);
+        assert_eq!(graph.dependents((0, 4)), vec![(0, 1)]);
+
+        graph.remove((0, 2));
+        assert_eq!(graph.dependents((1, 0)), Vec::<Position>::new());
+        assert_eq!(graph.full_order(), vec![(0, 1), (0, 3)]);
+    }
+
+    #[test]
+    fn cycles() {
+        let mut graph = DependencyGraph::new();
+        graph.set_precedents((0, 0), vec![Area::new((0, 1), (0, 1))]);
+        graph.set_precedents((0, 1), vec![Area::new((0, 0), (3, 0))]);
+        graph.set_precedents((5, 5), vec![Area::new((5, 5), (5, 5))]);
+
+        assert_eq!(
+            graph.find_cycles(),
+            vec![vec![(0, 0), (0, 1), (0, 0)], vec![(5, 5), (5, 5)]]
+        );
+    }
+}
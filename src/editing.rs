@@ -0,0 +1,273 @@
+use std::ops::Range;
+
+use crate::dependency::{Area, Position};
+use crate::reference::{CellRef, RangeRef};
+
+// Helpers for the formula being typed into a cell. They read the text as it
+// is, so they also work on formulas that don't parse yet
+
+// Entry of the picker shown while typing, what it inserts and how it's listed
+pub struct Suggestion {
+    pub insert: String,
+    pub label: String,
+}
+
+// Reference being picked with the arrow keys: where its text starts in the
+// formula, how long it is and the cells it spans from anchor to end
+pub struct ReferencePick {
+    pub start: usize,
+    pub len: usize,
+    pub anchor: Position,
+    pub end: Position,
+}
+
+// Reference in the formula text, sheet is set when it names one
+pub struct ReferenceSpan {
+    pub range: Range<usize>,
+    pub sheet: Option<String>,
+    pub area: Area,
+}
+
+// Whether a reference can go at the cursor, after = ( , or an operator and
+// before the end, ) , or an operator, like the cursor in =SUM(| or =A1+|
+pub fn accepts_reference(formula: &str, cursor: usize) -> bool {
+    let Some(before) = formula.get(..cursor) else {
+        return false;
+    };
+    // Not inside text
+    if !formula.starts_with('=') || before.matches('"').count() % 2 == 1 {
+        return false;
+    }
+
+    let operators = ['+', '-', '*', '/', '^', '&', '=', '<', '>'];
+    let before = before.trim_end();
+    let after = formula[cursor..].trim_start();
+    (before.ends_with(['(', ',']) || before.ends_with(operators))
+        && (after.is_empty() || after.starts_with([')', ',']) || after.starts_with(operators))
+}
+
+// Innermost function call the cursor is in and the argument it's at,
+// e.g. SUMIF and 1 for =SUMIF(A1:A9, | with the cursor at |
+pub fn call_at(formula: &str, cursor: usize) -> Option<(&str, usize)> {
+    let before = formula.get(..cursor)?;
+    // Open parentheses with the name before them, None for grouping ones
+    let mut calls: Vec<(Option<&str>, usize)> = Vec::new();
+    let mut quote = None;
+
+    for (i, c) in before.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => {
+                let start = before[..i]
+                    .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                    .map_or(0, |start| start + 1);
+                let name = &before[start..i];
+                calls.push((name.starts_with(char::is_alphabetic).then_some(name), 0));
+            }
+            (None, ')') => {
+                calls.pop();
+            }
+            (None, ',') => {
+                if let Some((_, arg)) = calls.last_mut() {
+                    *arg += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    calls
+        .into_iter()
+        .rev()
+        .find_map(|(name, arg)| Some((name?, arg)))
+}
+
+// Cell and range references in the formula text, in order
+pub fn reference_spans(formula: &str) -> Vec<ReferenceSpan> {
+    let chars: Vec<(usize, char)> = formula.char_indices().collect();
+    let at = |i: usize| chars.get(i).map(|(_, c)| *c);
+    let offset = |i: usize| chars.get(i).map_or(formula.len(), |(offset, _)| *offset);
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '.' || c == '$';
+    let word_end = |mut i: usize| {
+        while at(i).is_some_and(is_word) {
+            i += 1;
+        }
+        i
+    };
+
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while let Some(c) = at(i) {
+        let start = i;
+        let mut sheet = None;
+
+        if c == '"' {
+            i += 1;
+            while at(i).is_some_and(|c| c != '"') {
+                i += 1;
+            }
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_digit() {
+            i = word_end(i);
+            continue;
+        }
+        if c == '\'' {
+            let end = (i + 1..chars.len()).find(|&j| at(j) == Some('\''));
+            match end {
+                Some(end) if at(end + 1) == Some('!') => {
+                    sheet = Some(formula[offset(i + 1)..offset(end)].to_string());
+                    i = end + 2;
+                }
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            }
+        } else if !(c.is_alphabetic() || c == '_' || c == '$') {
+            i += 1;
+            continue;
+        }
+
+        let mut end = word_end(i);
+        if sheet.is_none() && at(end) == Some('!') {
+            sheet = Some(formula[offset(i)..offset(end)].to_string());
+            i = end + 1;
+            end = word_end(i);
+        }
+        let first = &formula[offset(i)..offset(end)];
+        let mut area =
+            CellRef::parse(first).map(|cell| Area::new(cell.position(), cell.position()));
+        if at(end) == Some(':') {
+            let last_end = word_end(end + 1);
+            let last = &formula[offset(end + 1)..offset(last_end)];
+            if let Some(range) = RangeRef::parse(first, last) {
+                area = Some(range.area());
+                end = last_end;
+            }
+        }
+        if at(end) == Some('#') {
+            end += 1;
+        }
+
+        if let (Some(area), false) = (area, at(end) == Some('(')) {
+            spans.push(ReferenceSpan {
+                range: offset(start)..offset(end),
+                sheet,
+                area,
+            });
+        }
+        i = end.max(start + 1);
+    }
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reference::MAX_ROWS;
+
+    // Formula with | marking the cursor, and the cursor's byte offset
+    fn at_cursor(formula: &str) -> (String, usize) {
+        let cursor = formula.find('|').unwrap();
+        (formula.replacen('|', "", 1), cursor)
+    }
+
+    #[test]
+    fn references_at_the_cursor() {
+        let accepts = |formula: &str| {
+            let (formula, cursor) = at_cursor(formula);
+            accepts_reference(&formula, cursor)
+        };
+
+        assert!(accepts("=|"));
+        assert!(accepts("=SUM(|"));
+        assert!(accepts("=SUM(|)"));
+        assert!(accepts("=SUM(A1, |"));
+        assert!(accepts("=A1+|"));
+        assert!(accepts("=A1 >= |"));
+        assert!(accepts("=IF(A1>0, SUM(|), 1)"));
+        assert!(accepts("=IF(A1>0, SUM(B1), |)"));
+        assert!(accepts("=SUM(|,B1)"));
+
+        assert!(!accepts("=SUM(A1|"));
+        assert!(!accepts("=A1+|B1"));
+        assert!(!accepts("=SUM|("));
+        assert!(!accepts("SUM(|"));
+        // Inside text there's nothing to pick
+        assert!(!accepts("=\"(|\""));
+        assert!(!accepts("=CONCAT(\"a,|\", B1)"));
+        assert!(!accepts_reference("=SUM(", 9));
+    }
+
+    #[test]
+    fn calls_at_the_cursor() {
+        let call = |formula: &str| {
+            let (formula, cursor) = at_cursor(formula);
+            call_at(&formula, cursor).map(|(name, arg)| (name.to_string(), arg))
+        };
+        let expect = |name: &str, arg: usize| Some((name.to_string(), arg));
+
+        assert_eq!(call("=SUMIF(|"), expect("SUMIF", 0));
+        assert_eq!(call("=SUMIF(A1:A9, |"), expect("SUMIF", 1));
+        assert_eq!(call("=SUMIF(A1:A9, \">5\", |)"), expect("SUMIF", 2));
+        // The innermost call, back to the outer one after it closes
+        assert_eq!(call("=IF(A1, SUM(B1, |"), expect("SUM", 1));
+        assert_eq!(call("=IF(A1, SUM(B1, C1), |"), expect("IF", 2));
+        assert_eq!(call("=IF(A1, SUM(B1, C1)|"), expect("IF", 1));
+        assert_eq!(call("=SUM((A1+B1)*2, (3+|"), expect("SUM", 1));
+        // Commas and parentheses in text and sheet names don't count
+        assert_eq!(call("=LEN(\"a,b(|"), expect("LEN", 0));
+        assert_eq!(call("=LEN(\"a,(b\", |"), expect("LEN", 1));
+        assert_eq!(call("=SUM('Q1, (x)'!A1, |"), expect("SUM", 1));
+        assert_eq!(call("=Fact(|"), expect("Fact", 0));
+
+        assert_eq!(call("=A1+|B1"), None);
+        assert_eq!(call("=(A1+|"), None);
+        assert_eq!(call("=SUM(A1)+|"), None);
+        assert_eq!(call_at("=SUM(", 9), None);
+    }
+
+    #[test]
+    fn spans() {
+        let formula = "=SUM(A1:B2, c3)+'My Sheet'!D4:E5*Sheet2!$F$6&\"A1\"&G7#";
+        let spans = reference_spans(formula);
+        let spans: Vec<(&str, Option<&str>, Area)> = spans
+            .iter()
+            .map(|span| {
+                (
+                    &formula[span.range.clone()],
+                    span.sheet.as_deref(),
+                    span.area,
+                )
+            })
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                ("A1:B2", None, Area::new((0, 0), (1, 1))),
+                ("c3", None, Area::new((2, 2), (2, 2))),
+                (
+                    "'My Sheet'!D4:E5",
+                    Some("My Sheet"),
+                    Area::new((3, 3), (4, 4))
+                ),
+                ("Sheet2!$F$6", Some("Sheet2"), Area::new((5, 5), (5, 5))),
+                ("G7#", None, Area::new((6, 6), (6, 6))),
+            ]
+        );
+
+        let areas = |formula: &str| -> Vec<Area> {
+            reference_spans(formula)
+                .iter()
+                .map(|span| span.area)
+                .collect()
+        };
+        assert_eq!(areas("=SUM(B:B)"), [Area::new((0, 1), (MAX_ROWS - 1, 1))]);
+        // Functions, names and numbers aren't references
+        assert_eq!(areas("=LOG10(100)+Revenue+1.5E3"), []);
+    }
+}
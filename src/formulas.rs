@@ -1,150 +1,5054 @@
-use crate::spreadsheet::{self, Spreadsheet};
+use rust_decimal::prelude::{Decimal, FromPrimitive, RoundingStrategy, ToPrimitive};
+use unicode_segmentation::UnicodeSegmentation;
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::cell::{format_number, Cell, CellValue};
+use crate::date::{
+    date_from_serial, day_of_week, days_in_month, format_date_pattern, is_date_pattern,
+    serial_from_date, Clock, SystemClock, MAX_SERIAL,
+};
+use crate::dependency::Area;
+use crate::functions::{ArgKind, Arity, Builtin, FunctionRegistry};
+use crate::names::{is_valid_name, Name, NameManager};
+use crate::reference::{CellRef, RangeRef};
+use crate::sheets::Workbook;
+use crate::spill;
+use crate::spreadsheet::Spreadsheet;
 
 pub trait FormulaHandler {
     fn is_formula(&mut self, value: &str, row: usize, col: usize) -> Option<bool>;
 
-    fn enter_formula(&mut self, row: usize, col: usize) -> Option<String>;
+    fn enter_formula(&mut self, row: usize, col: usize) -> CellValue;
+
+    fn parse_formula(&self, value: &str) -> Option<Expr>;
+}
+
+impl FormulaHandler for Spreadsheet {
+    fn enter_formula(&mut self, row: usize, col: usize) -> CellValue {
+        let value = self.cells[row][col].value.clone();
+
+        // Text that doesn't parse as a formula is treated like an unknown name
+        let result = match self.parse_formula(&value) {
+            Some(expr) => {
+                self.track_formula((row, col), Some(&expr));
+                self.load_sheets(&expr, &self.id.clone());
+                Evaluator::new(&self.cells, self.numeric_mode)
+                    .with_clock(self.clock.as_ref())
+                    .with_workbook(self.workbook())
+                    .with_functions(&self.functions)
+                    .with_names(&self.names)
+                    .eval(&expr)
+            }
+            None => {
+                self.track_formula((row, col), None);
+                CellValue::Error(FormulaError::Name)
+            }
+        };
+
+        self.cells[row][col].formula = true;
+        spill::place(&mut self.cells, (row, col), result)
+    }
 
-    fn parse_range(&self, value: &str) -> Option<String>;
+    fn is_formula(&mut self, value: &str, row: usize, col: usize) -> Option<bool> {
+        self.parse_formula(value)?;
 
-    fn convert_range(&self, range: &str) -> Option<Vec<(usize, usize)>>;
+        Some(true)
+    }
 
-    fn parse_operation(&self, value: &str) -> Option<FormulaType>;
+    fn parse_formula(&self, value: &str) -> Option<Expr> {
+        let body = value.strip_prefix("=")?;
 
-    fn evaluate(&self, operation: FormulaType, range: Vec<(usize, usize)>) -> Result<String, ()>;
+        let tokens = tokenize(body)?;
+        Parser::new(tokens).with_names(&self.names).parse()
+    }
+}
 
-    fn get_cords_from_ref(&self, range_str: &str) -> Option<(usize, usize)>;
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Number(f64),
+    Text(String),
+    Ident(String),
+    Colon,
+    Comma,
+    LParen,
+    RParen,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Caret,
+    Percent,
+    Ampersand,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    // Whitespace matters between two references, where it intersects them
+    Space,
+    // Sheet name before a reference, Sheet2! or 'Q1 Budget'!
+    Sheet(String),
+    // After a cell, the array spilled from it
+    Hash,
+    // Error literal like #REF!, left where a copied reference fell off the sheet
+    Error(FormulaError),
 }
 
-impl FormulaHandler for Spreadsheet {
-    fn enter_formula(&mut self, row: usize, col: usize) -> Option<String> {
-        let value = self.cells[row][col].value.clone();
+const ERRORS: [FormulaError; 10] = [
+    FormulaError::DivZero,
+    FormulaError::Ref,
+    FormulaError::Name,
+    FormulaError::Value,
+    FormulaError::Cycle,
+    FormulaError::Num,
+    FormulaError::Null,
+    FormulaError::NotAvailable,
+    FormulaError::Spill,
+    FormulaError::Calc,
+];
+
+pub fn tokenize(input: &str) -> Option<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => {
+                while i < chars.len() && chars[i].is_whitespace() {
+                    i += 1;
+                }
+                tokens.push(Token::Space);
+            }
+            '0'..='9' | '.' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                // Exponent, e.g. 1.5E3 or 2e-4
+                if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                    let mut j = i + 1;
+                    if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                        j += 1;
+                    }
+                    if j < chars.len() && chars[j].is_ascii_digit() {
+                        i = j;
+                        while i < chars.len() && chars[i].is_ascii_digit() {
+                            i += 1;
+                        }
+                    }
+                }
+                let number: String = chars[start..i].iter().collect();
+                tokens.push(Token::Number(number.parse().ok()?));
+            }
+            '"' => {
+                // Strings are delimited by double quotes, "" is an escaped quote
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        Some('"') if chars.get(i + 1) == Some(&'"') => {
+                            text.push('"');
+                            i += 2;
+                        }
+                        Some('"') => {
+                            i += 1;
+                            break;
+                        }
+                        Some(c) => {
+                            text.push(*c);
+                            i += 1;
+                        }
+                        None => return None,
+                    }
+                }
+                tokens.push(Token::Text(text));
+            }
+            c if c.is_alphabetic() || c == '_' || c == '$' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric()
+                        || chars[i] == '_'
+                        || chars[i] == '.'
+                        || chars[i] == '$')
+                {
+                    i += 1;
+                }
+                let ident: String = chars[start..i].iter().collect();
+                if chars.get(i) == Some(&'!') {
+                    i += 1;
+                    tokens.push(Token::Sheet(ident));
+                } else {
+                    tokens.push(Token::Ident(ident));
+                }
+            }
+            '\'' => {
+                // Quoted sheet names can have spaces, '' is an escaped quote
+                let mut name = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        Some('\'') if chars.get(i + 1) == Some(&'\'') => {
+                            name.push('\'');
+                            i += 2;
+                        }
+                        Some('\'') => {
+                            i += 1;
+                            break;
+                        }
+                        Some(c) => {
+                            name.push(*c);
+                            i += 1;
+                        }
+                        None => return None,
+                    }
+                }
+                if chars.get(i) != Some(&'!') {
+                    return None;
+                }
+                i += 1;
+                tokens.push(Token::Sheet(name));
+            }
+            '#' if error_literal(&chars[i..]).is_some() => {
+                let (error, len) = error_literal(&chars[i..])?;
+                tokens.push(Token::Error(error));
+                i += len;
+            }
+            '<' | '>' => {
+                let token = match (c, chars.get(i + 1)) {
+                    ('<', Some('>')) => Token::NotEqual,
+                    ('<', Some('=')) => Token::LessEqual,
+                    ('>', Some('=')) => Token::GreaterEqual,
+                    ('<', _) => Token::Less,
+                    _ => Token::Greater,
+                };
+                i += match token {
+                    Token::Less | Token::Greater => 1,
+                    _ => 2,
+                };
+                tokens.push(token);
+            }
+            _ => {
+                let token = match c {
+                    '=' => Token::Equal,
+                    ':' => Token::Colon,
+                    ',' => Token::Comma,
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '+' => Token::Plus,
+                    '-' => Token::Minus,
+                    '*' => Token::Star,
+                    '/' => Token::Slash,
+                    '^' => Token::Caret,
+                    '%' => Token::Percent,
+                    '&' => Token::Ampersand,
+                    '#' => Token::Hash,
+                    _ => return None,
+                };
+                tokens.push(token);
+                i += 1;
+            }
+        }
+    }
+
+    Some(tokens)
+}
 
-        let operation = self.parse_operation(&value)?;
+// Error at the start of the text and how many characters it takes
+fn error_literal(chars: &[char]) -> Option<(FormulaError, usize)> {
+    ERRORS.into_iter().find_map(|error| {
+        let code: Vec<char> = error.to_string().chars().collect();
+        let matches = chars.len() >= code.len()
+            && code
+                .iter()
+                .zip(chars)
+                .all(|(a, b)| a.eq_ignore_ascii_case(b));
+        matches.then_some((error, code.len()))
+    })
+}
 
-        let range_str = self.parse_range(&value)?;
+// Formula input copied rows down and cols right, e.g. =SUM(A1:A3) copied one
+// column right is =SUM(B1:B3). None when it isn't a formula that parses
+pub fn shift_formula(formula: &str, rows: isize, cols: isize) -> Option<String> {
+    let body = formula.strip_prefix('=')?;
+    // Parsed without names so they stay names instead of their definitions
+    let expr = Parser::new(tokenize(body)?).parse()?;
+    Some(format!("={}", expr.shift(rows, cols)))
+}
 
-        let range = self.convert_range(&range_str)?;
+// Expression of a defined name, its references on the sheet it was defined on
+pub fn parse_definition(defined: &Name, names: &NameManager) -> Option<Expr> {
+    let expr = Parser::new(tokenize(&defined.definition)?)
+        .with_names(names)
+        .parse()?;
+    Some(expr.on_sheet(&defined.sheet))
+}
 
-        self.cells[row][col].formula = true;
-        let result = self.evaluate(operation, range).unwrap();
-        Some(result)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Cell(CellRef),
+    Range(RangeRef),
+    Intersect(Box<Expr>, Box<Expr>),
+    Negate(Box<Expr>),
+    Percent(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Compare(CompareOp, Box<Expr>, Box<Expr>),
+    Concat(Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    // Reference to a cell or range on another sheet
+    Sheet(String, Box<Expr>),
+    // Name that isn't defined, #NAME? until it is, or a LET or LAMBDA variable
+    Name(String),
+    // Whole array spilled from a cell, A1#
+    Spill(CellRef),
+    // LET(x, 1, y, x + 1, x * y), variables and the calculation using them
+    Let(Vec<(String, Expr)>, Box<Expr>),
+    // LAMBDA(x, y, x + y), parameters and the calculation
+    Lambda(Vec<String>, Box<Expr>),
+    // Call of a lambda returned by something else, LAMBDA(x, x * 2)(4)
+    Apply(Box<Expr>, Vec<Expr>),
+    // Error literal, e.g. the #REF! of a reference copied off the sheet
+    Error(FormulaError),
+}
+
+// Function value made by LAMBDA. It keeps the LET and LAMBDA variables that
+// were in scope where it was made, so it can use them wherever it's called
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lambda {
+    pub params: Vec<String>,
+    pub body: Expr,
+    pub captured: Vec<(String, CellValue)>,
+}
+
+impl Expr {
+    pub fn is_reference(&self) -> bool {
+        matches!(
+            self,
+            Expr::Cell(_) | Expr::Range(_) | Expr::Intersect(..) | Expr::Sheet(..) | Expr::Spill(_)
+        )
     }
 
-    fn is_formula(&mut self, value: &str, row: usize, col: usize) -> Option<bool> {
-        let result = self.enter_formula(row, col);
+    // Cells and ranges the expression reads on its own sheet
+    pub fn references(&self) -> Vec<Area> {
+        let mut areas = Vec::new();
+        self.collect_references(&mut areas);
+        areas
+    }
+
+    fn collect_references(&self, areas: &mut Vec<Area>) {
+        match self {
+            Expr::Number(_)
+            | Expr::Text(_)
+            | Expr::Bool(_)
+            | Expr::Sheet(..)
+            | Expr::Name(_)
+            | Expr::Error(_) => {}
+            // A spill reference changes with the formula it spills from
+            Expr::Cell(cell) | Expr::Spill(cell) => {
+                areas.push(Area::new(cell.position(), cell.position()))
+            }
+            Expr::Range(range) => areas.push(range.area()),
+            Expr::Negate(inner) | Expr::Percent(inner) => inner.collect_references(areas),
+            Expr::Binary(_, left, right)
+            | Expr::Compare(_, left, right)
+            | Expr::Concat(left, right)
+            | Expr::Intersect(left, right) => {
+                left.collect_references(areas);
+                right.collect_references(areas);
+            }
+            Expr::Call(_, args) => {
+                for arg in args {
+                    arg.collect_references(areas);
+                }
+            }
+            Expr::Let(bindings, body) => {
+                for (_, value) in bindings {
+                    value.collect_references(areas);
+                }
+                body.collect_references(areas);
+            }
+            Expr::Lambda(_, body) => body.collect_references(areas),
+            Expr::Apply(callee, args) => {
+                callee.collect_references(areas);
+                for arg in args {
+                    arg.collect_references(areas);
+                }
+            }
+        }
+    }
 
-        let operation = self.parse_operation(&value)?;
+    // Names of the functions and lambdas the expression calls
+    pub fn calls(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        self.collect_calls(&mut names);
+        names
+    }
 
-        let range_str = self.parse_range(&value)?;
+    fn collect_calls<'e>(&'e self, names: &mut Vec<&'e str>) {
+        match self {
+            Expr::Call(name, args) => {
+                names.push(name);
+                for arg in args {
+                    arg.collect_calls(names);
+                }
+            }
+            Expr::Negate(inner) | Expr::Percent(inner) | Expr::Sheet(_, inner) => {
+                inner.collect_calls(names)
+            }
+            Expr::Binary(_, left, right)
+            | Expr::Compare(_, left, right)
+            | Expr::Concat(left, right)
+            | Expr::Intersect(left, right) => {
+                left.collect_calls(names);
+                right.collect_calls(names);
+            }
+            Expr::Let(bindings, body) => {
+                for (_, value) in bindings {
+                    value.collect_calls(names);
+                }
+                body.collect_calls(names);
+            }
+            Expr::Lambda(_, body) => body.collect_calls(names),
+            Expr::Apply(callee, args) => {
+                callee.collect_calls(names);
+                for arg in args {
+                    arg.collect_calls(names);
+                }
+            }
+            _ => {}
+        }
+    }
 
-        let range = self.convert_range(&range_str)?;
+    // Other sheets the expression reads, with the areas it reads there
+    pub fn sheet_references(&self) -> Vec<(String, Area)> {
+        match self {
+            Expr::Sheet(name, inner) => inner
+                .references()
+                .into_iter()
+                .map(|area| (name.clone(), area))
+                .collect(),
+            Expr::Negate(inner) | Expr::Percent(inner) => inner.sheet_references(),
+            Expr::Binary(_, left, right)
+            | Expr::Compare(_, left, right)
+            | Expr::Concat(left, right)
+            | Expr::Intersect(left, right) => {
+                let mut references = left.sheet_references();
+                references.extend(right.sheet_references());
+                references
+            }
+            Expr::Call(_, args) => args.iter().flat_map(Expr::sheet_references).collect(),
+            Expr::Let(bindings, body) => bindings
+                .iter()
+                .flat_map(|(_, value)| value.sheet_references())
+                .chain(body.sheet_references())
+                .collect(),
+            Expr::Lambda(_, body) => body.sheet_references(),
+            Expr::Apply(callee, args) => callee
+                .sheet_references()
+                .into_iter()
+                .chain(args.iter().flat_map(Expr::sheet_references))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
 
-        Some(true)
+    // The expression with its own sheet references pointing at the given sheet
+    fn on_sheet(self, sheet: &str) -> Expr {
+        let on_sheet = |expr: Box<Expr>| Box::new(expr.on_sheet(sheet));
+        match self {
+            Expr::Cell(_) | Expr::Range(_) | Expr::Spill(_) => {
+                Expr::Sheet(sheet.to_string(), Box::new(self))
+            }
+            Expr::Intersect(left, right) => Expr::Intersect(on_sheet(left), on_sheet(right)),
+            Expr::Negate(inner) => Expr::Negate(on_sheet(inner)),
+            Expr::Percent(inner) => Expr::Percent(on_sheet(inner)),
+            Expr::Binary(op, left, right) => Expr::Binary(op, on_sheet(left), on_sheet(right)),
+            Expr::Compare(op, left, right) => Expr::Compare(op, on_sheet(left), on_sheet(right)),
+            Expr::Concat(left, right) => Expr::Concat(on_sheet(left), on_sheet(right)),
+            Expr::Call(name, args) => Expr::Call(
+                name,
+                args.into_iter().map(|arg| arg.on_sheet(sheet)).collect(),
+            ),
+            Expr::Let(bindings, body) => Expr::Let(
+                bindings
+                    .into_iter()
+                    .map(|(name, value)| (name, value.on_sheet(sheet)))
+                    .collect(),
+                on_sheet(body),
+            ),
+            Expr::Lambda(params, body) => Expr::Lambda(params, on_sheet(body)),
+            Expr::Apply(callee, args) => Expr::Apply(
+                on_sheet(callee),
+                args.into_iter().map(|arg| arg.on_sheet(sheet)).collect(),
+            ),
+            expr => expr,
+        }
+    }
+
+    // The expression copied rows down and cols right. Relative parts of its
+    // references move, $ parts stay and references moved off the sheet are #REF!
+    pub fn shift(self, rows: isize, cols: isize) -> Expr {
+        let shift = |expr: Box<Expr>| Box::new(expr.shift(rows, cols));
+        let shift_all = |exprs: Vec<Expr>| {
+            exprs
+                .into_iter()
+                .map(|expr| expr.shift(rows, cols))
+                .collect()
+        };
+        let moved = |reference: Option<Expr>| reference.unwrap_or(Expr::Error(FormulaError::Ref));
+        match self {
+            Expr::Cell(cell) => moved(cell.shift(rows, cols).map(Expr::Cell)),
+            Expr::Spill(cell) => moved(cell.shift(rows, cols).map(Expr::Spill)),
+            Expr::Range(range) => moved(range.shift(rows, cols).map(Expr::Range)),
+            Expr::Sheet(name, inner) => match inner.shift(rows, cols) {
+                Expr::Error(error) => Expr::Error(error),
+                inner => Expr::Sheet(name, Box::new(inner)),
+            },
+            Expr::Intersect(left, right) => Expr::Intersect(shift(left), shift(right)),
+            Expr::Negate(inner) => Expr::Negate(shift(inner)),
+            Expr::Percent(inner) => Expr::Percent(shift(inner)),
+            Expr::Binary(op, left, right) => Expr::Binary(op, shift(left), shift(right)),
+            Expr::Compare(op, left, right) => Expr::Compare(op, shift(left), shift(right)),
+            Expr::Concat(left, right) => Expr::Concat(shift(left), shift(right)),
+            Expr::Call(name, args) => Expr::Call(name, shift_all(args)),
+            Expr::Let(bindings, body) => Expr::Let(
+                bindings
+                    .into_iter()
+                    .map(|(name, value)| (name, value.shift(rows, cols)))
+                    .collect(),
+                shift(body),
+            ),
+            Expr::Lambda(params, body) => Expr::Lambda(params, shift(body)),
+            Expr::Apply(callee, args) => Expr::Apply(shift(callee), shift_all(args)),
+            expr => expr,
+        }
+    }
+
+    // How tightly the expression binds, operands binding looser than their
+    // operator need parentheses when written out
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Compare(..) => 1,
+            Expr::Concat(..) => 2,
+            Expr::Binary(BinaryOp::Add | BinaryOp::Subtract, ..) => 3,
+            Expr::Binary(BinaryOp::Multiply | BinaryOp::Divide, ..) => 4,
+            Expr::Binary(BinaryOp::Power, ..) => 5,
+            Expr::Percent(_) => 6,
+            Expr::Negate(_) => 7,
+            Expr::Intersect(..) => 8,
+            _ => 9,
+        }
+    }
+
+    // Whether the value can change without any cell changing, i.e. it calls NOW or TODAY
+    pub fn is_volatile(&self, functions: &FunctionRegistry) -> bool {
+        let volatile = |expr: &Expr| expr.is_volatile(functions);
+        match self {
+            Expr::Call(name, args) => {
+                functions
+                    .get(name)
+                    .is_some_and(|function| function.is_volatile())
+                    || args.iter().any(volatile)
+            }
+            Expr::Negate(inner) | Expr::Percent(inner) => volatile(inner),
+            Expr::Binary(_, left, right)
+            | Expr::Compare(_, left, right)
+            | Expr::Concat(left, right)
+            | Expr::Intersect(left, right) => volatile(left) || volatile(right),
+            Expr::Let(bindings, body) => {
+                bindings.iter().any(|(_, value)| volatile(value)) || volatile(body)
+            }
+            Expr::Lambda(_, body) => volatile(body),
+            Expr::Apply(callee, args) => volatile(callee) || args.iter().any(volatile),
+            _ => false,
+        }
+    }
+}
+
+// Formula text of the expression without the leading =, names and
+// variables as written and only the parentheses precedence needs
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Operators are left associative, so an operand of the same
+        // precedence only needs parentheses on the right
+        let operand = |f: &mut fmt::Formatter<'_>, expr: &Expr, min: u8| {
+            if expr.precedence() < min {
+                write!(f, "({})", expr)
+            } else {
+                write!(f, "{}", expr)
+            }
+        };
+        let list =
+            |f: &mut fmt::Formatter<'_>, items: Vec<String>| write!(f, "({})", items.join(","));
+        let precedence = self.precedence();
+
+        match self {
+            Expr::Number(n) => write!(f, "{}", n),
+            Expr::Text(text) => write!(f, "\"{}\"", text.replace('"', "\"\"")),
+            Expr::Bool(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Expr::Cell(cell) => write!(f, "{}", cell),
+            Expr::Range(range) => write!(f, "{}", range),
+            Expr::Spill(cell) => write!(f, "{}#", cell),
+            Expr::Sheet(name, inner) => {
+                if name
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+                    && name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+                {
+                    write!(f, "{}!{}", name, inner)
+                } else {
+                    write!(f, "'{}'!{}", name.replace('\'', "''"), inner)
+                }
+            }
+            Expr::Intersect(left, right) => write!(f, "{} {}", left, right),
+            Expr::Negate(inner) => {
+                write!(f, "-")?;
+                operand(f, inner, precedence)
+            }
+            Expr::Percent(inner) => {
+                operand(f, inner, precedence)?;
+                write!(f, "%")
+            }
+            Expr::Binary(op, left, right) => {
+                let op = match op {
+                    BinaryOp::Add => "+",
+                    BinaryOp::Subtract => "-",
+                    BinaryOp::Multiply => "*",
+                    BinaryOp::Divide => "/",
+                    BinaryOp::Power => "^",
+                };
+                operand(f, left, precedence)?;
+                write!(f, "{}", op)?;
+                operand(f, right, precedence + 1)
+            }
+            Expr::Compare(op, left, right) => {
+                let op = match op {
+                    CompareOp::Equal => "=",
+                    CompareOp::NotEqual => "<>",
+                    CompareOp::Less => "<",
+                    CompareOp::LessEqual => "<=",
+                    CompareOp::Greater => ">",
+                    CompareOp::GreaterEqual => ">=",
+                };
+                operand(f, left, precedence)?;
+                write!(f, "{}", op)?;
+                operand(f, right, precedence + 1)
+            }
+            Expr::Concat(left, right) => {
+                operand(f, left, precedence)?;
+                write!(f, "&")?;
+                operand(f, right, precedence + 1)
+            }
+            Expr::Call(name, args) => {
+                write!(f, "{}", name)?;
+                list(f, args.iter().map(Expr::to_string).collect())
+            }
+            Expr::Name(name) => write!(f, "{}", name),
+            Expr::Let(bindings, body) => {
+                let mut items = Vec::new();
+                for (name, value) in bindings {
+                    items.push(name.clone());
+                    items.push(value.to_string());
+                }
+                items.push(body.to_string());
+                write!(f, "LET")?;
+                list(f, items)
+            }
+            Expr::Lambda(params, body) => {
+                let mut items = params.clone();
+                items.push(body.to_string());
+                write!(f, "LAMBDA")?;
+                list(f, items)
+            }
+            Expr::Apply(callee, args) => {
+                write!(f, "{}", callee)?;
+                list(f, args.iter().map(Expr::to_string).collect())
+            }
+            Expr::Error(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl CompareOp {
+    pub fn matches(&self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Equal => ordering == Ordering::Equal,
+            CompareOp::NotEqual => ordering != Ordering::Equal,
+            CompareOp::Less => ordering == Ordering::Less,
+            CompareOp::LessEqual => ordering != Ordering::Greater,
+            CompareOp::Greater => ordering == Ordering::Greater,
+            CompareOp::GreaterEqual => ordering != Ordering::Less,
+        }
+    }
+}
+
+// Names referring to names only go this deep, so a name defined in terms
+// of itself stays #NAME? instead of expanding forever
+const MAX_NAME_DEPTH: usize = 16;
+
+// Most values an array function like SEQUENCE returns
+const MAX_ARRAY_SIZE: f64 = 1_000_000.0;
+
+// How deep lambdas can call lambdas and how many calls one formula can make,
+// so runaway recursion gives #NUM! instead of overflowing or hanging
+const MAX_LAMBDA_DEPTH: usize = 64;
+const MAX_LAMBDA_CALLS: usize = 10_000;
+
+// Operator precedence follows Excel, from loosest to tightest: comparisons, &,
+// + -, * /, ^, %, unary -, intersection (space), then references and ranges.
+pub struct Parser<'a> {
+    tokens: Vec<Token>,
+    // Whether whitespace came before each token
+    spaced: Vec<bool>,
+    pos: usize,
+    names: Option<&'a NameManager>,
+    depth: usize,
+    // LET and LAMBDA variables in scope, they hide defined names
+    locals: Vec<String>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(tokens: Vec<Token>) -> Self {
+        let mut spaced = Vec::new();
+        let mut space = false;
+        for token in &tokens {
+            if *token == Token::Space {
+                space = true;
+            } else {
+                spaced.push(space);
+                space = false;
+            }
+        }
+
+        let tokens = tokens
+            .into_iter()
+            .filter(|token| *token != Token::Space)
+            .collect();
+        Self {
+            tokens,
+            spaced,
+            pos: 0,
+            names: None,
+            depth: 0,
+            locals: Vec::new(),
+        }
+    }
+
+    pub fn with_names(mut self, names: &'a NameManager) -> Self {
+        self.names = Some(names);
+        self
+    }
+
+    pub fn parse(mut self) -> Option<Expr> {
+        let expr = self.parse_comparison()?;
+
+        if self.pos != self.tokens.len() {
+            return None;
+        }
+        Some(expr)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
     }
 
-    fn parse_range(&self, value: &str) -> Option<String> {
-        let range = value.split("(").nth(1)?.strip_suffix(')')?;
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
 
-        if range.contains(":") {
-            Some(range.to_string())
+    fn expect(&mut self, token: Token) -> Option<()> {
+        if self.next()? == token {
+            Some(())
         } else {
             None
         }
     }
 
-    fn convert_range(&self, range: &str) -> Option<Vec<(usize, usize)>> {
-        let (start, end) = range.split_once(":")?;
+    fn parse_comparison(&mut self) -> Option<Expr> {
+        let mut left = self.parse_concat()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(Token::Equal) => CompareOp::Equal,
+                Some(Token::NotEqual) => CompareOp::NotEqual,
+                Some(Token::Less) => CompareOp::Less,
+                Some(Token::LessEqual) => CompareOp::LessEqual,
+                Some(Token::Greater) => CompareOp::Greater,
+                Some(Token::GreaterEqual) => CompareOp::GreaterEqual,
+                _ => return Some(left),
+            };
+            self.pos += 1;
+            let right = self.parse_concat()?;
+            left = Expr::Compare(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_concat(&mut self) -> Option<Expr> {
+        let mut left = self.parse_additive()?;
 
-        let start = self.get_cords_from_ref(start)?;
-        let end = self.get_cords_from_ref(end)?;
+        while self.peek() == Some(&Token::Ampersand) {
+            self.pos += 1;
+            let right = self.parse_additive()?;
+            left = Expr::Concat(Box::new(left), Box::new(right));
+        }
 
-        let range: Vec<_> = if start.0 == end.0 {
-            // On same row
+        Some(left)
+    }
 
-            (start.1..=end.1).map(|col| (start.0, col)).collect()
-        } else if start.1 == end.1 {
-            // On same column
+    fn parse_additive(&mut self) -> Option<Expr> {
+        let mut left = self.parse_multiplicative()?;
 
-            (start.0..=end.0).map(|row| (row, start.1)).collect()
-        } else {
-            return None;
-        };
+        loop {
+            let op = match self.peek() {
+                Some(Token::Plus) => BinaryOp::Add,
+                Some(Token::Minus) => BinaryOp::Subtract,
+                _ => return Some(left),
+            };
+            self.pos += 1;
+            let right = self.parse_multiplicative()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Option<Expr> {
+        let mut left = self.parse_power()?;
 
-        Some(range)
+        loop {
+            let op = match self.peek() {
+                Some(Token::Star) => BinaryOp::Multiply,
+                Some(Token::Slash) => BinaryOp::Divide,
+                _ => return Some(left),
+            };
+            self.pos += 1;
+            let right = self.parse_power()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
     }
 
-    fn get_cords_from_ref(&self, range_str: &str) -> Option<(usize, usize)> {
-        let mut chars = range_str.chars();
-        let col = chars.next()?.to_ascii_uppercase();
-        let row = chars.next()?.to_digit(10)?;
+    // Like Excel, ^ is left associative: 2^3^2 = 64
+    fn parse_power(&mut self) -> Option<Expr> {
+        let mut left = self.parse_percent()?;
 
-        if !col.is_alphabetic() {
-            return None;
-        };
+        while self.peek() == Some(&Token::Caret) {
+            self.pos += 1;
+            let right = self.parse_percent()?;
+            left = Expr::Binary(BinaryOp::Power, Box::new(left), Box::new(right));
+        }
 
-        let col_num = col as usize - 'A' as usize;
-        let row_num = row as usize - 1;
-        Some((row_num, col_num))
+        Some(left)
     }
 
-    fn evaluate(&self, operation: FormulaType, range: Vec<(usize, usize)>) -> Result<String, ()> {
-        match operation {
-            FormulaType::SUM => {
-                let mut result: u16 = 0;
-                for (row, col) in range {
-                    let value = self.cells[row][col].value.clone();
-                    let value = value.parse::<u16>().unwrap_or(0);
-                    result += value;
-                }
+    fn parse_percent(&mut self) -> Option<Expr> {
+        let mut expr = self.parse_unary()?;
+
+        while self.peek() == Some(&Token::Percent) {
+            self.pos += 1;
+            expr = Expr::Percent(Box::new(expr));
+        }
+
+        Some(expr)
+    }
+
+    fn parse_unary(&mut self) -> Option<Expr> {
+        match self.peek() {
+            Some(Token::Minus) => {
+                self.pos += 1;
+                Some(Expr::Negate(Box::new(self.parse_unary()?)))
+            }
+            Some(Token::Plus) => {
+                self.pos += 1;
+                self.parse_unary()
+            }
+            _ => self.parse_primary(),
+        }
+    }
 
-                return Ok(result.to_string());
+    fn parse_primary(&mut self) -> Option<Expr> {
+        let mut expr = self.parse_operand()?;
+
+        // A space between two references intersects them, e.g. A1:C3 B1:B5
+        while expr.is_reference()
+            && self.spaced.get(self.pos) == Some(&true)
+            && matches!(
+                self.peek(),
+                Some(Token::Ident(_))
+                    | Some(Token::Number(_))
+                    | Some(Token::LParen)
+                    | Some(Token::Sheet(_))
+            )
+        {
+            let right = self.parse_operand()?;
+            if !right.is_reference() {
+                return None;
+            }
+            expr = Expr::Intersect(Box::new(expr), Box::new(right));
+        }
+
+        Some(expr)
+    }
+
+    fn parse_operand(&mut self) -> Option<Expr> {
+        match self.next()? {
+            Token::Number(n) if self.peek() == Some(&Token::Colon) => {
+                // Whole rows, e.g. 3:3
+                self.pos += 1;
+                let end = self.range_part()?;
+                Some(Expr::Range(RangeRef::parse(&format_row(n)?, &end)?))
             }
-            FormulaType::PRODUCT => {
-                let mut result: u16 = 1;
+            Token::Number(n) => Some(Expr::Number(n)),
+            Token::Text(text) => Some(Expr::Text(text)),
+            Token::Error(error) => Some(Expr::Error(error)),
+            Token::LParen => {
+                let expr = self.parse_comparison()?;
+                self.expect(Token::RParen)?;
+                Some(expr)
+            }
+            Token::Ident(name) => {
+                if self.peek() == Some(&Token::LParen) {
+                    self.pos += 1;
+                    let mut expr = self.parse_call(name.to_ascii_uppercase())?;
+                    // A call returning a lambda can be called straight away
+                    while self.peek() == Some(&Token::LParen) {
+                        self.pos += 1;
+                        expr = Expr::Apply(Box::new(expr), self.parse_args()?);
+                    }
+                    return Some(expr);
+                }
 
-                for (row, col) in range {
-                    let value = self.cells[row][col].value.clone();
-                    let value = value.parse::<u16>().unwrap_or(0);
-                    result *= value;
+                if self.peek() == Some(&Token::Colon) {
+                    self.pos += 1;
+                    let end = self.range_part()?;
+                    return Some(Expr::Range(RangeRef::parse(&name, &end)?));
                 }
 
-                return Ok(result.to_string());
+                if name.eq_ignore_ascii_case("TRUE") {
+                    return Some(Expr::Bool(true));
+                }
+                if name.eq_ignore_ascii_case("FALSE") {
+                    return Some(Expr::Bool(false));
+                }
+
+                if let Some(cell) = CellRef::parse(&name) {
+                    if self.peek() == Some(&Token::Hash) {
+                        self.pos += 1;
+                        return Some(Expr::Spill(cell));
+                    }
+                    return Some(Expr::Cell(cell));
+                }
+                if self
+                    .locals
+                    .iter()
+                    .any(|local| local.eq_ignore_ascii_case(&name))
+                {
+                    return Some(Expr::Name(name));
+                }
+                is_valid_name(&name).then(|| self.resolve_name(name))
             }
-            _ => Err(()),
+            Token::Sheet(name) => match self.parse_operand()? {
+                inner @ (Expr::Cell(_) | Expr::Range(_) | Expr::Spill(_)) => {
+                    Some(Expr::Sheet(name, Box::new(inner)))
+                }
+                _ => None,
+            },
+            _ => None,
         }
     }
 
-    fn parse_operation(&self, value: &str) -> Option<FormulaType> {
-        if !value.starts_with("=") {
-            return None;
+    // Defined names are replaced by their definition, so =SUM(Revenue) reads
+    // and depends on the cells of Revenue like =SUM(Profits!B2:B40) would
+    fn resolve_name(&self, name: String) -> Expr {
+        let Some(names) = self.names else {
+            return Expr::Name(name);
+        };
+        let Some(defined) = names.get(&name).filter(|_| self.depth < MAX_NAME_DEPTH) else {
+            return Expr::Name(name);
+        };
+
+        let expr = tokenize(&defined.definition).and_then(|tokens| {
+            let mut parser = Parser::new(tokens).with_names(names);
+            parser.depth = self.depth + 1;
+            parser.parse()
+        });
+        match expr {
+            Some(expr) => expr.on_sheet(&defined.sheet),
+            None => Expr::Name(name),
+        }
+    }
+
+    // Right hand side of a range, a cell, column or row
+    fn range_part(&mut self) -> Option<String> {
+        match self.next()? {
+            Token::Ident(name) => Some(name),
+            Token::Number(n) => format_row(n),
+            _ => None,
+        }
+    }
+
+    fn parse_call(&mut self, name: String) -> Option<Expr> {
+        match name.as_str() {
+            "LET" => self.parse_let(),
+            "LAMBDA" => self.parse_lambda(),
+            _ => Some(Expr::Call(name, self.parse_args()?)),
+        }
+    }
+
+    // Arguments after the opening parenthesis, up to and including the closing one
+    fn parse_args(&mut self) -> Option<Vec<Expr>> {
+        let mut args = Vec::new();
+
+        if self.peek() == Some(&Token::RParen) {
+            self.pos += 1;
+            return Some(args);
+        }
+
+        loop {
+            args.push(self.parse_comparison()?);
+            match self.next()? {
+                Token::Comma => {}
+                Token::RParen => return Some(args),
+                _ => return None,
+            }
+        }
+    }
+
+    // LET(name1, value1, [name2, value2, ...], calculation), a variable can be
+    // used in the values after it and in the calculation
+    fn parse_let(&mut self) -> Option<Expr> {
+        let scope = self.locals.len();
+        let mut bindings = Vec::new();
+
+        while let Some(name) = self.variable() {
+            let value = self.parse_comparison()?;
+            self.expect(Token::Comma)?;
+            self.locals.push(name.clone());
+            bindings.push((name, value));
+        }
+        let body = self.parse_comparison()?;
+        self.expect(Token::RParen)?;
+
+        self.locals.truncate(scope);
+        (!bindings.is_empty()).then(|| Expr::Let(bindings, Box::new(body)))
+    }
+
+    // LAMBDA([param1, param2, ...], calculation)
+    fn parse_lambda(&mut self) -> Option<Expr> {
+        let scope = self.locals.len();
+        let mut params = Vec::new();
+
+        while let Some(name) = self.variable() {
+            if params
+                .iter()
+                .any(|param: &String| param.eq_ignore_ascii_case(&name))
+            {
+                return None;
+            }
+            self.locals.push(name.clone());
+            params.push(name);
+        }
+        let body = self.parse_comparison()?;
+        self.expect(Token::RParen)?;
+
+        self.locals.truncate(scope);
+        Some(Expr::Lambda(params, Box::new(body)))
+    }
+
+    // Name of a LET variable or LAMBDA parameter, a name followed by a comma
+    fn variable(&mut self) -> Option<String> {
+        match (self.peek(), self.tokens.get(self.pos + 1)) {
+            (Some(Token::Ident(name)), Some(Token::Comma)) if is_valid_name(name) => {
+                let name = name.clone();
+                self.pos += 2;
+                Some(name)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn format_row(n: f64) -> Option<String> {
+    (n.fract() == 0.0 && n >= 1.0).then(|| (n as usize).to_string())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FormulaError {
+    DivZero,
+    Ref,
+    Name,
+    Value,
+    Cycle,
+    Num,
+    Null,
+    NotAvailable,
+    Spill,
+    Calc,
+}
+
+impl FormulaError {
+    pub fn description(&self) -> &'static str {
+        match self {
+            FormulaError::DivZero => "Division by zero",
+            FormulaError::Ref => "Reference to a cell that doesn't exist",
+            FormulaError::Name => "Unknown function or name, or the formula can't be read",
+            FormulaError::Value => "Wrong type of value, e.g. text where a number is needed",
+            FormulaError::Cycle => "Formula refers back to its own cell",
+            FormulaError::Num => "Result is too large or not a valid number",
+            FormulaError::Null => "Intersected ranges have no cells in common",
+            FormulaError::NotAvailable => "No value matched",
+            FormulaError::Spill => "Array result is blocked by cells that aren't empty",
+            FormulaError::Calc => "Array result is empty",
+        }
+    }
+}
+
+impl fmt::Display for FormulaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self {
+            FormulaError::DivZero => "#DIV/0!",
+            FormulaError::Ref => "#REF!",
+            FormulaError::Name => "#NAME?",
+            FormulaError::Value => "#VALUE!",
+            FormulaError::Cycle => "#CYCLE!",
+            FormulaError::Num => "#NUM!",
+            FormulaError::Null => "#NULL!",
+            FormulaError::NotAvailable => "#N/A",
+            FormulaError::Spill => "#SPILL!",
+            FormulaError::Calc => "#CALC!",
         };
+        write!(f, "{}", code)
+    }
+}
 
-        let operation_str = value
-            .trim_matches(|c| c == '=' || c == ')')
-            .split_once("(")?
-            .0; // =SUM(A1:A5) -> ["SUM", "A1:A5"]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NumericMode {
+    Float,
+    // Each operation is done in base 10, so 0.1+0.2 is 0.3, but values are
+    // still stored as f64 and only exact to about 15 significant digits
+    Decimal,
+}
+
+// Settings for calculating circular references on purpose, e.g. feedback
+// models. Cycles are recalculated until no value changes by more than epsilon
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Iteration {
+    pub max_iterations: usize,
+    pub epsilon: f64,
+}
 
-        match operation_str {
-            "SUM" => Some(FormulaType::SUM),
-            "PRODUCT" => Some(FormulaType::PRODUCT),
-            _ => return None,
+impl Default for Iteration {
+    fn default() -> Self {
+        Self {
+            max_iterations: 100,
+            epsilon: 0.001,
         }
     }
 }
 
-#[derive(Clone, Debug)]
-pub enum FormulaType {
-    SUM,
-    PRODUCT,
-    DIFFERENCE,
-    QUOTIENT,
+pub fn arithmetic(mode: NumericMode, op: BinaryOp, left: f64, right: f64) -> CellValue {
+    if op == BinaryOp::Divide && right == 0.0 {
+        return CellValue::Error(FormulaError::DivZero);
+    }
+
+    let result = match (mode, op) {
+        (NumericMode::Decimal, BinaryOp::Power) | (NumericMode::Float, _) => match op {
+            BinaryOp::Add => Some(left + right),
+            BinaryOp::Subtract => Some(left - right),
+            BinaryOp::Multiply => Some(left * right),
+            BinaryOp::Divide => Some(left / right),
+            BinaryOp::Power => Some(left.powf(right)),
+        },
+        (NumericMode::Decimal, _) => decimal_arithmetic(op, left, right),
+    };
+
+    // Overflow to infinity and NaN both surface as #NUM! instead of a panic
+    match result {
+        Some(n) if n.is_finite() => CellValue::Number(n),
+        _ => CellValue::Error(FormulaError::Num),
+    }
+}
+
+// Rounds the operands to their shortest decimal form, calculates exactly
+// and converts back, None outside the range Decimal holds (about ±7.9e28)
+fn decimal_arithmetic(op: BinaryOp, left: f64, right: f64) -> Option<f64> {
+    let left = Decimal::from_f64(left)?;
+    let right = Decimal::from_f64(right)?;
+
+    let result = match op {
+        BinaryOp::Add => left.checked_add(right),
+        BinaryOp::Subtract => left.checked_sub(right),
+        BinaryOp::Multiply => left.checked_mul(right),
+        BinaryOp::Divide => left.checked_div(right),
+        BinaryOp::Power => None,
+    }?;
+
+    result.to_f64()
 }
 
-pub fn letter_pos(letter: char) -> Option<u32> {
-    if letter.is_alphabetic() {
-        Some(letter.to_ascii_uppercase() as u32 - 64)
-    } else {
-        None
+// Numeric operand of an operator. Errors in operands propagate unchanged
+fn to_number(value: CellValue) -> Result<f64, FormulaError> {
+    match scalar(value) {
+        CellValue::Error(error) => Err(error),
+        value => value.as_number().ok_or(FormulaError::Value),
+    }
+}
+
+// Text operand of & and the text functions, numbers are shown like in a cell
+fn to_text(value: CellValue) -> Result<String, FormulaError> {
+    match scalar(value) {
+        CellValue::Error(error) => Err(error),
+        value => Ok(value.to_string()),
+    }
+}
+
+// Condition of IF, AND, OR and NOT. Like Excel, text only works when it's TRUE or FALSE
+fn to_bool(value: CellValue) -> Result<bool, FormulaError> {
+    match scalar(value) {
+        CellValue::Error(error) => Err(error),
+        CellValue::Bool(b) => Ok(b),
+        CellValue::Text(text) if text.eq_ignore_ascii_case("TRUE") => Ok(true),
+        CellValue::Text(text) if text.eq_ignore_ascii_case("FALSE") => Ok(false),
+        CellValue::Text(_) => Err(FormulaError::Value),
+        value => Ok(value.as_number().unwrap_or(0.0) != 0.0),
+    }
+}
+
+// Value of an array used where a single value is needed, only one element arrays have one
+fn scalar(value: CellValue) -> CellValue {
+    match value {
+        CellValue::Array(mut rows) if rows.len() == 1 && rows[0].len() == 1 => {
+            rows[0].swap_remove(0)
+        }
+        CellValue::Array(_) => CellValue::Error(FormulaError::Value),
+        CellValue::Lambda(_) => CellValue::Error(FormulaError::Calc),
+        value => value,
+    }
+}
+
+// Applies an operation to a value, or to every element of an array
+fn map_array(value: CellValue, operation: impl Fn(CellValue) -> CellValue) -> CellValue {
+    match value {
+        CellValue::Array(rows) => CellValue::Array(
+            rows.into_iter()
+                .map(|row| row.into_iter().map(&operation).collect())
+                .collect(),
+        ),
+        value => operation(value),
+    }
+}
+
+// Applies an operation to pairs of elements. A single value, row or column
+// is repeated to match the other side, elements missing on one side are #N/A
+fn zip_arrays(
+    left: CellValue,
+    right: CellValue,
+    operation: impl Fn(CellValue, CellValue) -> CellValue,
+) -> CellValue {
+    let as_rows = |value: CellValue| match value {
+        CellValue::Array(rows) => rows,
+        value => vec![vec![value]],
+    };
+    if !matches!(left, CellValue::Array(_)) && !matches!(right, CellValue::Array(_)) {
+        return operation(left, right);
+    }
+
+    let left = as_rows(left);
+    let right = as_rows(right);
+    let rows = left.len().max(right.len());
+    let cols = left[0].len().max(right[0].len());
+    let at = |array: &Vec<Vec<CellValue>>, row: usize, col: usize| {
+        let row = if array.len() == 1 { 0 } else { row };
+        let col = if array[0].len() == 1 { 0 } else { col };
+        array
+            .get(row)
+            .and_then(|values| values.get(col))
+            .cloned()
+            .unwrap_or(CellValue::Error(FormulaError::NotAvailable))
+    };
+
+    CellValue::Array(
+        (0..rows)
+            .map(|row| {
+                (0..cols)
+                    .map(|col| operation(at(&left, row, col), at(&right, row, col)))
+                    .collect()
+            })
+            .collect(),
+    )
+}
+
+fn transpose(rows: Vec<Vec<CellValue>>) -> Vec<Vec<CellValue>> {
+    let cols = rows.first().map_or(0, Vec::len);
+    let mut result = vec![Vec::with_capacity(rows.len()); cols];
+    for row in rows {
+        for (col, value) in row.into_iter().enumerate() {
+            result[col].push(value);
+        }
+    }
+    result
+}
+
+// Orders values like Excel: numbers < text < booleans, text ignoring case
+// and blanks acting as 0, "" or FALSE depending on the other side
+pub fn compare_values(left: &CellValue, right: &CellValue) -> Ordering {
+    fn rank(value: &CellValue) -> u8 {
+        match value {
+            CellValue::Text(_) => 1,
+            CellValue::Bool(_) => 2,
+            _ => 0,
+        }
+    }
+
+    match (left, right) {
+        (CellValue::Empty, CellValue::Empty) => Ordering::Equal,
+        (CellValue::Empty, CellValue::Text(text)) => "".cmp(text.as_str()),
+        (CellValue::Text(text), CellValue::Empty) => text.as_str().cmp(""),
+        (CellValue::Empty, CellValue::Bool(b)) => false.cmp(b),
+        (CellValue::Bool(b), CellValue::Empty) => b.cmp(&false),
+        (CellValue::Text(left), CellValue::Text(right)) => {
+            left.to_lowercase().cmp(&right.to_lowercase())
+        }
+        (CellValue::Bool(left), CellValue::Bool(right)) => left.cmp(right),
+        _ if rank(left) != rank(right) => rank(left).cmp(&rank(right)),
+        _ => {
+            let left = left.as_number().unwrap_or(0.0);
+            let right = right.as_number().unwrap_or(0.0);
+            left.partial_cmp(&right).unwrap_or(Ordering::Equal)
+        }
+    }
+}
+
+// Area of a resolved reference together with the sheet it's on
+#[derive(Clone, Copy)]
+pub struct Region<'a> {
+    cells: &'a [Vec<Cell>],
+    area: Area,
+}
+
+impl<'a> Region<'a> {
+    fn with_area(self, area: Area) -> Self {
+        Self { area, ..self }
+    }
+
+    // Values of the cells in the area that exist, so whole columns
+    // and rows only visit the cells of the sheet
+    pub fn values(self) -> impl Iterator<Item = CellValue> + 'a {
+        let area = self.area;
+        self.cells
+            .iter()
+            .skip(area.start.0)
+            .take(area.rows())
+            .flat_map(move |row| row.iter().skip(area.start.1).take(area.cols()))
+            .map(|cell| cell.computed.clone())
+    }
+
+    // Values row by row, leaving out the part of the area outside the sheet
+    pub fn to_array(self) -> Vec<Vec<CellValue>> {
+        let rows: Vec<Vec<CellValue>> = self
+            .cells
+            .iter()
+            .skip(self.start.0)
+            .take(self.rows())
+            .map(|row| {
+                row.iter()
+                    .skip(self.start.1)
+                    .take(self.cols())
+                    .map(|cell| cell.computed.clone())
+                    .collect()
+            })
+            .collect();
+
+        if rows.is_empty() || rows[0].is_empty() {
+            return vec![vec![CellValue::Empty]];
+        }
+        rows
+    }
+
+    // Cells outside the grid are treated as blank
+    pub fn value(&self, row: usize, col: usize) -> CellValue {
+        self.cells
+            .get(row)
+            .and_then(|cells| cells.get(col))
+            .map(|cell| cell.computed.clone())
+            .unwrap_or(CellValue::Empty)
+    }
+}
+
+impl std::ops::Deref for Region<'_> {
+    type Target = Area;
+
+    fn deref(&self) -> &Area {
+        &self.area
+    }
+}
+
+#[derive(Clone)]
+pub struct Evaluator<'a> {
+    cells: &'a [Vec<Cell>],
+    mode: NumericMode,
+    clock: &'a dyn Clock,
+    workbook: Option<Workbook<'a>>,
+    functions: &'a FunctionRegistry,
+    names: Option<&'a NameManager>,
+    // LET and LAMBDA variables in scope, innermost last
+    locals: &'a [(String, CellValue)],
+    // How deep lambdas are calling lambdas, and the calls made so far in
+    // the whole evaluation, shared with the evaluators made for the calls
+    depth: usize,
+    calls: Rc<std::cell::Cell<usize>>,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(cells: &'a [Vec<Cell>], mode: NumericMode) -> Self {
+        Self {
+            cells,
+            mode,
+            clock: &SystemClock,
+            workbook: None,
+            functions: FunctionRegistry::builtins(),
+            names: None,
+            locals: &[],
+            depth: 0,
+            calls: Rc::default(),
+        }
+    }
+
+    // Names defined as a LAMBDA can then be called like functions
+    pub fn with_names(mut self, names: &'a NameManager) -> Self {
+        self.names = Some(names);
+        self
+    }
+
+    pub fn with_functions(mut self, functions: &'a FunctionRegistry) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    pub fn with_workbook(mut self, workbook: Workbook<'a>) -> Self {
+        self.workbook = Some(workbook);
+        self
+    }
+
+    // Evaluator reading another sheet, #REF! when it doesn't exist
+    fn on_sheet(&self, name: &str) -> Result<Self, FormulaError> {
+        let cells = self
+            .workbook
+            .and_then(|workbook| workbook.sheet(name))
+            .ok_or(FormulaError::Ref)?;
+        Ok(Self {
+            cells,
+            ..self.clone()
+        })
+    }
+
+    fn region(&self, area: Area) -> Region<'a> {
+        Region {
+            cells: self.cells,
+            area,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: &'a dyn Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn eval(&self, expr: &Expr) -> CellValue {
+        match expr {
+            Expr::Number(n) => CellValue::Number(*n),
+            Expr::Text(text) => CellValue::Text(text.clone()),
+            Expr::Bool(b) => CellValue::Bool(*b),
+            Expr::Cell(cell) => self.cell_value(cell.row, cell.col),
+            // A range outside a function spills its values, e.g. =A1:A3*2
+            Expr::Range(_) | Expr::Intersect(..) | Expr::Sheet(..) | Expr::Spill(_) => {
+                match self.reference(expr) {
+                    Some(Ok(region)) if region.start == region.end => {
+                        region.value(region.start.0, region.start.1)
+                    }
+                    Some(Ok(region)) => CellValue::Array(region.to_array()),
+                    Some(Err(error)) => CellValue::Error(error),
+                    None => CellValue::Error(FormulaError::Value),
+                }
+            }
+            // Operators work on every element of arrays
+            Expr::Negate(inner) => map_array(self.eval(inner), |value| match to_number(value) {
+                Ok(value) => CellValue::Number(-value),
+                Err(error) => CellValue::Error(error),
+            }),
+            Expr::Percent(inner) => map_array(self.eval(inner), |value| match to_number(value) {
+                Ok(value) => arithmetic(self.mode, BinaryOp::Divide, value, 100.0),
+                Err(error) => CellValue::Error(error),
+            }),
+            Expr::Binary(op, left, right) => {
+                zip_arrays(self.eval(left), self.eval(right), |left, right| {
+                    self.binary(*op, left, right)
+                })
+            }
+            Expr::Compare(op, left, right) => {
+                zip_arrays(self.eval(left), self.eval(right), |left, right| {
+                    match (left, right) {
+                        (CellValue::Error(error), _) | (_, CellValue::Error(error)) => {
+                            CellValue::Error(error)
+                        }
+                        (left, right) => CellValue::Bool(op.matches(compare_values(&left, &right))),
+                    }
+                })
+            }
+            Expr::Concat(left, right) => {
+                zip_arrays(self.eval(left), self.eval(right), |left, right| {
+                    match (to_text(left), to_text(right)) {
+                        (Ok(left), Ok(right)) => CellValue::Text(left + &right),
+                        (Err(error), _) | (_, Err(error)) => CellValue::Error(error),
+                    }
+                })
+            }
+            Expr::Name(name) => self
+                .local(name)
+                .unwrap_or(CellValue::Error(FormulaError::Name)),
+            Expr::Call(name, args) => {
+                // Variables holding a lambda are called like functions
+                if let Some(value) = self.local(name) {
+                    return self.call(&value, args);
+                }
+                let Some(function) = self.functions.get(name) else {
+                    return match self.named_lambda(name) {
+                        Some(lambda) => self.call(&lambda, args),
+                        None => CellValue::Error(FormulaError::Name),
+                    };
+                };
+                if !function.arity().accepts(args.len()) {
+                    return CellValue::Error(FormulaError::Value);
+                }
+
+                function
+                    .evaluate(self, args)
+                    .unwrap_or_else(CellValue::Error)
+            }
+            Expr::Let(bindings, body) => {
+                let mut locals = self.locals.to_vec();
+                for (name, value) in bindings {
+                    let value = self.scope(&locals).eval(value);
+                    locals.push((name.clone(), value));
+                }
+                self.scope(&locals).eval(body)
+            }
+            Expr::Lambda(params, body) => CellValue::Lambda(Rc::new(Lambda {
+                params: params.clone(),
+                body: (**body).clone(),
+                captured: self.locals.to_vec(),
+            })),
+            Expr::Apply(callee, args) => self.call(&self.eval(callee), args),
+            Expr::Error(error) => CellValue::Error(*error),
+        }
+    }
+
+    pub fn locals(&self) -> &'a [(String, CellValue)] {
+        self.locals
+    }
+
+    fn local(&self, name: &str) -> Option<CellValue> {
+        self.locals
+            .iter()
+            .rev()
+            .find(|(local, _)| local.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    }
+
+    // Evaluator with other variables in scope
+    pub fn scope<'b>(&self, locals: &'b [(String, CellValue)]) -> Evaluator<'b>
+    where
+        'a: 'b,
+    {
+        Evaluator {
+            locals,
+            ..self.clone()
+        }
+    }
+
+    // A name defined as a LAMBDA, looked up when it's called so it can call
+    // itself. Its definition was parsed when the name was defined
+    fn named_lambda(&self, name: &str) -> Option<CellValue> {
+        let expr = self.names?.parsed(name)?;
+        match self.scope(&[]).eval(expr) {
+            lambda @ CellValue::Lambda(_) => Some(lambda),
+            _ => None,
+        }
+    }
+
+    // Calls a lambda with the arguments evaluated where it's called, its
+    // calculation sees the variables it captured and its parameters
+    fn call(&self, callee: &CellValue, args: &[Expr]) -> CellValue {
+        let lambda = match callee {
+            CellValue::Lambda(lambda) => lambda,
+            CellValue::Error(error) => return CellValue::Error(*error),
+            _ => return CellValue::Error(FormulaError::Value),
+        };
+        if lambda.params.len() != args.len() {
+            return CellValue::Error(FormulaError::Value);
+        }
+
+        self.calls.set(self.calls.get() + 1);
+        if self.depth >= MAX_LAMBDA_DEPTH || self.calls.get() > MAX_LAMBDA_CALLS {
+            return CellValue::Error(FormulaError::Num);
+        }
+
+        let mut locals = lambda.captured.clone();
+        for (param, arg) in lambda.params.iter().zip(args) {
+            locals.push((param.clone(), self.eval(arg)));
+        }
+        Evaluator {
+            depth: self.depth + 1,
+            ..self.scope(&locals)
+        }
+        .eval(&lambda.body)
+    }
+
+    fn binary(&self, op: BinaryOp, left: CellValue, right: CellValue) -> CellValue {
+        // Days added to or taken from a date give a date, two dates
+        // subtracted give the days between them
+        let is_date = |value: &CellValue| matches!(value, CellValue::Date(_));
+        let date_result = match op {
+            BinaryOp::Add => is_date(&left) != is_date(&right),
+            BinaryOp::Subtract => is_date(&left) && !is_date(&right),
+            _ => false,
+        };
+
+        match (to_number(left), to_number(right)) {
+            (Ok(left), Ok(right)) => match arithmetic(self.mode, op, left, right) {
+                CellValue::Number(n) if date_result => CellValue::Date(n),
+                result => result,
+            },
+            (Err(error), _) | (_, Err(error)) => CellValue::Error(error),
+        }
+    }
+
+    // Area of the array spilled from a cell. A formula that returned a
+    // single value is its own spill area, any other cell is #REF!
+    fn spill_region(&self, cell: &CellRef) -> Result<Region<'a>, FormulaError> {
+        let position = cell.position();
+        let anchor = self
+            .cells
+            .get(position.0)
+            .and_then(|row| row.get(position.1))
+            .ok_or(FormulaError::Ref)?;
+        if anchor.computed == CellValue::Error(FormulaError::Spill) {
+            return Err(FormulaError::Spill);
+        }
+
+        match spill::area(self.cells, position) {
+            Some(area) => Ok(self.region(area)),
+            None if anchor.formula => Ok(self.region(Area::new(position, position))),
+            None => Err(FormulaError::Ref),
+        }
+    }
+
+    // Area of a reference expression, None when the expression isn't a reference.
+    // Functions read their range arguments through it
+    pub fn reference(&self, expr: &Expr) -> Option<Result<Region<'a>, FormulaError>> {
+        match expr {
+            Expr::Cell(cell) => Some(Ok(self.region(Area::new(cell.position(), cell.position())))),
+            Expr::Range(range) => Some(Ok(self.region(range.area()))),
+            Expr::Intersect(left, right) => {
+                let left = self.reference(left)?;
+                let right = self.reference(right)?;
+                Some(match (left, right) {
+                    (Ok(left), Ok(right)) if std::ptr::eq(left.cells, right.cells) => left
+                        .intersect(&right)
+                        .map(|area| left.with_area(area))
+                        .ok_or(FormulaError::Null),
+                    // Ranges on different sheets never overlap
+                    (Ok(_), Ok(_)) => Err(FormulaError::Value),
+                    (Err(error), _) | (_, Err(error)) => Err(error),
+                })
+            }
+            Expr::Sheet(name, inner) => match self.on_sheet(name) {
+                Ok(sheet) => sheet.reference(inner),
+                Err(error) => Some(Err(error)),
+            },
+            Expr::Spill(cell) => Some(self.spill_region(cell)),
+            // INDEX gives a reference, so SUM(INDEX(A1:C3, 0, 2)) adds up a column
+            Expr::Call(name, args) if name == "INDEX" => Some(self.index_area(args)),
+            _ => None,
+        }
+    }
+
+    // Numbers of the arguments like Excel: blanks, text and booleans in
+    // references are skipped while arguments typed in the formula are converted
+    pub fn numbers(&self, args: &[Expr]) -> Result<Vec<f64>, FormulaError> {
+        let mut numbers = Vec::new();
+
+        for arg in args {
+            // Arrays are read like references
+            let values: Vec<CellValue> = match self.reference(arg) {
+                Some(area) => area?.values().collect(),
+                None => match self.eval(arg) {
+                    CellValue::Array(rows) => rows.into_iter().flatten().collect(),
+                    CellValue::Empty => continue,
+                    value => {
+                        numbers.push(to_number(value)?);
+                        continue;
+                    }
+                },
+            };
+
+            for value in values {
+                match value {
+                    CellValue::Number(n) | CellValue::Date(n) => numbers.push(n),
+                    CellValue::Error(error) => return Err(error),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(numbers)
+    }
+
+    // Cells outside the grid are treated as blank
+    fn cell_value(&self, row: usize, col: usize) -> CellValue {
+        self.cells
+            .get(row)
+            .and_then(|cells| cells.get(col))
+            .map(|cell| cell.computed.clone())
+            .unwrap_or(CellValue::Empty)
+    }
+
+    pub fn number_arg(&self, arg: &Expr) -> Result<f64, FormulaError> {
+        to_number(self.eval(arg))
+    }
+
+    // Folds the arguments left to right, e.g. DIFFERENCE(A1:A3) is A1 - A2 - A3
+    fn fold(&self, op: BinaryOp, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let numbers = self.numbers(args)?;
+        Ok(self.fold_numbers(op, &numbers))
+    }
+
+    fn fold_numbers(&self, op: BinaryOp, numbers: &[f64]) -> CellValue {
+        let mut result = None;
+        for &value in numbers {
+            result = match result {
+                None => Some(value),
+                Some(result) => match arithmetic(self.mode, op, result, value) {
+                    CellValue::Number(n) => Some(n),
+                    error => return error,
+                },
+            };
+        }
+
+        CellValue::Number(result.unwrap_or(0.0))
+    }
+
+    // Blank cells are ignored, so they don't count towards the number of values
+    fn average(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let numbers = self.numbers(args)?;
+        if numbers.is_empty() {
+            return Err(FormulaError::DivZero);
+        }
+
+        let sum = match self.fold_numbers(BinaryOp::Add, &numbers) {
+            CellValue::Number(sum) => sum,
+            error => return Ok(error),
+        };
+        Ok(arithmetic(
+            self.mode,
+            BinaryOp::Divide,
+            sum,
+            numbers.len() as f64,
+        ))
+    }
+
+    // MIN and MAX are 0 when there are no numbers
+    fn extreme(&self, args: &[Expr], pick: fn(f64, f64) -> f64) -> Result<CellValue, FormulaError> {
+        let result = self.numbers(args)?.into_iter().reduce(pick);
+        Ok(CellValue::Number(result.unwrap_or(0.0)))
+    }
+
+    // Counts numbers, including ones typed as text or booleans in the formula.
+    // Errors aren't counted rather than propagated
+    fn count(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let mut count = 0;
+
+        for arg in args {
+            match self.reference(arg) {
+                Some(Ok(area)) => {
+                    count += area
+                        .values()
+                        .filter(|value| matches!(value, CellValue::Number(_) | CellValue::Date(_)))
+                        .count();
+                }
+                Some(Err(_)) => {}
+                None => {
+                    if to_number(self.eval(arg)).is_ok() {
+                        count += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(CellValue::Number(count as f64))
+    }
+
+    // Counts every value that isn't blank, errors included
+    fn count_all(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let mut count = 0;
+
+        for arg in args {
+            match self.reference(arg) {
+                Some(Ok(area)) => {
+                    count += area
+                        .values()
+                        .filter(|value| *value != CellValue::Empty)
+                        .count();
+                }
+                Some(Err(_)) | None => count += 1,
+            }
+        }
+
+        Ok(CellValue::Number(count as f64))
+    }
+
+    // ROUND(number, digits), halves are rounded away from zero like Excel
+    fn round(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [number, digits] = args else {
+            return Err(FormulaError::Value);
+        };
+        let number = self.number_arg(number)?;
+        let digits = self.number_arg(digits)?.trunc() as i32;
+
+        // Digits far out of range make the factor 0 or infinite
+        finite(round_to(number, digits))
+    }
+
+    fn unary_math(
+        &self,
+        args: &[Expr],
+        function: fn(f64) -> f64,
+    ) -> Result<CellValue, FormulaError> {
+        let [number] = args else {
+            return Err(FormulaError::Value);
+        };
+
+        finite(function(self.number_arg(number)?))
+    }
+
+    // MOD(number, divisor), the result has the sign of the divisor like Excel
+    fn modulo(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [number, divisor] = args else {
+            return Err(FormulaError::Value);
+        };
+        let number = self.number_arg(number)?;
+        let divisor = self.number_arg(divisor)?;
+
+        if divisor == 0.0 {
+            return Err(FormulaError::DivZero);
+        }
+        finite(number - divisor * (number / divisor).floor())
+    }
+
+    fn power(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [number, power] = args else {
+            return Err(FormulaError::Value);
+        };
+        let number = self.number_arg(number)?;
+        let power = self.number_arg(power)?;
+
+        Ok(arithmetic(self.mode, BinaryOp::Power, number, power))
+    }
+
+    // The logical functions only evaluate the arguments they need, so an
+    // error in a branch that isn't taken doesn't make the result an error
+
+    // IF(condition, value if true, [value if false])
+    fn if_(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let (condition, then, otherwise) = match args {
+            [condition, then] => (condition, then, None),
+            [condition, then, otherwise] => (condition, then, Some(otherwise)),
+            _ => return Err(FormulaError::Value),
+        };
+
+        if to_bool(self.eval(condition))? {
+            Ok(self.eval(then))
+        } else {
+            Ok(otherwise.map_or(CellValue::Bool(false), |otherwise| self.eval(otherwise)))
+        }
+    }
+
+    // IFS(condition1, value1, [condition2, value2], ...)
+    fn ifs(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            return Err(FormulaError::Value);
+        }
+
+        for pair in args.chunks(2) {
+            if to_bool(self.eval(&pair[0]))? {
+                return Ok(self.eval(&pair[1]));
+            }
+        }
+        Err(FormulaError::NotAvailable)
+    }
+
+    // AND stops at the first FALSE and OR at the first TRUE. Text and
+    // blanks in references are skipped
+    fn and_or(&self, args: &[Expr], stop_at: bool) -> Result<CellValue, FormulaError> {
+        let mut any = false;
+
+        for arg in args {
+            let values: Vec<CellValue> = match self.reference(arg) {
+                Some(area) => area?
+                    .values()
+                    .filter(|value| !matches!(value, CellValue::Empty | CellValue::Text(_)))
+                    .collect(),
+                None => vec![self.eval(arg)],
+            };
+
+            for value in values {
+                any = true;
+                if to_bool(value)? == stop_at {
+                    return Ok(CellValue::Bool(stop_at));
+                }
+            }
+        }
+
+        if !any {
+            return Err(FormulaError::Value);
+        }
+        Ok(CellValue::Bool(!stop_at))
+    }
+
+    fn not(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [value] = args else {
+            return Err(FormulaError::Value);
+        };
+
+        Ok(CellValue::Bool(!to_bool(self.eval(value))?))
+    }
+
+    // IFERROR(value, value if error)
+    fn if_error(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [value, fallback] = args else {
+            return Err(FormulaError::Value);
+        };
+
+        match self.eval(value) {
+            CellValue::Error(_) => Ok(self.eval(fallback)),
+            value => Ok(value),
+        }
+    }
+
+    // SWITCH(expression, value1, result1, [value2, result2], ..., [default])
+    fn switch(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let Some((expression, cases)) = args.split_first() else {
+            return Err(FormulaError::Value);
+        };
+        if cases.len() < 2 {
+            return Err(FormulaError::Value);
+        }
+
+        let value = match self.eval(expression) {
+            CellValue::Error(error) => return Err(error),
+            value => value,
+        };
+
+        for pair in cases.chunks(2) {
+            match pair {
+                [case, result] => {
+                    let case = match self.eval(case) {
+                        CellValue::Error(error) => return Err(error),
+                        case => case,
+                    };
+                    if compare_values(&value, &case) == Ordering::Equal {
+                        return Ok(self.eval(result));
+                    }
+                }
+                [default] => return Ok(self.eval(default)),
+                _ => {}
+            }
+        }
+        Err(FormulaError::NotAvailable)
+    }
+
+    pub fn text_arg(&self, arg: &Expr) -> Result<String, FormulaError> {
+        to_text(self.eval(arg))
+    }
+
+    // Optional count argument of LEFT, RIGHT and SUBSTITUTE
+    fn count_arg(&self, arg: Option<&Expr>, default: usize) -> Result<usize, FormulaError> {
+        let Some(arg) = arg else {
+            return Ok(default);
+        };
+
+        let count = self.number_arg(arg)?.trunc();
+        if count < 0.0 {
+            return Err(FormulaError::Value);
+        }
+        Ok(count as usize)
+    }
+
+    // CONCAT(text1, [text2], ...), ranges are joined cell by cell
+    fn concat(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let mut result = String::new();
+
+        for arg in args {
+            match self.reference(arg) {
+                Some(area) => {
+                    for value in area?.values() {
+                        result.push_str(&to_text(value)?);
+                    }
+                }
+                None => result.push_str(&self.text_arg(arg)?),
+            }
+        }
+
+        Ok(CellValue::Text(result))
+    }
+
+    // Text functions count user perceived characters (grapheme clusters),
+    // so an accented letter or an emoji with modifiers is one character
+
+    // LEFT(text, [count]) and RIGHT(text, [count])
+    fn left_right(&self, args: &[Expr], from_end: bool) -> Result<CellValue, FormulaError> {
+        let (text, count) = match args {
+            [text] => (text, None),
+            [text, count] => (text, Some(count)),
+            _ => return Err(FormulaError::Value),
+        };
+        let text = self.text_arg(text)?;
+        let count = self.count_arg(count, 1)?;
+
+        let graphemes: Vec<&str> = text.graphemes(true).collect();
+        let count = count.min(graphemes.len());
+        let part = if from_end {
+            &graphemes[graphemes.len() - count..]
+        } else {
+            &graphemes[..count]
+        };
+
+        Ok(CellValue::Text(part.concat()))
+    }
+
+    // MID(text, start, count), start is one based
+    fn mid(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [text, start, count] = args else {
+            return Err(FormulaError::Value);
+        };
+        let text = self.text_arg(text)?;
+        let start = self.number_arg(start)?.trunc();
+        let count = self.count_arg(Some(count), 0)?;
+
+        if start < 1.0 {
+            return Err(FormulaError::Value);
+        }
+
+        let part: String = text
+            .graphemes(true)
+            .skip(start as usize - 1)
+            .take(count)
+            .collect();
+        Ok(CellValue::Text(part))
+    }
+
+    fn len(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [text] = args else {
+            return Err(FormulaError::Value);
+        };
+
+        let len = self.text_arg(text)?.graphemes(true).count();
+        Ok(CellValue::Number(len as f64))
+    }
+
+    fn map_text(
+        &self,
+        args: &[Expr],
+        function: fn(&str) -> String,
+    ) -> Result<CellValue, FormulaError> {
+        let [text] = args else {
+            return Err(FormulaError::Value);
+        };
+
+        Ok(CellValue::Text(function(&self.text_arg(text)?)))
+    }
+
+    // SUBSTITUTE(text, old, new, [instance]) replaces every occurrence of old,
+    // or only the given one
+    fn substitute(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let (text, old, new, instance) = match args {
+            [text, old, new] => (text, old, new, None),
+            [text, old, new, instance] => (text, old, new, Some(instance)),
+            _ => return Err(FormulaError::Value),
+        };
+        let text = self.text_arg(text)?;
+        let old = self.text_arg(old)?;
+        let new = self.text_arg(new)?;
+
+        if old.is_empty() {
+            return Ok(CellValue::Text(text));
+        }
+
+        let result = match instance {
+            None => text.replace(&old, &new),
+            Some(instance) => {
+                let instance = self.count_arg(Some(instance), 0)?;
+                if instance == 0 {
+                    return Err(FormulaError::Value);
+                }
+                match text.match_indices(&old).nth(instance - 1) {
+                    Some((index, _)) => {
+                        format!("{}{}{}", &text[..index], new, &text[index + old.len()..])
+                    }
+                    None => text,
+                }
+            }
+        };
+
+        Ok(CellValue::Text(result))
+    }
+
+    // TEXT(value, format) formats numbers the same way cells are rendered, or
+    // as a date with a pattern like yyyy-mm-dd
+    fn text(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [value, format] = args else {
+            return Err(FormulaError::Value);
+        };
+        let format = self.text_arg(format)?;
+
+        let n = match self.eval(value) {
+            CellValue::Error(error) => return Err(error),
+            CellValue::Text(text) => match text.trim().parse::<f64>() {
+                Ok(n) => n,
+                Err(_) => return Ok(CellValue::Text(text)),
+            },
+            value => value.as_number().unwrap_or(0.0),
+        };
+
+        if is_date_pattern(&format) {
+            return format_date_pattern(n, &format)
+                .map(CellValue::Text)
+                .ok_or(FormulaError::Value);
+        }
+        Ok(CellValue::Text(format_number(n, &format)))
+    }
+
+    // SPLIT(text, delimiter, [index]) gives the part at the one based index,
+    // the first one by default
+    fn split(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let (text, delimiter, index) = match args {
+            [text, delimiter] => (text, delimiter, None),
+            [text, delimiter, index] => (text, delimiter, Some(index)),
+            _ => return Err(FormulaError::Value),
+        };
+        let text = self.text_arg(text)?;
+        let delimiter = self.text_arg(delimiter)?;
+        if delimiter.is_empty() {
+            return Err(FormulaError::Value);
+        }
+
+        // Without an index the parts spill across the row
+        let Some(index) = index else {
+            let parts = text
+                .split(delimiter.as_str())
+                .map(|part| CellValue::Text(part.to_string()))
+                .collect();
+            return Ok(CellValue::Array(vec![parts]));
+        };
+        let index = self.count_arg(Some(index), 1)?;
+        if index == 0 {
+            return Err(FormulaError::Value);
+        }
+
+        text.split(delimiter.as_str())
+            .nth(index - 1)
+            .map(|part| CellValue::Text(part.to_string()))
+            .ok_or(FormulaError::NotAvailable)
+    }
+
+    // Array functions return rows of values that spill into the cells
+    // around the formula
+
+    // Rows of an array argument, a reference, an array result or a single value
+    fn array_arg(&self, arg: &Expr) -> Result<Vec<Vec<CellValue>>, FormulaError> {
+        if let Some(region) = self.reference(arg) {
+            return Ok(region?.to_array());
+        }
+        match self.eval(arg) {
+            CellValue::Array(rows) => Ok(rows),
+            CellValue::Error(error) => Err(error),
+            value => Ok(vec![vec![value]]),
+        }
+    }
+
+    fn flag_arg(&self, arg: Option<&Expr>) -> Result<bool, FormulaError> {
+        match arg {
+            Some(arg) => to_bool(self.eval(arg)),
+            None => Ok(false),
+        }
+    }
+
+    // SEQUENCE(rows, [columns], [start], [step]) counts along each row, then down
+    fn sequence(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let number = |index: usize, default: f64| match args.get(index) {
+            Some(arg) => self.number_arg(arg),
+            None => Ok(default),
+        };
+        let rows = number(0, 1.0)?.trunc();
+        let cols = number(1, 1.0)?.trunc();
+        let start = number(2, 1.0)?;
+        let step = number(3, 1.0)?;
+
+        if rows < 1.0 || cols < 1.0 {
+            return Err(FormulaError::Calc);
+        }
+        if rows * cols > MAX_ARRAY_SIZE {
+            return Err(FormulaError::Num);
+        }
+        let (rows, cols) = (rows as usize, cols as usize);
+        Ok(CellValue::Array(
+            (0..rows)
+                .map(|row| {
+                    (0..cols)
+                        .map(|col| CellValue::Number(start + step * (row * cols + col) as f64))
+                        .collect()
+                })
+                .collect(),
+        ))
+    }
+
+    // FILTER(array, include, [if empty]) keeps the rows where include, a
+    // column of conditions, is TRUE. A row of conditions filters columns
+    fn filter(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let array = self.array_arg(&args[0])?;
+        let include = self.array_arg(&args[1])?;
+        let flags = |values: Vec<CellValue>| {
+            values
+                .into_iter()
+                .map(to_bool)
+                .collect::<Result<Vec<bool>, FormulaError>>()
+        };
+
+        let result: Vec<Vec<CellValue>> = if include.len() == array.len() && include[0].len() == 1 {
+            let keep = flags(include.into_iter().flatten().collect())?;
+            array
+                .into_iter()
+                .zip(keep)
+                .filter(|(_, keep)| *keep)
+                .map(|(row, _)| row)
+                .collect()
+        } else if include.len() == 1 && include[0].len() == array[0].len() {
+            let keep = flags(include.into_iter().flatten().collect())?;
+            array
+                .into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .zip(&keep)
+                        .filter(|(_, keep)| **keep)
+                        .map(|(value, _)| value)
+                        .collect()
+                })
+                .collect()
+        } else {
+            return Err(FormulaError::Value);
+        };
+
+        if result.is_empty() || result[0].is_empty() {
+            return match args.get(2) {
+                Some(if_empty) => Ok(self.eval(if_empty)),
+                None => Err(FormulaError::Calc),
+            };
+        }
+        Ok(CellValue::Array(result))
+    }
+
+    // SORT(array, [sort index], [order], [by column]) sorts the rows by one of
+    // their columns, or the columns by one of their rows. Order -1 is descending
+    fn sort(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let array = self.array_arg(&args[0])?;
+        let index = match args.get(1) {
+            Some(arg) => self.number_arg(arg)?.trunc(),
+            None => 1.0,
+        };
+        let order = match args.get(2) {
+            Some(arg) => self.number_arg(arg)?,
+            None => 1.0,
+        };
+        if order != 1.0 && order != -1.0 {
+            return Err(FormulaError::Value);
+        }
+        let by_col = self.flag_arg(args.get(3))?;
+
+        let mut lines = if by_col { transpose(array) } else { array };
+        if index < 1.0 || index as usize > lines[0].len() {
+            return Err(FormulaError::Value);
+        }
+        let key = index as usize - 1;
+        lines.sort_by(|a, b| {
+            let ordering = compare_values(&a[key], &b[key]);
+            if order < 0.0 {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+
+        Ok(CellValue::Array(if by_col {
+            transpose(lines)
+        } else {
+            lines
+        }))
+    }
+
+    // UNIQUE(array, [by column], [exactly once]) drops repeated rows, or
+    // columns. With exactly once, rows that are repeated are dropped entirely
+    fn unique(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let array = self.array_arg(&args[0])?;
+        let by_col = self.flag_arg(args.get(1))?;
+        let exactly_once = self.flag_arg(args.get(2))?;
+
+        let lines = if by_col { transpose(array) } else { array };
+        let same = |a: &Vec<CellValue>, b: &Vec<CellValue>| {
+            a.iter()
+                .zip(b)
+                .all(|(a, b)| compare_values(a, b) == Ordering::Equal)
+        };
+        let mut result: Vec<Vec<CellValue>> = Vec::new();
+        for line in &lines {
+            let count = lines.iter().filter(|other| same(line, other)).count();
+            let seen = result.iter().any(|other| same(line, other));
+            if !seen && (!exactly_once || count == 1) {
+                result.push(line.clone());
+            }
+        }
+
+        if result.is_empty() {
+            return Err(FormulaError::Calc);
+        }
+        Ok(CellValue::Array(if by_col {
+            transpose(result)
+        } else {
+            result
+        }))
+    }
+
+    fn transpose(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        Ok(CellValue::Array(transpose(self.array_arg(&args[0])?)))
+    }
+
+    // Whole day serial of a date argument up to 9999-12-31, text like
+    // "2024-01-15" works too
+    fn date_arg(&self, arg: &Expr) -> Result<f64, FormulaError> {
+        let serial = self.number_arg(arg)?.floor();
+        if !(0.0..=MAX_SERIAL).contains(&serial) {
+            return Err(FormulaError::Num);
+        }
+        Ok(serial)
+    }
+
+    fn ymd_arg(&self, arg: &Expr) -> Result<(i64, u32, u32), FormulaError> {
+        date_from_serial(self.date_arg(arg)?).ok_or(FormulaError::Num)
+    }
+
+    // DATE(year, month, day), years below 1900 count from 1900 like in Excel
+    fn date(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [year, month, day] = args else {
+            return Err(FormulaError::Value);
+        };
+        let year = self.number_arg(year)?.trunc() as i64;
+        let month = self.number_arg(month)?.trunc() as i64;
+        let day = self.number_arg(day)?.trunc() as i64;
+
+        let year = if (0..1900).contains(&year) {
+            year + 1900
+        } else {
+            year
+        };
+        if !(0..=9999).contains(&year) {
+            return Err(FormulaError::Num);
+        }
+
+        serial_from_date(year, month, day)
+            .map(CellValue::Date)
+            .ok_or(FormulaError::Num)
+    }
+
+    // NOW() and TODAY(), which drops the time of day
+    fn now(&self, args: &[Expr], whole_day: bool) -> Result<CellValue, FormulaError> {
+        if !args.is_empty() {
+            return Err(FormulaError::Value);
+        }
+
+        let now = self.clock.now();
+        Ok(CellValue::Date(if whole_day { now.floor() } else { now }))
+    }
+
+    fn date_part(
+        &self,
+        args: &[Expr],
+        part: fn((i64, u32, u32)) -> f64,
+    ) -> Result<CellValue, FormulaError> {
+        let [date] = args else {
+            return Err(FormulaError::Value);
+        };
+
+        Ok(CellValue::Number(part(self.ymd_arg(date)?)))
+    }
+
+    // DATEDIF(start, end, unit) counts whole years "Y", months "M" or days "D"
+    // between two dates, "YM", "MD" and "YD" ignore the larger units
+    fn date_dif(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [start, end, unit] = args else {
+            return Err(FormulaError::Value);
+        };
+        let start_serial = self.date_arg(start)?;
+        let end_serial = self.date_arg(end)?;
+        let unit = self.text_arg(unit)?.to_uppercase();
+
+        if start_serial > end_serial {
+            return Err(FormulaError::Num);
+        }
+        let (start_year, start_month, start_day) = self.ymd_arg(start)?;
+        let (end_year, end_month, end_day) = self.ymd_arg(end)?;
+
+        let mut months = (end_year - start_year) * 12 + end_month as i64 - start_month as i64;
+        if end_day < start_day {
+            months -= 1;
+        }
+
+        let result = match unit.as_str() {
+            "D" => end_serial - start_serial,
+            "M" => months as f64,
+            "Y" => (months / 12) as f64,
+            "YM" => (months % 12) as f64,
+            "MD" if end_day >= start_day => (end_day - start_day) as f64,
+            "MD" => {
+                let (year, month) = if end_month == 1 {
+                    (end_year - 1, 12)
+                } else {
+                    (end_year, end_month - 1)
+                };
+                (days_in_month(year, month) as i64 - start_day as i64 + end_day as i64).max(0)
+                    as f64
+            }
+            "YD" => {
+                let mut anniversary =
+                    serial_from_date(end_year, start_month as i64, start_day as i64)
+                        .ok_or(FormulaError::Num)?;
+                if anniversary > end_serial {
+                    anniversary =
+                        serial_from_date(end_year - 1, start_month as i64, start_day as i64)
+                            .ok_or(FormulaError::Num)?;
+                }
+                end_serial - anniversary
+            }
+            _ => return Err(FormulaError::Num),
+        };
+
+        Ok(CellValue::Number(result))
+    }
+
+    // EDATE(start, months) is the same day some months later, or the last
+    // day of that month when it's shorter
+    fn edate(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [start, months] = args else {
+            return Err(FormulaError::Value);
+        };
+        let (year, month, day) = self.ymd_arg(start)?;
+        let months = self.number_arg(months)?.trunc() as i64;
+
+        let index = (year * 12 + month as i64 - 1)
+            .checked_add(months)
+            .ok_or(FormulaError::Num)?;
+        let (year, month) = (index.div_euclid(12), index.rem_euclid(12) as u32 + 1);
+        if !(1900..=9999).contains(&year) {
+            return Err(FormulaError::Num);
+        }
+        let day = day.min(days_in_month(year, month));
+
+        serial_from_date(year, month as i64, day as i64)
+            .map(CellValue::Date)
+            .ok_or(FormulaError::Num)
+    }
+
+    // WEEKDAY(date, [type]) is 1 (Sunday) to 7 by default, type 2 starts
+    // at 1 on Monday and type 3 at 0 on Monday
+    fn weekday(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let (date, kind) = match args {
+            [date] => (date, 1.0),
+            [date, kind] => (date, self.number_arg(kind)?.trunc()),
+            _ => return Err(FormulaError::Value),
+        };
+        let sunday_based = day_of_week(self.date_arg(date)?);
+        let monday_based = (sunday_based + 6) % 7;
+
+        let weekday = match kind as i64 {
+            1 => sunday_based + 1,
+            2 => monday_based + 1,
+            3 => monday_based,
+            _ => return Err(FormulaError::Num),
+        };
+        Ok(CellValue::Number(weekday as f64))
+    }
+
+    // NETWORKDAYS(start, end, [holidays]) counts Monday to Friday, both ends
+    // included, negative when end is before start
+    fn network_days(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let (start, end, holidays) = match args {
+            [start, end] => (start, end, None),
+            [start, end, holidays] => (start, end, Some(holidays)),
+            _ => return Err(FormulaError::Value),
+        };
+        let start = self.date_arg(start)?;
+        let end = self.date_arg(end)?;
+
+        let mut skip = Vec::new();
+        match holidays.map(|holidays| (holidays, self.reference(holidays))) {
+            Some((_, Some(area))) => {
+                for value in area?.values() {
+                    match value {
+                        CellValue::Error(error) => return Err(error),
+                        CellValue::Empty => {}
+                        value => skip.push(to_number(value)?.floor()),
+                    }
+                }
+            }
+            Some((holidays, None)) => skip.push(self.date_arg(holidays)?),
+            None => {}
+        }
+
+        let (first, last, sign) = if start <= end {
+            (start, end, 1.0)
+        } else {
+            (end, start, -1.0)
+        };
+        let weekday = |serial: f64| (1..=5).contains(&day_of_week(serial));
+
+        // Whole weeks have 5 working days, the days left over are checked one by one
+        let weeks = ((last - first + 1.0) / 7.0).floor();
+        let mut days = weeks * 5.0;
+        let mut serial = first + weeks * 7.0;
+        while serial <= last {
+            if weekday(serial) {
+                days += 1.0;
+            }
+            serial += 1.0;
+        }
+
+        // Holidays are taken off once each, when they fall on a working day
+        skip.sort_by(f64::total_cmp);
+        skip.dedup();
+        let holidays = skip
+            .iter()
+            .filter(|&&holiday| (first..=last).contains(&holiday) && weekday(holiday))
+            .count();
+
+        Ok(CellValue::Number(sign * (days - holidays as f64)))
+    }
+
+    fn area_arg(&self, arg: &Expr) -> Result<Region<'a>, FormulaError> {
+        self.reference(arg).unwrap_or(Err(FormulaError::Value))
+    }
+
+    // Values of a single row or column, the ones the lookup functions search
+    fn line_values(&self, area: Region<'a>) -> Result<Vec<CellValue>, FormulaError> {
+        if area.rows() != 1 && area.cols() != 1 {
+            return Err(FormulaError::NotAvailable);
+        }
+        Ok(area.values().collect())
+    }
+
+    // Value to look for, errors in it propagate
+    fn lookup_arg(&self, arg: &Expr) -> Result<CellValue, FormulaError> {
+        match self.eval(arg) {
+            CellValue::Error(error) => Err(error),
+            value => Ok(value),
+        }
+    }
+
+    // VLOOKUP(value, table, column, [approximate]) searches the first column
+    // of the table and HLOOKUP(value, table, row, [approximate]) the first row
+    fn vlookup(&self, args: &[Expr], horizontal: bool) -> Result<CellValue, FormulaError> {
+        let (value, table, index, approximate) = match args {
+            [value, table, index] => (value, table, index, true),
+            [value, table, index, approximate] => {
+                (value, table, index, to_bool(self.eval(approximate))?)
+            }
+            _ => return Err(FormulaError::Value),
+        };
+        let value = self.lookup_arg(value)?;
+        let table = self.area_arg(table)?;
+        let index = self.number_arg(index)?.trunc();
+
+        let size = if horizontal {
+            table.rows()
+        } else {
+            table.cols()
+        };
+        if index < 1.0 {
+            return Err(FormulaError::Value);
+        }
+        if index as usize > size {
+            return Err(FormulaError::Ref);
+        }
+        let offset = index as usize - 1;
+
+        let keys = if horizontal {
+            table.with_area(Area::new(table.start, (table.start.0, table.end.1)))
+        } else {
+            table.with_area(Area::new(table.start, (table.end.0, table.start.1)))
+        };
+        let keys = self.line_values(keys)?;
+        let found = if approximate {
+            find_match(&value, &keys, MatchMode::NextSmaller, true)
+        } else {
+            find_match(&value, &keys, MatchMode::Wildcard, false)
+        }
+        .ok_or(FormulaError::NotAvailable)?;
+
+        Ok(if horizontal {
+            table.value(table.start.0 + offset, table.start.1 + found)
+        } else {
+            table.value(table.start.0 + found, table.start.1 + offset)
+        })
+    }
+
+    // XLOOKUP(value, lookup, return, [if not found], [match mode], [search mode])
+    // match mode 0 is exact, -1 exact or next smaller, 1 exact or next larger
+    // and 2 wildcards, search mode 1 goes from the first item and -1 from the last
+    fn xlookup(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        if !(3..=6).contains(&args.len()) {
+            return Err(FormulaError::Value);
+        }
+        let value = self.lookup_arg(&args[0])?;
+        let lookup = self.area_arg(&args[1])?;
+        let result = self.area_arg(&args[2])?;
+        let mode = match args.get(4) {
+            Some(mode) => self.number_arg(mode)?.trunc() as i64,
+            None => 0,
+        };
+        let search = match args.get(5) {
+            Some(search) => self.number_arg(search)?.trunc() as i64,
+            None => 1,
+        };
+
+        let mode = match mode {
+            0 => MatchMode::Exact,
+            -1 => MatchMode::NextSmaller,
+            1 => MatchMode::NextLarger,
+            2 => MatchMode::Wildcard,
+            _ => return Err(FormulaError::Value),
+        };
+        // Binary search modes give the same results on the sorted data they need
+        let reverse = match search {
+            1 | 2 => false,
+            -1 | -2 => true,
+            _ => return Err(FormulaError::Value),
+        };
+
+        let vertical = lookup.cols() == 1;
+        let matching_size = if vertical {
+            result.rows() == lookup.rows()
+        } else {
+            result.cols() == lookup.cols()
+        };
+        if !matching_size {
+            return Err(FormulaError::Value);
+        }
+
+        let keys = self.line_values(lookup)?;
+        match find_match(&value, &keys, mode, reverse) {
+            Some(found) if vertical => Ok(result.value(result.start.0 + found, result.start.1)),
+            Some(found) => Ok(result.value(result.start.0, result.start.1 + found)),
+            // The if not found value is only evaluated when it's needed
+            None => match args.get(3) {
+                Some(if_not_found) => Ok(self.eval(if_not_found)),
+                None => Err(FormulaError::NotAvailable),
+            },
+        }
+    }
+
+    // INDEX(range, row, [column]), a row or column of 0 selects all of them
+    // and a single row or column range takes just the position along it
+    fn index_area(&self, args: &[Expr]) -> Result<Region<'a>, FormulaError> {
+        let (range, row, col) = match args {
+            [range, row] => (range, row, None),
+            [range, row, col] => (range, row, Some(col)),
+            _ => return Err(FormulaError::Value),
+        };
+        let area = self.area_arg(range)?;
+        let first = self.number_arg(row)?.trunc();
+        let second = match col {
+            Some(col) => Some(self.number_arg(col)?.trunc()),
+            None => None,
+        };
+
+        let (row, col) = match second {
+            Some(col) => (first, col),
+            None if area.rows() == 1 => (1.0, first),
+            None if area.cols() == 1 => (first, 1.0),
+            None => (first, 0.0),
+        };
+        if row < 0.0 || col < 0.0 {
+            return Err(FormulaError::Value);
+        }
+        let (row, col) = (row as usize, col as usize);
+        if row > area.rows() || col > area.cols() {
+            return Err(FormulaError::Ref);
+        }
+
+        let (start_row, end_row) = match row {
+            0 => (area.start.0, area.end.0),
+            row => (area.start.0 + row - 1, area.start.0 + row - 1),
+        };
+        let (start_col, end_col) = match col {
+            0 => (area.start.1, area.end.1),
+            col => (area.start.1 + col - 1, area.start.1 + col - 1),
+        };
+        Ok(area.with_area(Area::new((start_row, start_col), (end_row, end_col))))
+    }
+
+    fn index(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let area = self.index_area(args)?;
+        if area.start != area.end {
+            return Err(FormulaError::Value);
+        }
+
+        Ok(area.value(area.start.0, area.start.1))
+    }
+
+    // MATCH(value, range, [type]) is the one based position of the value,
+    // type 1 finds the largest value below it in ascending data, 0 an exact
+    // match and -1 the smallest value above it in descending data
+    fn match_(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let (value, range, kind) = match args {
+            [value, range] => (value, range, 1.0),
+            [value, range, kind] => (value, range, self.number_arg(kind)?.trunc()),
+            _ => return Err(FormulaError::Value),
+        };
+        let value = self.lookup_arg(value)?;
+        let keys = self.line_values(self.area_arg(range)?)?;
+
+        let found = match kind {
+            kind if kind > 0.0 => find_match(&value, &keys, MatchMode::NextSmaller, true),
+            kind if kind < 0.0 => find_match(&value, &keys, MatchMode::NextLarger, true),
+            _ => find_match(&value, &keys, MatchMode::Wildcard, false),
+        };
+
+        found
+            .map(|index| CellValue::Number(index as f64 + 1.0))
+            .ok_or(FormulaError::NotAvailable)
+    }
+
+    // Offsets inside the ranges of the cells meeting every (range, criteria)
+    // pair, with the size the ranges share
+    fn matching_offsets(&self, pairs: &[Expr]) -> Result<Matches, FormulaError> {
+        if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+            return Err(FormulaError::Value);
+        }
+
+        let mut conditions = Vec::new();
+        for pair in pairs.chunks(2) {
+            let area = self.area_arg(&pair[0])?;
+            let criteria = Criteria::parse(self.eval(&pair[1]))?;
+            conditions.push((area, criteria));
+        }
+
+        let size = (conditions[0].0.rows(), conditions[0].0.cols());
+        if conditions
+            .iter()
+            .any(|(area, _)| (area.rows(), area.cols()) != size)
+        {
+            return Err(FormulaError::Value);
+        }
+
+        // Cells past the edge of each area's sheet are all blank, whole
+        // columns stop where the longest sheet does
+        let extent = |sheet_size: usize, start: usize, size: usize| {
+            sheet_size.saturating_sub(start).min(size)
+        };
+        let rows = conditions
+            .iter()
+            .map(|(area, _)| extent(area.cells.len(), area.start.0, size.0))
+            .max()
+            .unwrap_or(0);
+        let cols = conditions
+            .iter()
+            .map(|(area, _)| {
+                let sheet_cols = area.cells.first().map_or(0, |row| row.len());
+                extent(sheet_cols, area.start.1, size.1)
+            })
+            .max()
+            .unwrap_or(0);
+        let mut offsets = Vec::new();
+        for row in 0..rows {
+            for col in 0..cols {
+                let matches = conditions.iter().all(|(area, criteria)| {
+                    criteria.matches(&area.value(area.start.0 + row, area.start.1 + col))
+                });
+                if matches {
+                    offsets.push((row, col));
+                }
+            }
+        }
+
+        Ok((offsets, size))
+    }
+
+    // SUMIF(range, criteria, [sum range]) and AVERAGEIF, which use the
+    // criteria range itself when there's no separate range to add up
+    fn aggregate_if(&self, args: &[Expr], aggregate: Aggregate) -> Result<CellValue, FormulaError> {
+        let target = match args {
+            [range, _] => range,
+            [_, _, target] => target,
+            _ => return Err(FormulaError::Value),
+        };
+        let (offsets, _) = self.matching_offsets(&args[..2])?;
+        let target = self.area_arg(target)?;
+
+        self.aggregate(aggregate, target, &offsets)
+    }
+
+    // SUMIFS(sum range, range1, criteria1, ...), MAXIFS and MINIFS
+    fn aggregate_ifs(
+        &self,
+        args: &[Expr],
+        aggregate: Aggregate,
+    ) -> Result<CellValue, FormulaError> {
+        let [target, pairs @ ..] = args else {
+            return Err(FormulaError::Value);
+        };
+        let (offsets, size) = self.matching_offsets(pairs)?;
+        let target = self.area_arg(target)?;
+        if (target.rows(), target.cols()) != size {
+            return Err(FormulaError::Value);
+        }
+
+        self.aggregate(aggregate, target, &offsets)
+    }
+
+    // COUNTIF(range, criteria) and COUNTIFS(range1, criteria1, ...)
+    fn count_ifs(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let (offsets, _) = self.matching_offsets(args)?;
+        Ok(CellValue::Number(offsets.len() as f64))
+    }
+
+    // Combines the numbers at the offsets from the top left of the target,
+    // skipping text and blanks like SUM does
+    fn aggregate(
+        &self,
+        aggregate: Aggregate,
+        target: Region<'a>,
+        offsets: &[(usize, usize)],
+    ) -> Result<CellValue, FormulaError> {
+        let mut numbers = Vec::new();
+        for (row, col) in offsets {
+            match target.value(target.start.0 + row, target.start.1 + col) {
+                CellValue::Error(error) => return Err(error),
+                CellValue::Number(n) | CellValue::Date(n) => numbers.push(n),
+                _ => {}
+            }
+        }
+
+        match aggregate {
+            Aggregate::Sum => Ok(self.fold_numbers(BinaryOp::Add, &numbers)),
+            Aggregate::Average if numbers.is_empty() => Err(FormulaError::DivZero),
+            Aggregate::Average => {
+                let sum = to_number(self.fold_numbers(BinaryOp::Add, &numbers))?;
+                Ok(arithmetic(
+                    self.mode,
+                    BinaryOp::Divide,
+                    sum,
+                    numbers.len() as f64,
+                ))
+            }
+            Aggregate::Max => Ok(CellValue::Number(
+                numbers.into_iter().reduce(f64::max).unwrap_or(0.0),
+            )),
+            Aggregate::Min => Ok(CellValue::Number(
+                numbers.into_iter().reduce(f64::min).unwrap_or(0.0),
+            )),
+        }
+    }
+
+    fn median(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let numbers = sorted(self.numbers(args)?);
+        if numbers.is_empty() {
+            return Err(FormulaError::Num);
+        }
+
+        finite(percentile_of(&numbers, 0.5))
+    }
+
+    // Most frequent number, the first one to appear on a tie
+    fn mode(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let numbers = self.numbers(args)?;
+
+        let mut best: Option<(f64, usize)> = None;
+        for &n in &numbers {
+            let count = numbers.iter().filter(|other| **other == n).count();
+            if count > 1 && best.is_none_or(|(_, most)| count > most) {
+                best = Some((n, count));
+            }
+        }
+
+        best.map(|(n, _)| CellValue::Number(n))
+            .ok_or(FormulaError::NotAvailable)
+    }
+
+    // VAR.S, VAR.P and the square root of them, STDEV.S and STDEV.P
+    fn variance(&self, args: &[Expr], sample: bool, root: bool) -> Result<CellValue, FormulaError> {
+        let numbers = self.numbers(args)?;
+        let count = numbers.len() as f64;
+        let divisor = if sample { count - 1.0 } else { count };
+        if divisor <= 0.0 {
+            return Err(FormulaError::DivZero);
+        }
+
+        let (_, squares) = mean_and_squares(&numbers);
+        let variance = squares / divisor;
+        finite(if root { variance.sqrt() } else { variance })
+    }
+
+    // PERCENTILE(numbers, k) interpolates between the closest values like
+    // PERCENTILE.INC, QUARTILE(numbers, q) is the same with k = q / 4
+    fn percentile(&self, args: &[Expr], parts: f64) -> Result<CellValue, FormulaError> {
+        let [numbers, k] = args else {
+            return Err(FormulaError::Value);
+        };
+        let numbers = sorted(self.numbers(std::slice::from_ref(numbers))?);
+        let k = self.number_arg(k)?;
+        let k = if parts == 1.0 { k } else { k.trunc() };
+
+        if numbers.is_empty() || !(0.0..=parts).contains(&k) {
+            return Err(FormulaError::Num);
+        }
+        finite(percentile_of(&numbers, k / parts))
+    }
+
+    // Pearson correlation of two ranges of the same size, pairs where
+    // either side isn't a number are left out
+    fn correl(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [left, right] = args else {
+            return Err(FormulaError::Value);
+        };
+        let left = self.values_arg(left)?;
+        let right = self.values_arg(right)?;
+        if left.len() != right.len() {
+            return Err(FormulaError::NotAvailable);
+        }
+
+        let mut xs = Vec::new();
+        let mut ys = Vec::new();
+        for (x, y) in left.into_iter().zip(right) {
+            match (x, y) {
+                (CellValue::Error(error), _) | (_, CellValue::Error(error)) => return Err(error),
+                (
+                    CellValue::Number(x) | CellValue::Date(x),
+                    CellValue::Number(y) | CellValue::Date(y),
+                ) => {
+                    xs.push(x);
+                    ys.push(y);
+                }
+                _ => {}
+            }
+        }
+
+        // Deviations from the means rather than sums of products, which
+        // cancel out badly when the values are large
+        let (x_mean, x_squares) = mean_and_squares(&xs);
+        let (y_mean, y_squares) = mean_and_squares(&ys);
+        let products: f64 = xs
+            .iter()
+            .zip(&ys)
+            .map(|(x, y)| (x - x_mean) * (y - y_mean))
+            .sum();
+
+        if x_squares == 0.0 || y_squares == 0.0 {
+            return Err(FormulaError::DivZero);
+        }
+        finite(products / (x_squares * y_squares).sqrt())
+    }
+
+    // Values of a reference, or the single value of any other argument
+    fn values_arg(&self, arg: &Expr) -> Result<Vec<CellValue>, FormulaError> {
+        match self.reference(arg) {
+            Some(area) => Ok(area?.values().collect()),
+            None => match self.eval(arg) {
+                CellValue::Array(rows) => Ok(rows.into_iter().flatten().collect()),
+                value => Ok(vec![value]),
+            },
+        }
+    }
+
+    // RANK(number, numbers, [order]) is 1 for the largest number, or for the
+    // smallest when order isn't 0. Equal numbers share a rank
+    fn rank(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let (number, numbers, ascending) = match args {
+            [number, numbers] => (number, numbers, false),
+            [number, numbers, order] => (number, numbers, self.number_arg(order)? != 0.0),
+            _ => return Err(FormulaError::Value),
+        };
+        let number = self.number_arg(number)?;
+        let numbers = self.numbers(std::slice::from_ref(numbers))?;
+
+        if !numbers.contains(&number) {
+            return Err(FormulaError::NotAvailable);
+        }
+        let before = numbers
+            .iter()
+            .filter(|n| {
+                if ascending {
+                    **n < number
+                } else {
+                    **n > number
+                }
+            })
+            .count();
+        Ok(CellValue::Number(before as f64 + 1.0))
+    }
+
+    // LARGE(numbers, k) and SMALL(numbers, k), the k-th largest or smallest
+    fn kth(&self, args: &[Expr], largest: bool) -> Result<CellValue, FormulaError> {
+        let [numbers, k] = args else {
+            return Err(FormulaError::Value);
+        };
+        let mut numbers = sorted(self.numbers(std::slice::from_ref(numbers))?);
+        let k = self.number_arg(k)?.ceil();
+
+        if k < 1.0 || k as usize > numbers.len() {
+            return Err(FormulaError::Num);
+        }
+        if largest {
+            numbers.reverse();
+        }
+        Ok(CellValue::Number(numbers[k as usize - 1]))
+    }
+
+    // The time value of money functions follow Excel's sign convention:
+    // money paid out is negative and money received is positive. Type 1
+    // means payments at the start of each period instead of the end
+
+    // Required arguments followed by optional ones defaulting to 0, so
+    // PMT(rate, nper, pv, [fv], [type]) is tvm_args(args, 3)
+    fn tvm_args(&self, args: &[Expr], required: usize) -> Result<[f64; 5], FormulaError> {
+        if args.len() < required || args.len() > 5 {
+            return Err(FormulaError::Value);
+        }
+
+        let mut values = [0.0; 5];
+        for (value, arg) in values.iter_mut().zip(args) {
+            *value = self.number_arg(arg)?;
+        }
+        values[4] = if values[4] != 0.0 { 1.0 } else { 0.0 };
+        Ok(values)
+    }
+
+    // PMT(rate, nper, pv, [fv], [type]) is the payment of each period
+    fn pmt(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [rate, nper, pv, fv, kind] = self.tvm_args(args, 3)?;
+        if nper == 0.0 {
+            return Err(FormulaError::Num);
+        }
+
+        if rate == 0.0 {
+            return finite(-(pv + fv) / nper);
+        }
+        let growth = (1.0 + rate).powf(nper);
+        finite(-(rate * (fv + pv * growth)) / ((1.0 + rate * kind) * (growth - 1.0)))
+    }
+
+    // PV(rate, nper, pmt, [fv], [type]) is what the payments are worth today
+    fn pv(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [rate, nper, pmt, fv, kind] = self.tvm_args(args, 3)?;
+
+        if rate == 0.0 {
+            return finite(-(fv + pmt * nper));
+        }
+        let growth = (1.0 + rate).powf(nper);
+        finite(-(fv + pmt * (1.0 + rate * kind) * (growth - 1.0) / rate) / growth)
+    }
+
+    // FV(rate, nper, pmt, [pv], [type]) is what the payments are worth at the end
+    fn fv(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [rate, nper, pmt, pv, kind] = self.tvm_args(args, 3)?;
+
+        if rate == 0.0 {
+            return finite(-(pv + pmt * nper));
+        }
+        let growth = (1.0 + rate).powf(nper);
+        finite(-(pv * growth + pmt * (1.0 + rate * kind) * (growth - 1.0) / rate))
+    }
+
+    // NPER(rate, pmt, pv, [fv], [type]) is the number of periods needed
+    fn nper(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [rate, pmt, pv, fv, kind] = self.tvm_args(args, 3)?;
+
+        if rate == 0.0 {
+            if pmt == 0.0 {
+                return Err(FormulaError::Num);
+            }
+            return finite(-(pv + fv) / pmt);
+        }
+        let payment = pmt * (1.0 + rate * kind);
+        let ratio = (payment - fv * rate) / (payment + pv * rate);
+        if ratio <= 0.0 {
+            return Err(FormulaError::Num);
+        }
+        finite(ratio.ln() / (1.0 + rate).ln())
+    }
+
+    // RATE(nper, pmt, pv, [fv], [type], [guess]) solves the rate FV and PV use
+    fn rate(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let (args, guess) = match args.get(5) {
+            Some(guess) => (&args[..5], self.number_arg(guess)?),
+            None => (args, 0.1),
+        };
+        let [nper, pmt, pv, fv, kind] = self.tvm_args(args, 3)?;
+
+        let balance = |rate: f64| {
+            if rate == 0.0 {
+                return pv + pmt * nper + fv;
+            }
+            let growth = (1.0 + rate).powf(nper);
+            pv * growth + pmt * (1.0 + rate * kind) * (growth - 1.0) / rate + fv
+        };
+        solve_rate(guess, balance).map(CellValue::Number)
+    }
+
+    // NPV(rate, value1, ...) discounts values at the end of each period
+    fn npv(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [rate, values @ ..] = args else {
+            return Err(FormulaError::Value);
+        };
+        let rate = self.number_arg(rate)?;
+        let values = self.numbers(values)?;
+        if rate == -1.0 {
+            return Err(FormulaError::DivZero);
+        }
+
+        finite(present_value(rate, &values, |period| period as f64 + 1.0))
+    }
+
+    // IRR(values, [guess]) is the rate where the NPV of the values, starting
+    // now, is 0
+    fn irr(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let (values, guess) = match args {
+            [values] => (values, 0.1),
+            [values, guess] => (values, self.number_arg(guess)?),
+            _ => return Err(FormulaError::Value),
+        };
+        let values = self.numbers(std::slice::from_ref(values))?;
+        if !has_both_signs(&values) {
+            return Err(FormulaError::Num);
+        }
+
+        solve_rate(guess, |rate| {
+            present_value(rate, &values, |period| period as f64)
+        })
+        .map(CellValue::Number)
+    }
+
+    // Values and dates of XNPV and XIRR, years counted in days / 365 from the first date
+    fn dated_values(
+        &self,
+        values: &Expr,
+        dates: &Expr,
+    ) -> Result<(Vec<f64>, Vec<f64>), FormulaError> {
+        let values = self.numbers(std::slice::from_ref(values))?;
+        let dates = self.numbers(std::slice::from_ref(dates))?;
+        if values.is_empty() || values.len() != dates.len() {
+            return Err(FormulaError::Num);
+        }
+
+        let start = dates[0].floor();
+        let mut years = Vec::new();
+        for date in dates {
+            if date.floor() < start {
+                return Err(FormulaError::Num);
+            }
+            years.push((date.floor() - start) / 365.0);
+        }
+        Ok((values, years))
+    }
+
+    // XNPV(rate, values, dates) is NPV for payments on the given dates
+    fn xnpv(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let [rate, values, dates] = args else {
+            return Err(FormulaError::Value);
+        };
+        let rate = self.number_arg(rate)?;
+        let (values, years) = self.dated_values(values, dates)?;
+        if rate <= -1.0 {
+            return Err(FormulaError::Num);
+        }
+
+        finite(present_value(rate, &values, |index| years[index]))
+    }
+
+    // XIRR(values, dates, [guess]) is IRR for payments on the given dates
+    fn xirr(&self, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        let (values, dates, guess) = match args {
+            [values, dates] => (values, dates, 0.1),
+            [values, dates, guess] => (values, dates, self.number_arg(guess)?),
+            _ => return Err(FormulaError::Value),
+        };
+        let (values, years) = self.dated_values(values, dates)?;
+        if !has_both_signs(&values) {
+            return Err(FormulaError::Num);
+        }
+
+        solve_rate(guess, |rate| {
+            present_value(rate, &values, |index| years[index])
+        })
+        .map(CellValue::Number)
+    }
+}
+
+// Sum of the values discounted by how many periods (or years) away they are
+fn present_value(rate: f64, values: &[f64], periods: impl Fn(usize) -> f64) -> f64 {
+    values
+        .iter()
+        .enumerate()
+        .map(|(index, value)| value / (1.0 + rate).powf(periods(index)))
+        .sum()
+}
+
+// A rate of return only exists when money goes both in and out
+fn has_both_signs(values: &[f64]) -> bool {
+    values.iter().any(|value| *value > 0.0) && values.iter().any(|value| *value < 0.0)
+}
+
+const SOLVER_ITERATIONS: usize = 100;
+const SOLVER_PRECISION: f64 = 1e-10;
+
+// Newton's method for the rate where the function is 0, starting from the
+// guess. Rates stay above -100% and #NUM! means it didn't converge
+fn solve_rate(guess: f64, function: impl Fn(f64) -> f64) -> Result<f64, FormulaError> {
+    let mut rate = guess;
+
+    for _ in 0..SOLVER_ITERATIONS {
+        let value = function(rate);
+        let step_size = 1e-7 * rate.abs().max(1.0);
+        let slope = (function(rate + step_size) - function(rate - step_size)) / (2.0 * step_size);
+        if !value.is_finite() || !slope.is_finite() || slope == 0.0 {
+            return Err(FormulaError::Num);
+        }
+
+        let next = rate - value / slope;
+        // Overshooting goes halfway to -100% instead, which never counts as converged
+        if next <= -1.0 {
+            rate = (rate - 1.0) / 2.0;
+            continue;
+        }
+        if (next - rate).abs() < SOLVER_PRECISION {
+            return Ok(next);
+        }
+        rate = next;
+    }
+
+    Err(FormulaError::Num)
+}
+
+fn sorted(mut numbers: Vec<f64>) -> Vec<f64> {
+    numbers.sort_by(|a, b| a.total_cmp(b));
+    numbers
+}
+
+// Value at the fraction k of sorted numbers, interpolating linearly
+// between the two closest ones
+fn percentile_of(numbers: &[f64], k: f64) -> f64 {
+    let position = k * (numbers.len() - 1) as f64;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+    numbers[lower] + (position - lower as f64) * (numbers[upper] - numbers[lower])
+}
+
+// Mean and sum of squared deviations from it in one pass (Welford's
+// algorithm), which stays accurate for large numbers with a small spread
+fn mean_and_squares(numbers: &[f64]) -> (f64, f64) {
+    let mut mean = 0.0;
+    let mut squares = 0.0;
+    for (i, &n) in numbers.iter().enumerate() {
+        let delta = n - mean;
+        mean += delta / (i + 1) as f64;
+        squares += delta * (n - mean);
+    }
+    (mean, squares)
+}
+
+// Offsets from the top left of the areas of the cells matching all the
+// criteria, and the rows and columns every area has
+type Matches = (Vec<(usize, usize)>, (usize, usize));
+
+#[derive(Clone, Copy)]
+enum Aggregate {
+    Sum,
+    Average,
+    Max,
+    Min,
+}
+
+// Condition of the *IF and *IFS functions: a value like 10 or "apple", or
+// text starting with an operator like ">100", "<>x" or "<=2024-01-01".
+// Text compared for equality can use the * and ? wildcards
+struct Criteria {
+    op: CompareOp,
+    value: CellValue,
+}
+
+impl Criteria {
+    fn parse(criteria: CellValue) -> Result<Self, FormulaError> {
+        let text = match criteria {
+            CellValue::Error(error) => return Err(error),
+            CellValue::Text(text) => text,
+            value => {
+                return Ok(Self {
+                    op: CompareOp::Equal,
+                    value,
+                })
+            }
+        };
+
+        let operators = [
+            (">=", CompareOp::GreaterEqual),
+            ("<=", CompareOp::LessEqual),
+            ("<>", CompareOp::NotEqual),
+            (">", CompareOp::Greater),
+            ("<", CompareOp::Less),
+            ("=", CompareOp::Equal),
+        ];
+        let (op, operand) = operators
+            .iter()
+            .find_map(|(prefix, op)| text.strip_prefix(prefix).map(|rest| (*op, rest)))
+            .unwrap_or((CompareOp::Equal, &text));
+
+        Ok(Self {
+            op,
+            value: CellValue::parse(operand),
+        })
+    }
+
+    fn matches(&self, value: &CellValue) -> bool {
+        match self.op {
+            CompareOp::Equal => self.equals(value),
+            CompareOp::NotEqual => !self.equals(value),
+            op => same_kind(value, &self.value) && op.matches(compare_values(value, &self.value)),
+        }
+    }
+
+    fn equals(&self, value: &CellValue) -> bool {
+        match (&self.value, value) {
+            // "=" and "" only match blank cells
+            (CellValue::Empty, value) => {
+                matches!(value, CellValue::Empty)
+                    || matches!(value, CellValue::Text(text) if text.is_empty())
+            }
+            (CellValue::Text(pattern), CellValue::Text(text)) => wildcard_match(pattern, text),
+            (criteria, value) => {
+                same_kind(criteria, value) && compare_values(criteria, value) == Ordering::Equal
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MatchMode {
+    Exact,
+    NextSmaller,
+    NextLarger,
+    Wildcard,
+}
+
+// Whether two values can be ordered against each other in a lookup,
+// numbers only match numbers and text only matches text
+fn same_kind(left: &CellValue, right: &CellValue) -> bool {
+    let kind = |value: &CellValue| match value {
+        CellValue::Number(_) | CellValue::Date(_) => 1,
+        CellValue::Text(_) => 2,
+        CellValue::Bool(_) => 3,
+        _ => 0,
+    };
+    kind(left) != 0 && kind(left) == kind(right)
+}
+
+// Index of the value in the keys. Next smaller and next larger fall back to
+// the closest key on that side, ties go to the first key in search order
+fn find_match(
+    value: &CellValue,
+    keys: &[CellValue],
+    mode: MatchMode,
+    reverse: bool,
+) -> Option<usize> {
+    let order: Vec<usize> = if reverse {
+        (0..keys.len()).rev().collect()
+    } else {
+        (0..keys.len()).collect()
+    };
+
+    let mut closest: Option<usize> = None;
+    for index in order {
+        let key = &keys[index];
+        if !same_kind(value, key) {
+            continue;
+        }
+
+        if let (MatchMode::Wildcard, CellValue::Text(pattern), CellValue::Text(text)) =
+            (mode, value, key)
+        {
+            if wildcard_match(pattern, text) {
+                return Some(index);
+            }
+            continue;
+        }
+
+        let ordering = compare_values(key, value);
+        if ordering == Ordering::Equal {
+            return Some(index);
+        }
+        let side = match mode {
+            MatchMode::NextSmaller => Ordering::Less,
+            MatchMode::NextLarger => Ordering::Greater,
+            _ => continue,
+        };
+        let closer = match closest {
+            Some(closest) => compare_values(key, &keys[closest]) == side.reverse(),
+            None => true,
+        };
+        if ordering == side && closer {
+            closest = Some(index);
+        }
+    }
+
+    closest
+}
+
+// Excel wildcards, case insensitive: * is any run of characters, ? is one
+// character and ~ makes the next character literal
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    // Where to resume when the characters after the last * stop matching
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some('~') if p + 1 < pattern.len() && pattern[p + 1] == text[t] => {
+                p += 2;
+                t += 1;
+                continue;
+            }
+            Some('?') => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some(c) if *c != '~' && *c == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Like Excel's TRIM, drops leading and trailing spaces and keeps single spaces between words
+fn trim_spaces(text: &str) -> String {
+    text.split(' ')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn finite(n: f64) -> Result<CellValue, FormulaError> {
+    if n.is_finite() {
+        Ok(CellValue::Number(n))
+    } else {
+        Err(FormulaError::Num)
+    }
+}
+
+// Rounds in base 10 where possible, so 2.675 rounds up to 2.68 like Excel
+// even though the nearest float is slightly below it
+pub fn round_to(number: f64, digits: i32) -> f64 {
+    if digits >= 0 {
+        if let Some(decimal) = Decimal::from_f64(number) {
+            let rounded = decimal
+                .round_dp_with_strategy(digits as u32, RoundingStrategy::MidpointAwayFromZero);
+            return rounded.to_f64().unwrap_or(number);
+        }
+    }
+
+    let factor = 10f64.powi(digits);
+    (number * factor).round() / factor
+}
+
+// The built-in functions, each calling the evaluator method implementing it
+pub fn register_builtins(registry: &mut FunctionRegistry) {
+    use ArgKind::*;
+    let mut add = |name, arity, kinds, evaluate| {
+        registry.register(Builtin {
+            name,
+            arity,
+            kinds,
+            params: builtin_params(name),
+            evaluate,
+            volatile: false,
+        })
+    };
+
+    add("SUM", Arity::at_least(0), &[Any], |e, args| {
+        e.fold(BinaryOp::Add, args)
+    });
+    add("PRODUCT", Arity::at_least(0), &[Any], |e, args| {
+        e.fold(BinaryOp::Multiply, args)
+    });
+    add("DIFFERENCE", Arity::at_least(0), &[Any], |e, args| {
+        e.fold(BinaryOp::Subtract, args)
+    });
+    add("QUOTIENT", Arity::at_least(0), &[Any], |e, args| {
+        e.fold(BinaryOp::Divide, args)
+    });
+    add("AVERAGE", Arity::at_least(0), &[Any], |e, args| {
+        e.average(args)
+    });
+    add("MIN", Arity::at_least(0), &[Any], |e, args| {
+        e.extreme(args, f64::min)
+    });
+    add("MAX", Arity::at_least(0), &[Any], |e, args| {
+        e.extreme(args, f64::max)
+    });
+    add("COUNT", Arity::at_least(0), &[Any], |e, args| e.count(args));
+    add("COUNTA", Arity::at_least(0), &[Any], |e, args| {
+        e.count_all(args)
+    });
+    add("ROUND", Arity::exactly(2), &[Number, Number], |e, args| {
+        e.round(args)
+    });
+    add("ABS", Arity::exactly(1), &[Number], |e, args| {
+        e.unary_math(args, f64::abs)
+    });
+    add("SQRT", Arity::exactly(1), &[Number], |e, args| {
+        e.unary_math(args, f64::sqrt)
+    });
+    add("MOD", Arity::exactly(2), &[Number, Number], |e, args| {
+        e.modulo(args)
+    });
+    add("POWER", Arity::exactly(2), &[Number, Number], |e, args| {
+        e.power(args)
+    });
+    add(
+        "IF",
+        Arity::between(2, 3),
+        &[Logical, Value, Value],
+        |e, args| e.if_(args),
+    );
+    add("IFS", Arity::at_least(2), &[Logical, Value], |e, args| {
+        e.ifs(args)
+    });
+    add("AND", Arity::at_least(0), &[Logical], |e, args| {
+        e.and_or(args, false)
+    });
+    add("OR", Arity::at_least(0), &[Logical], |e, args| {
+        e.and_or(args, true)
+    });
+    add("NOT", Arity::exactly(1), &[Logical], |e, args| e.not(args));
+    add("IFERROR", Arity::exactly(2), &[Value, Value], |e, args| {
+        e.if_error(args)
+    });
+    add(
+        "SWITCH",
+        Arity::at_least(3),
+        &[Value, Value, Value],
+        |e, args| e.switch(args),
+    );
+    add("CONCAT", Arity::at_least(0), &[Any], |e, args| {
+        e.concat(args)
+    });
+    add("LEFT", Arity::between(1, 2), &[Text, Number], |e, args| {
+        e.left_right(args, false)
+    });
+    add("RIGHT", Arity::between(1, 2), &[Text, Number], |e, args| {
+        e.left_right(args, true)
+    });
+    add(
+        "MID",
+        Arity::exactly(3),
+        &[Text, Number, Number],
+        |e, args| e.mid(args),
+    );
+    add("LEN", Arity::exactly(1), &[Text], |e, args| e.len(args));
+    add("UPPER", Arity::exactly(1), &[Text], |e, args| {
+        e.map_text(args, |text| text.to_uppercase())
+    });
+    add("LOWER", Arity::exactly(1), &[Text], |e, args| {
+        e.map_text(args, |text| text.to_lowercase())
+    });
+    add("TRIM", Arity::exactly(1), &[Text], |e, args| {
+        e.map_text(args, trim_spaces)
+    });
+    add(
+        "SUBSTITUTE",
+        Arity::between(3, 4),
+        &[Text, Text, Text, Number],
+        |e, args| e.substitute(args),
+    );
+    add("TEXT", Arity::exactly(2), &[Value, Text], |e, args| {
+        e.text(args)
+    });
+    add(
+        "SPLIT",
+        Arity::between(2, 3),
+        &[Text, Text, Number],
+        |e, args| e.split(args),
+    );
+    add(
+        "DATE",
+        Arity::exactly(3),
+        &[Number, Number, Number],
+        |e, args| e.date(args),
+    );
+    add("YEAR", Arity::exactly(1), &[Number], |e, args| {
+        e.date_part(args, |(year, _, _)| year as f64)
+    });
+    add("MONTH", Arity::exactly(1), &[Number], |e, args| {
+        e.date_part(args, |(_, month, _)| month as f64)
+    });
+    add("DAY", Arity::exactly(1), &[Number], |e, args| {
+        e.date_part(args, |(_, _, day)| day as f64)
+    });
+    add(
+        "DATEDIF",
+        Arity::exactly(3),
+        &[Number, Number, Text],
+        |e, args| e.date_dif(args),
+    );
+    add("EDATE", Arity::exactly(2), &[Number, Number], |e, args| {
+        e.edate(args)
+    });
+    add(
+        "WEEKDAY",
+        Arity::between(1, 2),
+        &[Number, Number],
+        |e, args| e.weekday(args),
+    );
+    add(
+        "NETWORKDAYS",
+        Arity::between(2, 3),
+        &[Number, Number, Any],
+        |e, args| e.network_days(args),
+    );
+    add(
+        "VLOOKUP",
+        Arity::between(3, 4),
+        &[Value, Range, Number, Logical],
+        |e, args| e.vlookup(args, false),
+    );
+    add(
+        "HLOOKUP",
+        Arity::between(3, 4),
+        &[Value, Range, Number, Logical],
+        |e, args| e.vlookup(args, true),
+    );
+    add(
+        "XLOOKUP",
+        Arity::between(3, 6),
+        &[Value, Range, Range, Value, Number, Number],
+        |e, args| e.xlookup(args),
+    );
+    add(
+        "INDEX",
+        Arity::between(2, 3),
+        &[Range, Number, Number],
+        |e, args| e.index(args),
+    );
+    add(
+        "MATCH",
+        Arity::between(2, 3),
+        &[Value, Range, Number],
+        |e, args| e.match_(args),
+    );
+    add(
+        "SUMIF",
+        Arity::between(2, 3),
+        &[Range, Value, Range],
+        |e, args| e.aggregate_if(args, Aggregate::Sum),
+    );
+    add(
+        "SUMIFS",
+        Arity::at_least(3),
+        &[Range, Range, Value],
+        |e, args| e.aggregate_ifs(args, Aggregate::Sum),
+    );
+    add("COUNTIF", Arity::exactly(2), &[Range, Value], |e, args| {
+        e.count_ifs(args)
+    });
+    add(
+        "COUNTIFS",
+        Arity::at_least(0),
+        &[Range, Value],
+        |e, args| e.count_ifs(args),
+    );
+    add(
+        "AVERAGEIF",
+        Arity::between(2, 3),
+        &[Range, Value, Range],
+        |e, args| e.aggregate_if(args, Aggregate::Average),
+    );
+    add(
+        "MAXIFS",
+        Arity::at_least(3),
+        &[Range, Range, Value],
+        |e, args| e.aggregate_ifs(args, Aggregate::Max),
+    );
+    add(
+        "MINIFS",
+        Arity::at_least(3),
+        &[Range, Range, Value],
+        |e, args| e.aggregate_ifs(args, Aggregate::Min),
+    );
+    add("MEDIAN", Arity::at_least(0), &[Any], |e, args| {
+        e.median(args)
+    });
+    add("MODE", Arity::at_least(0), &[Any], |e, args| e.mode(args));
+    add("STDEV", Arity::at_least(0), &[Any], |e, args| {
+        e.variance(args, true, true)
+    });
+    add("STDEV.P", Arity::at_least(0), &[Any], |e, args| {
+        e.variance(args, false, true)
+    });
+    add("VAR", Arity::at_least(0), &[Any], |e, args| {
+        e.variance(args, true, false)
+    });
+    add("VAR.P", Arity::at_least(0), &[Any], |e, args| {
+        e.variance(args, false, false)
+    });
+    add(
+        "PERCENTILE",
+        Arity::exactly(2),
+        &[Any, Number],
+        |e, args| e.percentile(args, 1.0),
+    );
+    add("QUARTILE", Arity::exactly(2), &[Any, Number], |e, args| {
+        e.percentile(args, 4.0)
+    });
+    add("CORREL", Arity::exactly(2), &[Any, Any], |e, args| {
+        e.correl(args)
+    });
+    add(
+        "RANK",
+        Arity::between(2, 3),
+        &[Number, Any, Number],
+        |e, args| e.rank(args),
+    );
+    add("LARGE", Arity::exactly(2), &[Any, Number], |e, args| {
+        e.kth(args, true)
+    });
+    add("SMALL", Arity::exactly(2), &[Any, Number], |e, args| {
+        e.kth(args, false)
+    });
+    add("PMT", Arity::between(3, 5), &[Number], |e, args| {
+        e.pmt(args)
+    });
+    add("PV", Arity::between(3, 5), &[Number], |e, args| e.pv(args));
+    add("FV", Arity::between(3, 5), &[Number], |e, args| e.fv(args));
+    add("NPER", Arity::between(3, 5), &[Number], |e, args| {
+        e.nper(args)
+    });
+    add("RATE", Arity::between(3, 6), &[Number], |e, args| {
+        e.rate(args)
+    });
+    add("NPV", Arity::at_least(2), &[Number, Any], |e, args| {
+        e.npv(args)
+    });
+    add("IRR", Arity::between(1, 2), &[Any, Number], |e, args| {
+        e.irr(args)
+    });
+    add("XNPV", Arity::exactly(3), &[Number, Any, Any], |e, args| {
+        e.xnpv(args)
+    });
+    add(
+        "XIRR",
+        Arity::between(2, 3),
+        &[Any, Any, Number],
+        |e, args| e.xirr(args),
+    );
+
+    add("SEQUENCE", Arity::between(1, 4), &[Number], |e, args| {
+        e.sequence(args)
+    });
+    add(
+        "FILTER",
+        Arity::between(2, 3),
+        &[Any, Any, Value],
+        |e, args| e.filter(args),
+    );
+    add(
+        "SORT",
+        Arity::between(1, 4),
+        &[Any, Number, Number, Logical],
+        |e, args| e.sort(args),
+    );
+    add(
+        "UNIQUE",
+        Arity::between(1, 3),
+        &[Any, Logical, Logical],
+        |e, args| e.unique(args),
+    );
+    add("TRANSPOSE", Arity::exactly(1), &[Any], |e, args| {
+        e.transpose(args)
+    });
+
+    // NOW and TODAY change without any cell changing
+    registry.register(Builtin {
+        name: "TODAY",
+        arity: Arity::exactly(0),
+        kinds: &[],
+        params: &[],
+        evaluate: |e, args| e.now(args, true),
+        volatile: true,
+    });
+    registry.register(Builtin {
+        name: "NOW",
+        arity: Arity::exactly(0),
+        kinds: &[],
+        params: &[],
+        evaluate: |e, args| e.now(args, false),
+        volatile: true,
+    });
+
+    // Older and newer Excel names of the same functions
+    registry.alias("MODE.SNGL", "MODE");
+    registry.alias("STDEV.S", "STDEV");
+    registry.alias("STDEVP", "STDEV.P");
+    registry.alias("VAR.S", "VAR");
+    registry.alias("VARP", "VAR.P");
+    registry.alias("PERCENTILE.INC", "PERCENTILE");
+    registry.alias("QUARTILE.INC", "QUARTILE");
+    registry.alias("RANK.EQ", "RANK");
+}
+
+// Excel's parameter names for the signature hints, the functions missing
+// here have their parameters named after the argument kinds
+fn builtin_params(name: &str) -> &'static [&'static str] {
+    match name {
+        "SUM" | "PRODUCT" | "DIFFERENCE" | "QUOTIENT" | "AVERAGE" | "MIN" | "MAX" | "MEDIAN"
+        | "MODE" | "STDEV" | "STDEV.P" | "VAR" | "VAR.P" => &["number1", "number2"],
+        "COUNT" | "COUNTA" => &["value1", "value2"],
+        "ROUND" => &["number", "num_digits"],
+        "MOD" => &["number", "divisor"],
+        "POWER" => &["number", "power"],
+        "IF" => &["logical_test", "value_if_true", "value_if_false"],
+        "IFS" => &["logical_test1", "value_if_true1"],
+        "AND" | "OR" => &["logical1", "logical2"],
+        "IFERROR" => &["value", "value_if_error"],
+        "SWITCH" => &["expression", "value1", "result1"],
+        "CONCAT" => &["text1", "text2"],
+        "LEFT" | "RIGHT" => &["text", "num_chars"],
+        "MID" => &["text", "start_num", "num_chars"],
+        "SUBSTITUTE" => &["text", "old_text", "new_text", "instance_num"],
+        "TEXT" => &["value", "format_text"],
+        "SPLIT" => &["text", "delimiter", "index"],
+        "DATE" => &["year", "month", "day"],
+        "YEAR" | "MONTH" | "DAY" => &["serial_number"],
+        "DATEDIF" => &["start_date", "end_date", "unit"],
+        "EDATE" => &["start_date", "months"],
+        "WEEKDAY" => &["serial_number", "return_type"],
+        "NETWORKDAYS" => &["start_date", "end_date", "holidays"],
+        "VLOOKUP" => &[
+            "lookup_value",
+            "table_array",
+            "col_index_num",
+            "range_lookup",
+        ],
+        "HLOOKUP" => &[
+            "lookup_value",
+            "table_array",
+            "row_index_num",
+            "range_lookup",
+        ],
+        "XLOOKUP" => &[
+            "lookup_value",
+            "lookup_array",
+            "return_array",
+            "if_not_found",
+            "match_mode",
+            "search_mode",
+        ],
+        "INDEX" => &["array", "row_num", "column_num"],
+        "MATCH" => &["lookup_value", "lookup_array", "match_type"],
+        "SUMIF" => &["range", "criteria", "sum_range"],
+        "SUMIFS" => &["sum_range", "criteria_range1", "criteria1"],
+        "COUNTIF" => &["range", "criteria"],
+        "COUNTIFS" => &["criteria_range1", "criteria1"],
+        "AVERAGEIF" => &["range", "criteria", "average_range"],
+        "MAXIFS" => &["max_range", "criteria_range1", "criteria1"],
+        "MINIFS" => &["min_range", "criteria_range1", "criteria1"],
+        "PERCENTILE" | "LARGE" | "SMALL" => &["array", "k"],
+        "QUARTILE" => &["array", "quart"],
+        "CORREL" => &["array1", "array2"],
+        "RANK" => &["number", "ref", "order"],
+        "PMT" => &["rate", "nper", "pv", "fv", "type"],
+        "PV" => &["rate", "nper", "pmt", "fv", "type"],
+        "FV" => &["rate", "nper", "pmt", "pv", "type"],
+        "NPER" => &["rate", "pmt", "pv", "fv", "type"],
+        "RATE" => &["nper", "pmt", "pv", "fv", "type", "guess"],
+        "NPV" => &["rate", "value1", "value2"],
+        "IRR" => &["values", "guess"],
+        "XNPV" => &["rate", "values", "dates"],
+        "XIRR" => &["values", "dates", "guess"],
+        "SEQUENCE" => &["rows", "columns", "start", "step"],
+        "FILTER" => &["array", "include", "if_empty"],
+        "SORT" => &["array", "sort_index", "sort_order", "by_col"],
+        "UNIQUE" => &["array", "by_col", "exactly_once"],
+        "TRANSPOSE" => &["array"],
+        _ => &[],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::date::FixedClock;
+    use crate::dependency::DependencyGraph;
+    use crate::functions::Function;
+    use crate::reference::{column_name, MAX_ROWS};
+    use crate::sheets::SheetCache;
+
+    // Grid of values row by row, formulas in it can only use constants
+    fn grid(rows: &[&[&str]]) -> Vec<Vec<Cell>> {
+        rows.iter()
+            .map(|row| {
+                row.iter()
+                    .map(|value| Cell {
+                        value: value.to_string(),
+                        computed: match value.starts_with('=') {
+                            true => eval(value),
+                            false => CellValue::parse(value),
+                        },
+                        ..Cell::empty()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn parse(formula: &str) -> Option<Expr> {
+        Parser::new(tokenize(formula.strip_prefix('=')?)?).parse()
+    }
+
+    fn eval_in(cells: &[Vec<Cell>], formula: &str) -> CellValue {
+        Evaluator::new(cells, NumericMode::Float).eval(&parse(formula).unwrap())
+    }
+
+    fn eval(formula: &str) -> CellValue {
+        eval_in(&[], formula)
+    }
+
+    fn number(n: f64) -> CellValue {
+        CellValue::Number(n)
+    }
+
+    fn text(text: &str) -> CellValue {
+        CellValue::Text(text.to_string())
+    }
+
+    #[test]
+    fn precedence() {
+        // ^ is left associative and unary minus binds tighter, like Excel
+        assert_eq!(eval("=2^3^2"), number(64.0));
+        assert_eq!(eval("=-2^2"), number(4.0));
+        assert_eq!(eval("=2*3+4*5"), number(26.0));
+        assert_eq!(eval("=(2+3)*4"), number(20.0));
+        assert_eq!(eval("=50%"), number(0.5));
+        assert_eq!(eval("=10+50%*2"), number(11.0));
+        assert_eq!(eval("=1+2&3"), text("33"));
+        assert_eq!(eval("=\"a\"&1+2"), text("a3"));
+        assert_eq!(eval("=1+1=2"), CellValue::Bool(true));
+    }
+
+    #[test]
+    fn ranges() {
+        let cells = grid(&[&["1", "2", "3"], &["4", "5", "6"], &["7", "8", "9"]]);
+        assert_eq!(eval_in(&cells, "=SUM(A1:C3)"), number(45.0));
+        assert_eq!(eval_in(&cells, "=SUM(C3:A1)"), number(45.0));
+        assert_eq!(eval_in(&cells, "=SUM(A:A)"), number(12.0));
+        assert_eq!(eval_in(&cells, "=SUM(B:C)"), number(33.0));
+        assert_eq!(eval_in(&cells, "=SUM(3:3)"), number(24.0));
+        assert_eq!(eval_in(&cells, "=SUM($A$1:B2)"), number(12.0));
+        // Whole columns past the sheet read as blank
+        assert_eq!(eval_in(&cells, "=COUNT(A:XFD)"), number(9.0));
+    }
+
+    #[test]
+    fn argument_lists() {
+        let cells = grid(&[&["1", "2", "3"], &["4", "5", "6"], &["7", "8", "9"]]);
+        assert_eq!(eval_in(&cells, "=SUM(A1:A3, C1, 5)"), number(20.0));
+        assert_eq!(eval_in(&cells, "=SUM(A1:A2,A1:A2)"), number(10.0));
+        assert_eq!(eval_in(&cells, "=MAX(A:A, 3:3, 0)"), number(9.0));
+    }
+
+    #[test]
+    fn intersection() {
+        let cells = grid(&[&["1", "2", "3"], &["4", "5", "6"], &["7", "8", "9"]]);
+        assert_eq!(eval_in(&cells, "=SUM(A1:C3 B2:B9)"), number(13.0));
+        assert_eq!(
+            eval_in(&cells, "=A:C 2:2"),
+            CellValue::Array(vec![vec![number(4.0), number(5.0), number(6.0)]])
+        );
+        assert_eq!(eval_in(&cells, "=B:B 2:2"), number(5.0));
+        assert_eq!(
+            eval_in(&cells, "=SUM(A1:A3 C1:C3)"),
+            CellValue::Error(FormulaError::Null)
+        );
+        assert_eq!(
+            eval_in(&cells, "=A1 C1"),
+            CellValue::Error(FormulaError::Null)
+        );
+    }
+
+    fn error(error: FormulaError) -> CellValue {
+        CellValue::Error(error)
+    }
+
+    // 1, blank, text, TRUE, 3 and #DIV/0!
+    fn mixed() -> Vec<Vec<Cell>> {
+        grid(&[&["1", "", "apple", "TRUE", "3", "=1/0"]])
+    }
+
+    #[test]
+    fn sum() {
+        let cells = mixed();
+        assert_eq!(eval_in(&cells, "=SUM(A1:E1)"), number(4.0));
+        assert_eq!(eval_in(&cells, "=SUM(A1:F1)"), error(FormulaError::DivZero));
+        assert_eq!(eval_in(&cells, "=SUM(B1)"), number(0.0));
+        assert_eq!(eval_in(&cells, "=SUM()"), number(0.0));
+        // Values typed in the formula are converted
+        assert_eq!(eval_in(&cells, "=SUM(1, \"2\", TRUE)"), number(4.0));
+        assert_eq!(
+            eval_in(&cells, "=SUM(\"apple\")"),
+            error(FormulaError::Value)
+        );
+    }
+
+    #[test]
+    fn average() {
+        let cells = mixed();
+        assert_eq!(eval_in(&cells, "=AVERAGE(A1:E1)"), number(2.0));
+        assert_eq!(eval_in(&cells, "=AVERAGE(A1:E1, 5)"), number(3.0));
+        assert_eq!(
+            eval_in(&cells, "=AVERAGE(B1:D1)"),
+            error(FormulaError::DivZero)
+        );
+        assert_eq!(
+            eval_in(&cells, "=AVERAGE(F1, 1)"),
+            error(FormulaError::DivZero)
+        );
+    }
+
+    #[test]
+    fn count() {
+        let cells = mixed();
+        assert_eq!(eval_in(&cells, "=COUNT(A1:F1)"), number(2.0));
+        assert_eq!(
+            eval_in(&cells, "=COUNT(1, \"2\", \"x\", TRUE)"),
+            number(3.0)
+        );
+        assert_eq!(eval_in(&cells, "=COUNTA(A1:F1)"), number(5.0));
+        assert_eq!(eval_in(&cells, "=COUNTA(B1)"), number(0.0));
+        assert_eq!(eval_in(&cells, "=COUNTA(1, \"\")"), number(2.0));
+    }
+
+    #[test]
+    fn min_and_max() {
+        let cells = mixed();
+        assert_eq!(eval_in(&cells, "=MIN(A1:E1)"), number(1.0));
+        assert_eq!(eval_in(&cells, "=MAX(A1:E1, -2)"), number(3.0));
+        assert_eq!(eval_in(&cells, "=MIN(A1:E1, -2)"), number(-2.0));
+        assert_eq!(eval_in(&cells, "=MAX(B1:C1)"), number(0.0));
+        assert_eq!(eval_in(&cells, "=MIN(A1:F1)"), error(FormulaError::DivZero));
+    }
+
+    #[test]
+    fn round() {
+        let cells = mixed();
+        assert_eq!(eval("=ROUND(2.675, 2)"), number(2.68));
+        assert_eq!(eval("=ROUND(-2.5, 0)"), number(-3.0));
+        assert_eq!(eval("=ROUND(1234.5, -2)"), number(1200.0));
+        assert_eq!(eval_in(&cells, "=ROUND(B1, 1)"), number(0.0));
+        assert_eq!(eval_in(&cells, "=ROUND(C1, 1)"), error(FormulaError::Value));
+        assert_eq!(
+            eval_in(&cells, "=ROUND(F1, 1)"),
+            error(FormulaError::DivZero)
+        );
+        assert_eq!(eval("=ROUND(1, -400)"), error(FormulaError::Num));
+        assert_eq!(eval("=ROUND(1E300, 400)"), error(FormulaError::Num));
+    }
+
+    #[test]
+    fn modulo() {
+        let cells = mixed();
+        assert_eq!(eval("=MOD(7, 3)"), number(1.0));
+        assert_eq!(eval("=MOD(-3, 2)"), number(1.0));
+        assert_eq!(eval("=MOD(3, -2)"), number(-1.0));
+        assert_eq!(eval("=MOD(1, 0)"), error(FormulaError::DivZero));
+        assert_eq!(eval_in(&cells, "=MOD(B1, 2)"), number(0.0));
+        assert_eq!(eval_in(&cells, "=MOD(C1, 2)"), error(FormulaError::Value));
+    }
+
+    #[test]
+    fn sqrt() {
+        let cells = mixed();
+        assert_eq!(eval("=SQRT(16)"), number(4.0));
+        assert_eq!(eval("=SQRT(-1)"), error(FormulaError::Num));
+        assert_eq!(eval_in(&cells, "=SQRT(B1)"), number(0.0));
+        assert_eq!(eval_in(&cells, "=SQRT(C1)"), error(FormulaError::Value));
+        assert_eq!(eval_in(&cells, "=SQRT(F1)"), error(FormulaError::DivZero));
+    }
+
+    fn date(year: i64, month: i64, day: i64) -> CellValue {
+        CellValue::Date(serial_from_date(year, month, day).unwrap())
+    }
+
+    #[test]
+    fn today_and_now() {
+        // 2024-01-15 18:00
+        let clock = FixedClock(45306.75);
+        let at = |formula| {
+            Evaluator::new(&[], NumericMode::Float)
+                .with_clock(&clock)
+                .eval(&parse(formula).unwrap())
+        };
+        assert_eq!(at("=TODAY()"), date(2024, 1, 15));
+        assert_eq!(at("=NOW()"), CellValue::Date(45306.75));
+        assert_eq!(at("=YEAR(NOW())"), number(2024.0));
+        assert_eq!(at("=NOW()-TODAY()"), number(0.75));
+    }
+
+    #[test]
+    fn date_rollover() {
+        assert_eq!(eval("=DATE(2024, 1, 15)"), CellValue::Date(45306.0));
+        assert_eq!(eval("=DATE(2024, 14, 1)"), date(2025, 2, 1));
+        assert_eq!(eval("=DATE(2024, 3, 0)"), date(2024, 2, 29));
+        assert_eq!(eval("=DATE(2024, 1, -1)"), date(2023, 12, 30));
+        assert_eq!(eval("=DATE(2024, -1, 1)"), date(2023, 11, 1));
+        assert_eq!(eval("=DATE(24, 1, 1)"), date(1924, 1, 1));
+        // Excel's 1900 leap day bug
+        assert_eq!(eval("=DATE(1900, 2, 28)"), CellValue::Date(59.0));
+        assert_eq!(eval("=DATE(1900, 3, 1)"), CellValue::Date(61.0));
+        assert_eq!(eval("=DAY(60)"), number(29.0));
+        assert_eq!(eval("=DATE(9999, 12, 31)"), CellValue::Date(MAX_SERIAL));
+        assert_eq!(eval("=DATE(9999, 12, 32)"), error(FormulaError::Num));
+    }
+
+    #[test]
+    fn dates_out_of_range() {
+        for formula in [
+            "=YEAR(1E20)",
+            "=YEAR(2958466)",
+            "=YEAR(-1)",
+            "=DATE(2024, 1E30, 1)",
+            "=DATE(2024, -1E30, 1)",
+            "=DATE(2024, 1, 1E30)",
+            "=DATE(2024, 1, -1E30)",
+            "=EDATE(1, 1E30)",
+            "=EDATE(1, -1E30)",
+            "=EDATE(DATE(9999, 12, 1), 1)",
+            "=DATEDIF(1, 1E20, \"YD\")",
+            "=WEEKDAY(1E20)",
+            "=NETWORKDAYS(0, 1E15)",
+            "=NETWORKDAYS(0, 1E17)",
+        ] {
+            assert_eq!(eval(formula), error(FormulaError::Num), "{}", formula);
+        }
+        assert_eq!(eval("=YEAR(2958465)"), number(9999.0));
+    }
+
+    #[test]
+    fn date_display() {
+        let shown = |serial: f64| CellValue::Date(serial).to_string();
+        assert_eq!(shown(45306.0), "2024-01-15");
+        assert_eq!(shown(45306.75), "2024-01-15 18:00:00");
+        assert_eq!(shown(45306.0 + 1.4 / 86400.0), "2024-01-15 00:00:01");
+        // Times that round up to midnight show the next day
+        assert_eq!(shown(45306.0 + 86399.6 / 86400.0), "2024-01-16");
+        assert_eq!(shown(45306.0 + 86399.4 / 86400.0), "2024-01-15 23:59:59");
+        assert_eq!(shown(-1.0), "#NUM!");
+    }
+
+    #[test]
+    fn edate() {
+        assert_eq!(eval("=EDATE(DATE(2024, 1, 15), 1)"), date(2024, 2, 15));
+        assert_eq!(eval("=EDATE(DATE(2024, 1, 31), 1)"), date(2024, 2, 29));
+        assert_eq!(eval("=EDATE(DATE(2023, 1, 31), 1)"), date(2023, 2, 28));
+        assert_eq!(eval("=EDATE(DATE(2024, 3, 31), -1)"), date(2024, 2, 29));
+        assert_eq!(eval("=EDATE(DATE(2024, 1, 31), 14)"), date(2025, 3, 31));
+    }
+
+    #[test]
+    fn datedif() {
+        let dif = |start, end, unit| {
+            eval(&format!(
+                "=DATEDIF(DATE({}), DATE({}), \"{}\")",
+                start, end, unit
+            ))
+        };
+        assert_eq!(dif("2020,1,10", "2024,2,20", "Y"), number(4.0));
+        assert_eq!(dif("2020,1,10", "2024,2,20", "M"), number(49.0));
+        assert_eq!(dif("2020,1,10", "2024,2,20", "YM"), number(1.0));
+        assert_eq!(dif("2024,1,5", "2024,3,10", "MD"), number(5.0));
+        // Days left over count from the same day of the month before
+        assert_eq!(dif("2024,1,15", "2024,3,10", "MD"), number(24.0));
+        assert_eq!(dif("2020,1,10", "2024,2,20", "YD"), number(41.0));
+        assert_eq!(dif("2023,6,15", "2024,3,1", "YD"), number(260.0));
+        assert_eq!(dif("2024,3,1", "2024,1,1", "D"), error(FormulaError::Num));
+    }
+
+    #[test]
+    fn network_days() {
+        // A Saturday and the same Monday twice
+        let cells = grid(&[&["2024-01-13"], &["2024-01-15"], &["2024-01-15"]]);
+        let january = "DATE(2024, 1, 1), DATE(2024, 1, 31)";
+        assert_eq!(eval(&format!("=NETWORKDAYS({})", january)), number(23.0));
+        assert_eq!(
+            eval_in(&cells, &format!("=NETWORKDAYS({}, A1:A3)", january)),
+            number(22.0)
+        );
+        assert_eq!(
+            eval("=NETWORKDAYS(DATE(2024, 1, 31), DATE(2024, 1, 1))"),
+            number(-23.0)
+        );
+        assert_eq!(
+            eval("=NETWORKDAYS(DATE(2024, 1, 6), DATE(2024, 1, 7))"),
+            number(0.0)
+        );
+
+        // The whole calendar, counted day by day
+        let weekdays = (1..=MAX_SERIAL as i64)
+            .filter(|serial| (1..=5).contains(&day_of_week(*serial as f64)))
+            .count();
+        assert_eq!(eval("=NETWORKDAYS(1, 2958465)"), number(weekdays as f64));
+    }
+
+    #[test]
+    fn criteria() {
+        let cells = grid(&[
+            &["apple"],
+            &["apricot"],
+            &["a*"],
+            &["banana"],
+            &[""],
+            &["5"],
+            &["7"],
+            &["TRUE"],
+            &["x"],
+            &[""],
+        ]);
+        let count = |criteria: &str| eval_in(&cells, &format!("=COUNTIF(A1:A10, {})", criteria));
+
+        // <> matches everything else, blanks included
+        assert_eq!(count("\"<>x\""), number(9.0));
+        assert_eq!(count("\"<>X\""), number(9.0));
+        assert_eq!(count("\"<>\""), number(8.0));
+        // Comparisons only match values of the same kind
+        assert_eq!(count("\">=5\""), number(2.0));
+        assert_eq!(count("\"<7\""), number(1.0));
+        assert_eq!(count("\">=b\""), number(2.0));
+        assert_eq!(count("\"5\""), number(1.0));
+        assert_eq!(count("5"), number(1.0));
+        assert_eq!(count("TRUE"), number(1.0));
+        assert_eq!(eval_in(&cells, "=SUMIF(A1:A10, \">=5\")"), number(12.0));
+
+        assert_eq!(count("\"ap*\""), number(2.0));
+        assert_eq!(count("\"a*\""), number(3.0));
+        assert_eq!(count("\"*\""), number(5.0));
+        assert_eq!(count("\"?\""), number(1.0));
+        assert_eq!(count("\"?????\""), number(1.0));
+        assert_eq!(count("\"b?n*\""), number(1.0));
+        // ~ makes the next wildcard literal
+        assert_eq!(count("\"a~*\""), number(1.0));
+        assert_eq!(count("\"<>a~*\""), number(9.0));
+
+        // An empty criteria string and a lone = match blank cells
+        assert_eq!(count("\"\""), number(2.0));
+        assert_eq!(count("\"=\""), number(2.0));
+        assert_eq!(count("1/0"), error(FormulaError::DivZero));
+    }
+
+    #[test]
+    fn other_sheets() {
+        let open = grid(&[&["1", "2"]]);
+        let eval_on = |others: &SheetCache, formula: &str| {
+            let workbook = Workbook {
+                name: "Sheet1",
+                cells: &open,
+                others,
+            };
+            Evaluator::new(&open, NumericMode::Float)
+                .with_workbook(workbook)
+                .eval(&parse(formula).unwrap())
+        };
+        let mut others = SheetCache::new();
+        others.insert("Sheet2", Some(grid(&[&["10", "20"], &["30", "40"]])));
+        others.insert("My Sheet", Some(grid(&[&["1", "2"], &["3", "4"]])));
+
+        assert_eq!(eval_on(&others, "=Sheet2!A1"), number(10.0));
+        assert_eq!(eval_on(&others, "=sheet2!B2+A1"), number(41.0));
+        assert_eq!(eval_on(&others, "=Sheet1!B1"), number(2.0));
+        assert_eq!(eval_on(&others, "=SUM('My Sheet'!A1:B2)"), number(10.0));
+        assert_eq!(
+            eval_on(&others, "='My Sheet'!A1:B2"),
+            CellValue::Array(vec![
+                vec![number(1.0), number(2.0)],
+                vec![number(3.0), number(4.0)]
+            ])
+        );
+        assert_eq!(eval_on(&others, "=Missing!A1"), error(FormulaError::Ref));
+
+        // A deleted sheet is cached as missing until it's created again
+        others.insert("Sheet2", None);
+        assert_eq!(eval_on(&others, "=Sheet2!A1"), error(FormulaError::Ref));
+        assert_eq!(
+            eval_on(&others, "=SUM(Sheet2!A1:B2)"),
+            error(FormulaError::Ref)
+        );
+        others.insert("Sheet2", Some(grid(&[&["5"]])));
+        assert_eq!(eval_on(&others, "=Sheet2!A1"), number(5.0));
+        assert_eq!(eval_on(&others, "=SUM(Sheet2!A1:B2)"), number(5.0));
+        // Without a workbook there are no other sheets to read
+        assert_eq!(eval_in(&open, "=Sheet2!A1"), error(FormulaError::Ref));
+    }
+
+    #[test]
+    fn criteria_on_another_sheet() {
+        let open = grid(&[&["1", "10"]]);
+        let mut others = SheetCache::new();
+        others.insert(
+            "Other",
+            Some(grid(&[
+                &["1", "10"],
+                &["2", "20"],
+                &["3", "30"],
+                &["4", "40"],
+            ])),
+        );
+        let workbook = Workbook {
+            name: "Sheet1",
+            cells: &open,
+            others: &others,
+        };
+        let at = |formula| {
+            Evaluator::new(&open, NumericMode::Float)
+                .with_workbook(workbook)
+                .eval(&parse(formula).unwrap())
+        };
+        assert_eq!(at("=SUMIFS(Other!B:B, Other!A:A, \">1\")"), number(90.0));
+        assert_eq!(at("=COUNTIF(Other!A:A, \">=3\")"), number(2.0));
+        assert_eq!(at("=SUMIF(Other!A2:A9, \"<4\", B:B)"), number(10.0));
+        assert_eq!(at("=COUNTIFS(A:A, 1, Other!A:A, 1)"), number(1.0));
+    }
+
+    // Within the digits Excel's documentation shows
+    fn assert_near(value: CellValue, expected: f64, tolerance: f64) {
+        match value {
+            CellValue::Number(n) => {
+                assert!((n - expected).abs() <= tolerance, "{} != {}", n, expected)
+            }
+            value => panic!("{:?} != {}", value, expected),
+        }
+    }
+
+    // Column A of the grid
+    fn column(values: &[&str]) -> Vec<Vec<Cell>> {
+        grid(
+            &values
+                .iter()
+                .map(std::slice::from_ref)
+                .collect::<Vec<&[&str]>>(),
+        )
+    }
+
+    #[test]
+    fn median_and_mode() {
+        assert_eq!(eval("=MEDIAN(1, 2, 3, 4, 5)"), number(3.0));
+        assert_eq!(eval("=MEDIAN(1, 2, 3, 4, 5, 6)"), number(3.5));
+        assert_eq!(eval("=MEDIAN(3, 1, 2)"), number(2.0));
+        assert_eq!(eval("=MEDIAN(A1:A3)"), error(FormulaError::Num));
+        assert_eq!(eval("=MODE(5.6, 4, 4, 3, 2, 4)"), number(4.0));
+        assert_eq!(eval("=MODE(1, 2, 2, 1)"), number(1.0));
+        assert_eq!(eval("=MODE(1, 2, 3)"), error(FormulaError::NotAvailable));
+    }
+
+    #[test]
+    fn variance() {
+        let cells = column(&[
+            "1345", "1301", "1368", "1322", "1310", "1370", "1318", "1350", "1303", "1299",
+        ]);
+        assert_near(eval_in(&cells, "=STDEV.S(A1:A10)"), 27.46391572, 1e-8);
+        assert_near(eval_in(&cells, "=STDEV.P(A1:A10)"), 26.05455814, 1e-8);
+        assert_near(eval_in(&cells, "=VAR.S(A1:A10)"), 754.2666667, 1e-7);
+        assert_near(eval_in(&cells, "=VAR.P(A1:A10)"), 678.84, 1e-8);
+        assert_eq!(eval("=VAR.S(1)"), error(FormulaError::DivZero));
+        assert_eq!(eval("=VAR.P(4)"), number(0.0));
+
+        // A small spread on a large offset, where the sum of squares loses every digit
+        let cells = column(&["1000000004", "1000000007", "1000000013", "1000000016"]);
+        assert_near(eval_in(&cells, "=VAR.S(A1:A4)"), 30.0, 1e-9);
+        assert_near(eval_in(&cells, "=VAR.P(A1:A4)"), 22.5, 1e-9);
+        let cells = column(&["1E15", "1000000000000002"]);
+        assert_near(eval_in(&cells, "=STDEV.P(A1:A2)"), 1.0, 1e-9);
+    }
+
+    #[test]
+    fn percentile() {
+        let cells = column(&["1", "3", "2", "4"]);
+        assert_near(eval_in(&cells, "=PERCENTILE(A1:A4, 0.3)"), 1.9, 1e-12);
+        assert_eq!(eval_in(&cells, "=PERCENTILE(A1:A4, 1)"), number(4.0));
+        assert_eq!(
+            eval_in(&cells, "=PERCENTILE(A1:A4, 1.5)"),
+            error(FormulaError::Num)
+        );
+        let cells = column(&["1", "2", "4", "7", "8", "9", "10", "12"]);
+        assert_eq!(eval_in(&cells, "=QUARTILE(A1:A8, 1)"), number(3.5));
+        assert_eq!(eval_in(&cells, "=QUARTILE(A1:A8, 2)"), number(7.5));
+        assert_eq!(
+            eval_in(&cells, "=QUARTILE(A1:A8, 5)"),
+            error(FormulaError::Num)
+        );
+    }
+
+    #[test]
+    fn correl() {
+        let cells = grid(&[
+            &["3", "9"],
+            &["2", "7"],
+            &["4", "12"],
+            &["5", "15"],
+            &["6", "17"],
+        ]);
+        assert_near(eval_in(&cells, "=CORREL(A1:A5, B1:B5)"), 0.997054486, 1e-9);
+        assert_eq!(
+            eval_in(&cells, "=CORREL(A1:A5, B1:B4)"),
+            error(FormulaError::NotAvailable)
+        );
+        assert_eq!(
+            eval_in(&cells, "=CORREL(A1:A5, A1:A5*0)"),
+            error(FormulaError::DivZero)
+        );
+    }
+
+    #[test]
+    fn rank_large_and_small() {
+        let cells = column(&["7", "3.5", "3.5", "1", "2"]);
+        assert_eq!(eval_in(&cells, "=RANK(A3, A1:A5, 1)"), number(3.0));
+        assert_eq!(eval_in(&cells, "=RANK(A1, A1:A5, 1)"), number(5.0));
+        assert_eq!(eval_in(&cells, "=RANK(A2, A1:A5)"), number(2.0));
+        assert_eq!(
+            eval_in(&cells, "=RANK(9, A1:A5)"),
+            error(FormulaError::NotAvailable)
+        );
+
+        let cells = grid(&[&["3", "5", "3", "5", "4"], &["4", "2", "4", "6", "7"]]);
+        assert_eq!(eval_in(&cells, "=LARGE(A1:E2, 3)"), number(5.0));
+        assert_eq!(eval_in(&cells, "=LARGE(A1:E2, 7)"), number(4.0));
+        assert_eq!(eval_in(&cells, "=SMALL(A1:E2, 1)"), number(2.0));
+        assert_eq!(eval_in(&cells, "=SMALL(A1:E2, 4)"), number(4.0));
+        assert_eq!(
+            eval_in(&cells, "=SMALL(A1:E2, 11)"),
+            error(FormulaError::Num)
+        );
+    }
+
+    #[test]
+    fn loans() {
+        assert_near(eval("=PMT(0.05/12, 360, 200000)"), -1073.64, 0.005);
+        assert_near(eval("=PMT(0.08/12, 10, 10000)"), -1037.03, 0.005);
+        assert_near(eval("=PMT(0, 10, 1000)"), -100.0, 1e-9);
+        assert_near(eval("=PV(0.08/12, 240, 500)"), -59777.15, 0.005);
+        assert_near(eval("=FV(0.06/12, 10, -200, -500, 1)"), 2581.40, 0.005);
+        assert_near(eval("=FV(0, 12, -100)"), 1200.0, 1e-9);
+        assert_near(
+            eval("=NPER(0.12/12, -100, -1000, 10000, 1)"),
+            59.6738657,
+            1e-7,
+        );
+        assert_near(eval("=NPER(0.01, -100, -1000, 10000)"), 60.0821229, 1e-7);
+        assert_near(eval("=RATE(48, -200, 8000)"), 0.00770147, 1e-8);
+        assert_near(eval("=RATE(360, -1073.64, 200000)*12"), 0.05, 1e-5);
+    }
+
+    #[test]
+    fn cash_flows() {
+        let cells = grid(&[
+            &["-70000", "-10000", "2008-01-01"],
+            &["12000", "2750", "2008-03-01"],
+            &["15000", "4250", "2008-10-30"],
+            &["18000", "3250", "2009-02-15"],
+            &["21000", "2750", "2009-04-01"],
+            &["26000"],
+        ]);
+        assert_near(eval("=NPV(0.1, -10000, 3000, 4200, 6800)"), 1188.44, 0.005);
+        assert_near(eval_in(&cells, "=IRR(A1:A6)"), 0.086630948, 1e-8);
+        assert_near(eval_in(&cells, "=IRR(A1:A5)"), -0.021244848, 1e-8);
+        assert_near(eval_in(&cells, "=IRR(A1:A3, -0.1)"), -0.443506941, 1e-8);
+        assert_near(eval_in(&cells, "=XNPV(0.09, B1:B5, C1:C5)"), 2086.65, 0.005);
+        assert_near(eval_in(&cells, "=XIRR(B1:B5, C1:C5)"), 0.373362535, 1e-8);
+        // Money only going one way has no rate of return
+        assert_eq!(eval_in(&cells, "=IRR(A2:A6)"), error(FormulaError::Num));
+    }
+
+    #[test]
+    fn rates_that_dont_converge() {
+        assert_eq!(
+            solve_rate(0.1, |rate| rate * rate + 1.0),
+            Err(FormulaError::Num)
+        );
+        assert_eq!(solve_rate(0.1, |_| 1.0), Err(FormulaError::Num));
+        assert_eq!(eval("=RATE(10, 1000, 1000)"), error(FormulaError::Num));
+        assert_eq!(
+            eval("=RATE(10, -100, 1000, 0, 0, 1E300)"),
+            error(FormulaError::Num)
+        );
+    }
+
+    #[test]
+    fn shift_formulas() {
+        let shift = |formula, rows, cols| shift_formula(formula, rows, cols).unwrap();
+        assert_eq!(shift("=SUM(A1:A3)", 0, 1), "=SUM(B1:B3)");
+        assert_eq!(shift("=A1*2", 2, 0), "=A3*2");
+        assert_eq!(shift("=$A$1+$A1+A$1", 1, 1), "=$A$1+$A2+B$1");
+        assert_eq!(shift("=SUM(A:A)+SUM(3:3)", 1, 1), "=SUM(B:B)+SUM(4:4)");
+        assert_eq!(shift("=Sheet2!B2", -1, -1), "=Sheet2!A1");
+        assert_eq!(shift("=Revenue*2", 5, 5), "=Revenue*2");
+        // References moved off the sheet become #REF!
+        assert_eq!(shift("=A1+B2", -1, 0), "=#REF!+B1");
+        assert_eq!(shift("=SUM(A1:B2)", 0, -1), "=SUM(#REF!)");
+        assert_eq!(shift("=XFD1", 0, 1), "=#REF!");
+        assert_eq!(shift("=$A1", 0, -1), "=$A1");
+        assert_eq!(eval("=#REF!+1"), error(FormulaError::Ref));
+        assert_eq!(shift_formula("=1+", 1, 1), None);
+        assert_eq!(shift_formula("text", 1, 1), None);
+    }
+
+    #[test]
+    fn untaken_branches() {
+        // Branches that aren't taken aren't evaluated, so their errors don't leak
+        assert_eq!(eval("=IF(TRUE, 1, 1/0)"), number(1.0));
+        assert_eq!(eval("=IF(FALSE, 1/0, 2)"), number(2.0));
+        assert_eq!(eval("=IF(TRUE, 1/0, 2)"), error(FormulaError::DivZero));
+        assert_eq!(eval("=IFS(1=1, \"a\", 1/0, \"b\")"), text("a"));
+        assert_eq!(eval("=IFS(FALSE, 1/0, TRUE, 2)"), number(2.0));
+        assert_eq!(eval("=SWITCH(2, 1, 1/0, 2, \"two\", 1/0)"), text("two"));
+        assert_eq!(eval("=SWITCH(3, 1, 1/0, \"other\")"), text("other"));
+        assert_eq!(eval("=AND(FALSE, 1/0)"), CellValue::Bool(false));
+        assert_eq!(eval("=AND(TRUE, 1/0)"), error(FormulaError::DivZero));
+        assert_eq!(eval("=OR(TRUE, 1/0)"), CellValue::Bool(true));
+        assert_eq!(eval("=OR(FALSE, 1/0)"), error(FormulaError::DivZero));
+    }
+
+    #[test]
+    fn comparisons() {
+        // Numbers sort before text and text before booleans, text ignores case
+        assert_eq!(eval("=1<\"a\""), CellValue::Bool(true));
+        assert_eq!(eval("=1000<\"1\""), CellValue::Bool(true));
+        assert_eq!(eval("=\"z\"<TRUE"), CellValue::Bool(true));
+        assert_eq!(eval("=FALSE>99"), CellValue::Bool(true));
+        assert_eq!(eval("=FALSE<TRUE"), CellValue::Bool(true));
+        assert_eq!(eval("=\"Apple\"=\"aPPLE\""), CellValue::Bool(true));
+        assert_eq!(eval("=\"apple\"<\"Banana\""), CellValue::Bool(true));
+        assert_eq!(eval("=1=\"1\""), CellValue::Bool(false));
+        assert_eq!(eval("=1<>TRUE"), CellValue::Bool(true));
+        // Blanks compare as 0, "" or FALSE depending on the other side
+        let cells = grid(&[&[""]]);
+        assert_eq!(eval_in(&cells, "=A1=0"), CellValue::Bool(true));
+        assert_eq!(eval_in(&cells, "=A1=\"\""), CellValue::Bool(true));
+        assert_eq!(eval_in(&cells, "=A1=FALSE"), CellValue::Bool(true));
+        assert_eq!(eval("=1/0=1"), error(FormulaError::DivZero));
+    }
+
+    #[test]
+    fn if_error() {
+        let errors = [
+            FormulaError::DivZero,
+            FormulaError::Ref,
+            FormulaError::Name,
+            FormulaError::Value,
+            FormulaError::Cycle,
+            FormulaError::Num,
+            FormulaError::Null,
+            FormulaError::NotAvailable,
+            FormulaError::Spill,
+            FormulaError::Calc,
+        ];
+        let cells = vec![errors
+            .iter()
+            .map(|&error| Cell {
+                computed: CellValue::Error(error),
+                ..Cell::empty()
+            })
+            .collect::<Vec<_>>()];
+        for (col, error) in errors.iter().enumerate() {
+            let formula = format!("=IFERROR({}1, \"caught\")", column_name(col));
+            assert_eq!(eval_in(&cells, &formula), text("caught"), "{:?}", error);
+        }
+        assert_eq!(eval("=IFERROR(1/0, \"x\")"), text("x"));
+        assert_eq!(eval("=IFERROR(SQRT(-1), \"x\")"), text("x"));
+        assert_eq!(eval("=IFERROR(\"a\"+1, \"x\")"), text("x"));
+        assert_eq!(eval("=IFERROR(NOPE(), \"x\")"), text("x"));
+        assert_eq!(eval("=IFERROR(5, 1/0)"), number(5.0));
+    }
+
+    #[test]
+    fn text_functions() {
+        // A thumbs up with a skin tone and e with a combining accent are one character each
+        assert_eq!(eval("=LEN(\"👍🏽\")"), number(1.0));
+        assert_eq!(eval("=LEN(\"e\u{301}te\")"), number(3.0));
+        assert_eq!(eval("=MID(\"a👍🏽b\", 2, 1)"), text("👍🏽"));
+        assert_eq!(eval("=MID(\"e\u{301}t\", 1, 1)"), text("e\u{301}"));
+        assert_eq!(eval("=LEFT(\"👍🏽x\")"), text("👍🏽"));
+
+        assert_eq!(eval("=MID(\"abc\", 2, 100)"), text("bc"));
+        assert_eq!(eval("=MID(\"abc\", 4, 1)"), text(""));
+        assert_eq!(eval("=MID(\"abc\", 2, 0)"), text(""));
+        assert_eq!(eval("=MID(\"abc\", 0, 1)"), error(FormulaError::Value));
+        assert_eq!(eval("=MID(\"abc\", 1, -1)"), error(FormulaError::Value));
+
+        assert_eq!(eval("=TEXT(1234.5, \"#,##0.00\")"), text("1,234.50"));
+        assert_eq!(eval("=TEXT(0.256, \"0.0%\")"), text("25.6%"));
+        assert_eq!(eval("=TEXT(\"7\", \"000\")"), text("007"));
+        let monday = "DATE(2024, 1, 15)";
+        for (pattern, expected) in [
+            ("yyyy-mm-dd", "2024-01-15"),
+            ("d/m/yy", "15/1/24"),
+            ("dddd, mmmm d", "Monday, January 15"),
+            ("ddd d mmm", "Mon 15 Jan"),
+            ("\"Week of\" d\\.m\\.", "Week of 15.1."),
+        ] {
+            let formula = format!("=TEXT({}, \"{}\")", monday, pattern.replace('"', "\"\""));
+            assert_eq!(eval(&formula), text(expected), "{}", pattern);
+        }
+        // m after an hour or before seconds is the minute
+        assert_eq!(
+            eval(&format!("=TEXT({}+0.75, \"hh:mm:ss\")", monday)),
+            text("18:00:00")
+        );
+        assert_eq!(
+            eval(&format!("=TEXT({}+0.75, \"h:mm AM/PM\")", monday)),
+            text("6:00 PM")
+        );
+        assert_eq!(eval("=TEXT(0.5/86400, \"h:m:s\")"), text("0:0:1"));
+        assert_eq!(eval("=TEXT(-1, \"yyyy\")"), error(FormulaError::Value));
+
+        assert_eq!(eval("=SUBSTITUTE(\"a-b-c\", \"-\", \"+\")"), text("a+b+c"));
+        assert_eq!(
+            eval("=SUBSTITUTE(\"a-b-c\", \"-\", \"+\", 2)"),
+            text("a-b+c")
+        );
+        assert_eq!(
+            eval("=SUBSTITUTE(\"a-b-c\", \"-\", \"+\", 3)"),
+            text("a-b-c")
+        );
+        assert_eq!(
+            eval("=SUBSTITUTE(\"a-b-c\", \"-\", \"+\", 0)"),
+            error(FormulaError::Value)
+        );
+
+        let cells = grid(&[&["", "1.5", "TRUE"]]);
+        assert_eq!(eval_in(&cells, "=\"a\"&B1"), text("a1.5"));
+        assert_eq!(eval_in(&cells, "=A1&\"b\"&C1"), text("bTRUE"));
+        assert_eq!(eval_in(&cells, "=1&2+3"), text("15"));
+        assert_eq!(eval_in(&cells, "=\"a\"&1/0"), error(FormulaError::DivZero));
+    }
+
+    #[test]
+    fn lookups() {
+        // Sorted keys, names, unsorted text, keys sorted descending and unsorted keys
+        let cells = grid(&[
+            &["10", "ten", "apple", "40", "30"],
+            &["20", "twenty", "banana", "30", "10"],
+            &["30", "thirty", "cherry", "20", "40"],
+            &["40", "forty", "apricot", "10", "20"],
+        ]);
+        let na = error(FormulaError::NotAvailable);
+
+        assert_eq!(
+            eval_in(&cells, "=VLOOKUP(20, A1:C4, 2, FALSE)"),
+            text("twenty")
+        );
+        assert_eq!(eval_in(&cells, "=VLOOKUP(25, A1:C4, 2, FALSE)"), na);
+        assert_eq!(eval_in(&cells, "=VLOOKUP(25, A1:C4, 2)"), text("twenty"));
+        assert_eq!(
+            eval_in(&cells, "=VLOOKUP(25, A1:C4, 2, TRUE)"),
+            text("twenty")
+        );
+        assert_eq!(eval_in(&cells, "=VLOOKUP(99, A1:C4, 3)"), text("apricot"));
+        assert_eq!(eval_in(&cells, "=VLOOKUP(5, A1:C4, 2)"), na);
+        assert_eq!(eval_in(&cells, "=VLOOKUP(\"10\", A1:C4, 2, FALSE)"), na);
+        assert_eq!(
+            eval_in(&cells, "=VLOOKUP(10, A1:C4, 4, FALSE)"),
+            error(FormulaError::Ref)
+        );
+        assert_eq!(
+            eval_in(&cells, "=VLOOKUP(10, A1:C4, 0, FALSE)"),
+            error(FormulaError::Value)
+        );
+        assert_eq!(
+            eval_in(&cells, "=VLOOKUP(\"AP*\", C1:C4, 1, FALSE)"),
+            text("apple")
+        );
+        assert_eq!(
+            eval_in(&cells, "=VLOOKUP(\"b?nana\", C1:C4, 1, FALSE)"),
+            text("banana")
+        );
+        assert_eq!(eval_in(&cells, "=VLOOKUP(\"c?\", C1:C4, 1, FALSE)"), na);
+
+        let row = grid(&[&["10", "20", "30"], &["x", "y", "z"]]);
+        assert_eq!(eval_in(&row, "=HLOOKUP(20, A1:C2, 2, FALSE)"), text("y"));
+        assert_eq!(eval_in(&row, "=HLOOKUP(25, A1:C2, 2)"), text("y"));
+        assert_eq!(eval_in(&row, "=HLOOKUP(25, A1:C2, 2, FALSE)"), na);
+        assert_eq!(eval_in(&row, "=HLOOKUP(5, A1:C2, 2)"), na);
+        assert_eq!(
+            eval_in(&row, "=HLOOKUP(20, A1:C2, 3, FALSE)"),
+            error(FormulaError::Ref)
+        );
+
+        assert_eq!(eval_in(&cells, "=MATCH(30, A1:A4, 0)"), number(3.0));
+        assert_eq!(eval_in(&cells, "=MATCH(35, A1:A4)"), number(3.0));
+        assert_eq!(eval_in(&cells, "=MATCH(35, A1:A4, 0)"), na);
+        assert_eq!(eval_in(&cells, "=MATCH(5, A1:A4, 1)"), na);
+        assert_eq!(eval_in(&cells, "=MATCH(25, D1:D4, -1)"), number(2.0));
+        assert_eq!(eval_in(&cells, "=MATCH(45, D1:D4, -1)"), na);
+        assert_eq!(eval_in(&cells, "=MATCH(\"*rr*\", C1:C4, 0)"), number(3.0));
+        assert_eq!(eval_in(&cells, "=MATCH(20, A1:D1, 0)"), na);
+
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(30, A1:A4, B1:B4)"),
+            text("thirty")
+        );
+        assert_eq!(eval_in(&cells, "=XLOOKUP(35, A1:A4, B1:B4)"), na);
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(35, A1:A4, B1:B4, \"none\")"),
+            text("none")
+        );
+        // The if not found value is only evaluated when nothing is found
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(30, A1:A4, B1:B4, 1/0)"),
+            text("thirty")
+        );
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(35, A1:A4, B1:B4, 0, -1)"),
+            text("thirty")
+        );
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(35, A1:A4, B1:B4, 0, 1)"),
+            text("forty")
+        );
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(45, A1:A4, B1:B4, 0, 1)"),
+            number(0.0)
+        );
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(\"ap*\", C1:C4, A1:A4, 0)"),
+            number(0.0)
+        );
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(\"ap*\", C1:C4, A1:A4, 0, 2)"),
+            number(10.0)
+        );
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(\"ap*\", C1:C4, A1:A4, 0, 2, -1)"),
+            number(40.0)
+        );
+        assert_eq!(eval_in(&cells, "=XLOOKUP(20, A1:C1, A2:C2)"), na);
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(\"ten\", A1:C1, A2:C2)"),
+            text("twenty")
+        );
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(30, A1:A4, B1:B3)"),
+            error(FormulaError::Value)
+        );
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(30, A1:A4, B1:B4, 0, 3)"),
+            error(FormulaError::Value)
+        );
+        assert_eq!(
+            eval_in(&cells, "=XLOOKUP(30, A1:A4, B1:B4, 0, 0, 0)"),
+            error(FormulaError::Value)
+        );
+
+        // Approximate matches on unsorted data still take the closest key
+        // below the value rather than wherever a binary search would stop
+        assert_eq!(eval_in(&cells, "=VLOOKUP(25, E1:E4, 1)"), number(20.0));
+        assert_eq!(eval_in(&cells, "=MATCH(35, E1:E4)"), number(1.0));
+        assert_eq!(eval_in(&cells, "=MATCH(15, E1:E4, -1)"), number(4.0));
+        assert_eq!(eval_in(&cells, "=MATCH(5, E1:E4)"), na);
+    }
+
+    #[test]
+    fn defined_names() {
+        let cells = grid(&[&["1"], &["2"]]);
+        let others = SheetCache::new();
+        let mut names = NameManager::new();
+        let define = |names: &mut NameManager, name: &str, definition: &str| {
+            names
+                .define(Name {
+                    name: name.to_string(),
+                    sheet: "Sheet1".to_string(),
+                    definition: definition.to_string(),
+                })
+                .unwrap()
+        };
+        let eval_with = |names: &NameManager, formula: &str| {
+            let expr = Parser::new(tokenize(&formula[1..]).unwrap())
+                .with_names(names)
+                .parse()
+                .unwrap();
+            Evaluator::new(&cells, NumericMode::Float)
+                .with_workbook(Workbook {
+                    name: "Sheet1",
+                    cells: &cells,
+                    others: &others,
+                })
+                .eval(&expr)
+        };
+
+        define(&mut names, "Revenue", "A1:A2");
+        define(&mut names, "Rate", "0.5");
+        define(&mut names, "Taxed", "SUM(Revenue)*Rate");
+        assert_eq!(eval_with(&names, "=SUM(revenue)*RATE"), number(1.5));
+        assert_eq!(eval_with(&names, "=Taxed+1"), number(2.5));
+        // Names resolve to their definition on the sheet they were defined on
+        let expr = Parser::new(tokenize("SUM(Revenue)").unwrap())
+            .with_names(&names)
+            .parse()
+            .unwrap();
+        assert_eq!(
+            expr.sheet_references(),
+            vec![("Sheet1".to_string(), Area::new((0, 0), (1, 0)))]
+        );
+
+        names.rename("Rate", "Tax").unwrap();
+        assert_eq!(eval_with(&names, "=Rate"), error(FormulaError::Name));
+        assert_eq!(eval_with(&names, "=Tax*4"), number(2.0));
+        // Definitions using the old name are rewritten by Spreadsheet::rename_name
+        assert_eq!(eval_with(&names, "=Taxed"), error(FormulaError::Name));
+
+        names.delete("REVENUE").unwrap();
+        assert_eq!(
+            eval_with(&names, "=SUM(Revenue)"),
+            error(FormulaError::Name)
+        );
+        assert!(names.delete("Revenue").is_err());
+        define(&mut names, "Revenue", "A2");
+        assert_eq!(eval_with(&names, "=SUM(Revenue)"), number(2.0));
+    }
+
+    // Counts the blank cells of a range, to test registering functions
+    struct Blanks;
+
+    impl Function for Blanks {
+        fn name(&self) -> &str {
+            "BLANKS"
+        }
+
+        fn arity(&self) -> Arity {
+            Arity::exactly(1)
+        }
+
+        fn arg_kinds(&self) -> &[ArgKind] {
+            &[ArgKind::Range]
+        }
+
+        fn evaluate(
+            &self,
+            evaluator: &Evaluator,
+            args: &[Expr],
+        ) -> Result<CellValue, FormulaError> {
+            let region = evaluator.reference(&args[0]).ok_or(FormulaError::Value)??;
+            let filled = region
+                .values()
+                .filter(|value| *value != CellValue::Empty)
+                .count();
+            Ok(CellValue::Number(
+                (region.rows() * region.cols() - filled) as f64,
+            ))
+        }
+    }
+
+    #[test]
+    fn custom_functions() {
+        let cells = grid(&[&["1", "", "x"], &["", "", "2"]]);
+        let mut functions = FunctionRegistry::with_builtins();
+        functions.register(Blanks);
+        functions.alias("Empties", "blanks");
+        let at = |formula| {
+            Evaluator::new(&cells, NumericMode::Float)
+                .with_functions(&functions)
+                .eval(&parse(formula).unwrap())
+        };
+
+        assert_eq!(at("=BLANKS(A1:C2)"), number(3.0));
+        assert_eq!(at("=blanks(A1:C3)+SUM(A1:C2)"), number(9.0));
+        assert_eq!(at("=Empties(B:B)"), number(MAX_ROWS as f64));
+        assert_eq!(at("=BLANKS(5)"), error(FormulaError::Value));
+        assert_eq!(at("=BLANKS(A1, A2)"), error(FormulaError::Value));
+        assert_eq!(eval_in(&cells, "=BLANKS(A1:C2)"), error(FormulaError::Name));
+
+        assert!(FunctionRegistry::builtins().get("BLANKS").is_none());
+        assert_eq!(functions.get("bLaNkS").unwrap().name(), "BLANKS");
+        assert_eq!(functions.get("EMPTIES").unwrap().name(), "BLANKS");
+        assert_eq!(functions.get("empties").unwrap().signature(), ["range"]);
+        assert!(functions.names().any(|name| name == "EMPTIES"));
+    }
+
+    #[test]
+    fn lambdas() {
+        let mut cells = grid(&[&["3", "2", ""]]);
+        let mut names = NameManager::new();
+        for (name, definition) in [
+            ("Rate", "0.5"),
+            ("Fact", "LAMBDA(n, IF(n<=1, 1, n*Fact(n-1)))"),
+            ("Fib", "LAMBDA(n, IF(n<2, n, Fib(n-1)+Fib(n-2)))"),
+            ("Scale", "LAMBDA(x, x*B1)"),
+            ("Twice", "LAMBDA(x, Scale(Scale(x)))"),
+        ] {
+            names
+                .define(Name {
+                    name: name.to_string(),
+                    sheet: "Sheet1".to_string(),
+                    definition: definition.to_string(),
+                })
+                .unwrap();
+        }
+        let others = SheetCache::new();
+        let parse_with = |formula: &str| {
+            Parser::new(tokenize(&formula[1..]).unwrap())
+                .with_names(&names)
+                .parse()
+                .unwrap()
+        };
+        let eval_with = |cells: &[Vec<Cell>], formula: &str| {
+            Evaluator::new(cells, NumericMode::Float)
+                .with_names(&names)
+                .with_workbook(Workbook {
+                    name: "Sheet1",
+                    cells,
+                    others: &others,
+                })
+                .eval(&parse_with(formula))
+        };
+
+        // Inner LET variables and parameters shadow outer ones and names
+        assert_eq!(
+            eval_with(&cells, "=LET(x, 1, LET(x, x+1, x*10))"),
+            number(20.0)
+        );
+        assert_eq!(
+            eval_with(&cells, "=LET(x, 1, f, LAMBDA(x, x*100), f(5)+x)"),
+            number(501.0)
+        );
+        assert_eq!(eval_with(&cells, "=LET(Rate, 2, Rate*3)+Rate"), number(6.5));
+        assert_eq!(
+            eval_with(&cells, "=LET(x, 1, y, x+1, x, y*5, x)"),
+            number(10.0)
+        );
+
+        assert_eq!(eval_with(&cells, "=Fact(5)"), number(120.0));
+        assert_eq!(eval_with(&cells, "=fact(A1)+Fact(1)"), number(7.0));
+        let deepest = format!("=Fact({})", MAX_LAMBDA_DEPTH);
+        assert!(matches!(eval_with(&cells, &deepest), CellValue::Number(_)));
+        let too_deep = format!("=Fact({})", MAX_LAMBDA_DEPTH + 1);
+        assert_eq!(eval_with(&cells, &too_deep), error(FormulaError::Num));
+        // The calls of the whole formula count towards the limit
+        assert_eq!(eval_with(&cells, "=Fib(17)"), number(1597.0));
+        assert_eq!(
+            eval_with(&cells, "=Fib(17)+Fib(17)"),
+            error(FormulaError::Num)
+        );
+
+        // A formula calling a lambda depends on what the lambda reads
+        let formula = "=Twice(A1)";
+        assert_eq!(eval_with(&cells, formula), number(12.0));
+        let expr = parse_with(formula);
+        let exprs = names.with_called(&expr, FunctionRegistry::builtins());
+        assert_eq!(exprs.len(), 3);
+        let mut graph = DependencyGraph::new();
+        let mut precedents: Vec<Area> = exprs.iter().flat_map(|expr| expr.references()).collect();
+        precedents.extend(
+            exprs
+                .iter()
+                .flat_map(|expr| expr.sheet_references())
+                .map(|(_, area)| area),
+        );
+        graph.set_precedents((0, 2), precedents);
+        assert_eq!(graph.recalc_order((0, 1)), vec![(0, 2)]);
+
+        cells[0][1].computed = number(10.0);
+        assert_eq!(eval_with(&cells, formula), number(300.0));
+    }
+
+    #[test]
+    fn decimal_mode() {
+        let expr = parse("=0.1+0.2").unwrap();
+        assert_eq!(
+            Evaluator::new(&[], NumericMode::Float).eval(&expr),
+            number(0.1 + 0.2)
+        );
+        assert_eq!(
+            Evaluator::new(&[], NumericMode::Decimal).eval(&expr),
+            number(0.3)
+        );
+        assert_eq!(
+            arithmetic(NumericMode::Decimal, BinaryOp::Multiply, 1e20, 1e20),
+            CellValue::Error(FormulaError::Num)
+        );
+    }
+
+    #[test]
+    fn malformed_formulas() {
+        assert_eq!(parse("=1+"), None);
+        assert_eq!(parse("=(1+2"), None);
+        assert_eq!(parse("=1 2"), None);
+        assert_eq!(parse("=\"open"), None);
     }
 }
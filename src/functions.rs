@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+use crate::cell::CellValue;
+use crate::formulas::{register_builtins, Evaluator, Expr, FormulaError};
+
+// What a function expects for an argument
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgKind {
+    Number,
+    Text,
+    Logical,
+    // A single value of any type
+    Value,
+    // A reference like A1:C9
+    Range,
+    // Values or references, like the arguments of SUM
+    Any,
+}
+
+impl ArgKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArgKind::Number => "number",
+            ArgKind::Text => "text",
+            ArgKind::Logical => "logical",
+            ArgKind::Value | ArgKind::Any => "value",
+            ArgKind::Range => "range",
+        }
+    }
+}
+
+// How many arguments a function takes, max is None when there's no limit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Arity {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    pub const fn exactly(count: usize) -> Self {
+        Self {
+            min: count,
+            max: Some(count),
+        }
+    }
+
+    pub const fn between(min: usize, max: usize) -> Self {
+        Self {
+            min,
+            max: Some(max),
+        }
+    }
+
+    pub const fn at_least(min: usize) -> Self {
+        Self { min, max: None }
+    }
+
+    pub fn accepts(&self, count: usize) -> bool {
+        count >= self.min && self.max.is_none_or(|max| count <= max)
+    }
+}
+
+// A function formulas can call. The arguments are passed unevaluated so a
+// function can skip the ones it doesn't need, like IF does, and read
+// references as ranges through the evaluator
+pub trait Function: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn arity(&self) -> Arity;
+
+    // Kinds of the arguments in order, the last one repeats for extra arguments.
+    // They only name the parameters in the hint, evaluate checks and converts
+    // the arguments itself
+    fn arg_kinds(&self) -> &[ArgKind];
+
+    fn evaluate(&self, evaluator: &Evaluator, args: &[Expr]) -> Result<CellValue, FormulaError>;
+
+    // Volatile functions like NOW recalculate after every edit
+    fn is_volatile(&self) -> bool {
+        false
+    }
+
+    // Parameter names for the hint shown while typing a call
+    fn params(&self) -> Vec<String> {
+        kind_params(self.arity(), self.arg_kinds())
+    }
+
+    // Parameters as the hint shows them, optional ones in brackets and ...
+    // when more can follow, e.g. range, criteria, [sum_range]
+    fn signature(&self) -> Vec<String> {
+        let arity = self.arity();
+        // Functions taking any number of arguments still want the first one
+        let required = match arity.max {
+            Some(_) => arity.min,
+            None => arity.min.max(1),
+        };
+        let mut params: Vec<String> = self
+            .params()
+            .into_iter()
+            .enumerate()
+            .map(|(i, param)| match i < required {
+                true => param,
+                false => format!("[{}]", param),
+            })
+            .collect();
+        if arity.max.is_none() {
+            params.push("...".to_string());
+        }
+        params
+    }
+}
+
+// Parameters named after their kinds, numbered when a kind repeats like
+// number1, number2. Functions without a limit show one optional extra
+fn kind_params(arity: Arity, kinds: &[ArgKind]) -> Vec<String> {
+    let count = arity.max.unwrap_or(arity.min.max(kinds.len()).max(1) + 1);
+    let kind = |i: usize| kinds.get(i).or(kinds.last()).map_or("value", ArgKind::name);
+    (0..count)
+        .map(|i| {
+            let name = kind(i);
+            let same: Vec<usize> = (0..count).filter(|&j| kind(j) == name).collect();
+            match same.len() {
+                1 => name.to_string(),
+                _ => format!("{}{}", name, same.iter().position(|&j| j == i).unwrap() + 1),
+            }
+        })
+        .collect()
+}
+
+// Functions looked up by name ignoring case, the built-ins and anything
+// registered on top of them
+#[derive(Clone, Default)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, Arc<dyn Function>>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        register_builtins(&mut registry);
+        registry
+    }
+
+    // Shared registry of just the built-ins, for evaluators nothing else was given to
+    pub fn builtins() -> &'static FunctionRegistry {
+        static BUILTINS: OnceLock<FunctionRegistry> = OnceLock::new();
+        BUILTINS.get_or_init(Self::with_builtins)
+    }
+
+    // Adds the function, replacing one with the same name
+    pub fn register(&mut self, function: impl Function + 'static) {
+        let name = function.name().to_uppercase();
+        self.functions.insert(name, Arc::new(function));
+    }
+
+    // Another name for a registered function, like STDEV.S for STDEV
+    pub fn alias(&mut self, alias: &str, name: &str) {
+        if let Some(function) = self.functions.get(&name.to_uppercase()).cloned() {
+            self.functions.insert(alias.to_uppercase(), function);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Function> {
+        self.functions.get(&name.to_uppercase()).map(Arc::as_ref)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.functions.keys().map(String::as_str)
+    }
+}
+
+pub type BuiltinFn = fn(&Evaluator, &[Expr]) -> Result<CellValue, FormulaError>;
+
+// Built-in functions are plain entries pointing at an evaluator method
+pub struct Builtin {
+    pub name: &'static str,
+    pub arity: Arity,
+    pub kinds: &'static [ArgKind],
+    // Parameter names like Excel's, empty to name them after the kinds
+    pub params: &'static [&'static str],
+    pub evaluate: BuiltinFn,
+    pub volatile: bool,
+}
+
+impl Function for Builtin {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    fn arg_kinds(&self) -> &[ArgKind] {
+        self.kinds
+    }
+
+    fn evaluate(&self, evaluator: &Evaluator, args: &[Expr]) -> Result<CellValue, FormulaError> {
+        (self.evaluate)(evaluator, args)
+    }
+
+    fn is_volatile(&self) -> bool {
+        self.volatile
+    }
+
+    fn params(&self) -> Vec<String> {
+        if self.params.is_empty() {
+            return kind_params(self.arity, self.kinds);
+        }
+        self.params.iter().map(|param| param.to_string()).collect()
+    }
+}
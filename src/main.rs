@@ -3,12 +3,21 @@ mod app;
 mod cell;
 use app::AppState;
 mod formulas;
+mod functions;
 mod render;
 mod spreadsheet;
 use render::render_app;
 mod command;
 mod database;
+mod date;
+mod dependency;
+mod editing;
 mod home;
+mod names;
+mod reference;
+mod sheets;
+mod spill;
+mod trace;
 fn main() -> Result<(), String> {
     let cell_width = 12;
     let cell_height = 3;
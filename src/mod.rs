@@ -2,9 +2,18 @@ pub mod app;
 pub mod cell;
 pub mod command;
 pub mod database;
+pub mod date;
+pub mod dependency;
+pub mod editing;
 pub mod formulas;
+pub mod functions;
 pub mod home;
+pub mod names;
 pub mod options;
+pub mod reference;
 pub mod render;
+pub mod sheets;
+pub mod spill;
+pub mod trace;
 pub mod spreadsheet;
 pub mod ui;
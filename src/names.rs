@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+
+use crate::formulas::{parse_definition, Expr};
+use crate::functions::FunctionRegistry;
+use crate::reference::CellRef;
+
+// A workbook level name like Revenue or VAT. The definition is formula text
+// such as B2:B40 or 0.21, its references point at the sheet it was defined on
+#[derive(Clone, Debug, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub sheet: String,
+    pub definition: String,
+}
+
+// Defined names shared by all sheets, looked up ignoring case
+#[derive(Clone, Default)]
+pub struct NameManager {
+    names: BTreeMap<String, Name>,
+    // Definitions parsed again whenever a name changes, since they can use
+    // each other, so lambdas aren't parsed on every call
+    parsed: BTreeMap<String, Expr>,
+}
+
+impl NameManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_names(names: Vec<Name>) -> Self {
+        let mut manager = Self::new();
+        for name in names {
+            manager.names.insert(name.name.to_uppercase(), name);
+        }
+        manager.parse_definitions();
+        manager
+    }
+
+    fn parse_definitions(&mut self) {
+        self.parsed = self
+            .names
+            .iter()
+            .filter_map(|(key, name)| Some((key.clone(), parse_definition(name, self)?)))
+            .collect();
+    }
+
+    // Definition of a name on the sheet it was defined on, None when it doesn't parse
+    pub fn parsed(&self, name: &str) -> Option<&Expr> {
+        self.parsed.get(&name.to_uppercase())
+    }
+
+    // The expression and the definitions of the lambdas it calls, and the
+    // ones those call, i.e. everything calculating it can read
+    pub fn with_called<'a>(
+        &'a self,
+        expr: &'a Expr,
+        functions: &FunctionRegistry,
+    ) -> Vec<&'a Expr> {
+        let mut exprs = vec![expr];
+        let mut seen: Vec<String> = Vec::new();
+        let mut calls = expr.calls();
+
+        while let Some(name) = calls.pop() {
+            let key = name.to_uppercase();
+            if functions.get(name).is_some() || seen.contains(&key) {
+                continue;
+            }
+            if let Some(definition) = self.parsed.get(&key) {
+                calls.extend(definition.calls());
+                exprs.push(definition);
+            }
+            seen.push(key);
+        }
+        exprs
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Name> {
+        self.names.get(&name.to_uppercase())
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &Name> {
+        self.names.values()
+    }
+
+    // Names starting with the prefix, for the picker while typing a formula
+    pub fn matching(&self, prefix: &str) -> Vec<&Name> {
+        let prefix = prefix.to_uppercase();
+        self.names
+            .iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    // Adds the name or replaces its definition
+    pub fn define(&mut self, name: Name) -> Result<(), String> {
+        if !is_valid_name(&name.name) {
+            return Err(format!("{} isn't a valid name", name.name));
+        }
+
+        self.names.insert(name.name.to_uppercase(), name);
+        self.parse_definitions();
+        Ok(())
+    }
+
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<Name, String> {
+        if !is_valid_name(new) {
+            return Err(format!("{} isn't a valid name", new));
+        }
+        if !old.eq_ignore_ascii_case(new) && self.get(new).is_some() {
+            return Err(format!("{} is already defined", new));
+        }
+
+        let mut name = self.delete(old)?;
+        name.name = new.to_string();
+        self.names.insert(new.to_uppercase(), name.clone());
+        self.parse_definitions();
+        Ok(name)
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<Name, String> {
+        let name = self
+            .names
+            .remove(&name.to_uppercase())
+            .ok_or(format!("{} isn't defined", name))?;
+        self.parse_definitions();
+        Ok(name)
+    }
+}
+
+// Like Excel, names start with a letter or _, use letters, digits, _ and .
+// and can't look like a cell reference or a boolean
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let starts_well = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_');
+
+    starts_well
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+        && CellRef::parse(name).is_none()
+        && !name.eq_ignore_ascii_case("TRUE")
+        && !name.eq_ignore_ascii_case("FALSE")
+}
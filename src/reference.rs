@@ -0,0 +1,319 @@
+use std::fmt;
+
+use crate::dependency::{Area, Position};
+
+// Same limits as Excel: columns A..XFD and rows 1..1048576
+pub const MAX_COLS: usize = 16384;
+pub const MAX_ROWS: usize = 1048576;
+
+// A1 style reference. Anchored parts ($A$1) stay fixed when a formula is copied
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CellRef {
+    pub row: usize,
+    pub col: usize,
+    pub abs_row: bool,
+    pub abs_col: bool,
+}
+
+impl CellRef {
+    pub fn new(row: usize, col: usize) -> Self {
+        Self {
+            row,
+            col,
+            abs_row: false,
+            abs_col: false,
+        }
+    }
+
+    pub fn position(&self) -> Position {
+        (self.row, self.col)
+    }
+
+    // Parses references like A1, aa10, $B$2 or XFD1048576
+    pub fn parse(reference: &str) -> Option<Self> {
+        let (abs_col, rest) = strip_dollar(reference);
+        let letters = rest.chars().take_while(|c| c.is_ascii_alphabetic()).count();
+        let (letters, rest) = rest.split_at(letters);
+        let (abs_row, digits) = strip_dollar(rest);
+
+        let col = column_index(letters)?;
+        let row = row_index(digits)?;
+
+        Some(Self {
+            row,
+            col,
+            abs_row,
+            abs_col,
+        })
+    }
+
+    // The reference in a formula copied rows down and cols right, the
+    // anchored parts stay. None when it moves off the sheet
+    pub fn shift(&self, rows: isize, cols: isize) -> Option<Self> {
+        let row = match self.abs_row {
+            true => self.row,
+            false => offset(self.row, rows, MAX_ROWS)?,
+        };
+        let col = match self.abs_col {
+            true => self.col,
+            false => offset(self.col, cols, MAX_COLS)?,
+        };
+        Some(Self { row, col, ..*self })
+    }
+}
+
+impl fmt::Display for CellRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dollar = |abs: bool| if abs { "$" } else { "" };
+        write!(
+            f,
+            "{}{}{}{}",
+            dollar(self.abs_col),
+            column_name(self.col),
+            dollar(self.abs_row),
+            self.row + 1
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RangeKind {
+    Cells,
+    // Whole columns like A:A, the rows of start and end are ignored
+    Columns,
+    // Whole rows like 3:3, the columns of start and end are ignored
+    Rows,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RangeRef {
+    pub start: CellRef,
+    pub end: CellRef,
+    pub kind: RangeKind,
+}
+
+impl RangeRef {
+    // Parses both sides of A1:C3, A:C or 1:3
+    pub fn parse(start: &str, end: &str) -> Option<Self> {
+        if let (Some(start), Some(end)) = (CellRef::parse(start), CellRef::parse(end)) {
+            return Some(Self {
+                start,
+                end,
+                kind: RangeKind::Cells,
+            });
+        }
+
+        let (start_abs, start) = strip_dollar(start);
+        let (end_abs, end) = strip_dollar(end);
+
+        if let (Some(start), Some(end)) = (column_index(start), column_index(end)) {
+            return Some(Self {
+                start: CellRef {
+                    col: start,
+                    abs_col: start_abs,
+                    ..CellRef::new(0, 0)
+                },
+                end: CellRef {
+                    col: end,
+                    abs_col: end_abs,
+                    ..CellRef::new(MAX_ROWS - 1, 0)
+                },
+                kind: RangeKind::Columns,
+            });
+        }
+
+        let (start, end) = (row_index(start)?, row_index(end)?);
+        Some(Self {
+            start: CellRef {
+                row: start,
+                abs_row: start_abs,
+                ..CellRef::new(0, 0)
+            },
+            end: CellRef {
+                row: end,
+                abs_row: end_abs,
+                ..CellRef::new(0, MAX_COLS - 1)
+            },
+            kind: RangeKind::Rows,
+        })
+    }
+
+    // Whole columns only move sideways and whole rows only up or down
+    pub fn shift(&self, rows: isize, cols: isize) -> Option<Self> {
+        let (rows, cols) = match self.kind {
+            RangeKind::Cells => (rows, cols),
+            RangeKind::Columns => (0, cols),
+            RangeKind::Rows => (rows, 0),
+        };
+        Some(Self {
+            start: self.start.shift(rows, cols)?,
+            end: self.end.shift(rows, cols)?,
+            ..*self
+        })
+    }
+
+    pub fn area(&self) -> Area {
+        match self.kind {
+            RangeKind::Cells => Area::new(self.start.position(), self.end.position()),
+            RangeKind::Columns => Area::new((0, self.start.col), (MAX_ROWS - 1, self.end.col)),
+            RangeKind::Rows => Area::new((self.start.row, 0), (self.end.row, MAX_COLS - 1)),
+        }
+    }
+}
+
+impl fmt::Display for RangeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dollar = |abs: bool| if abs { "$" } else { "" };
+        match self.kind {
+            RangeKind::Cells => write!(f, "{}:{}", self.start, self.end),
+            RangeKind::Columns => write!(
+                f,
+                "{}{}:{}{}",
+                dollar(self.start.abs_col),
+                column_name(self.start.col),
+                dollar(self.end.abs_col),
+                column_name(self.end.col)
+            ),
+            RangeKind::Rows => write!(
+                f,
+                "{}{}:{}{}",
+                dollar(self.start.abs_row),
+                self.start.row + 1,
+                dollar(self.end.abs_row),
+                self.end.row + 1
+            ),
+        }
+    }
+}
+
+fn offset(index: usize, by: isize, limit: usize) -> Option<usize> {
+    index.checked_add_signed(by).filter(|&index| index < limit)
+}
+
+fn strip_dollar(text: &str) -> (bool, &str) {
+    match text.strip_prefix('$') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    }
+}
+
+// Zero based column of a name, A -> 0, Z -> 25, AA -> 26
+pub fn column_index(name: &str) -> Option<usize> {
+    if name.is_empty() || name.len() > 3 || !name.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let col = name.chars().fold(0, |col, c| {
+        col * 26 + (c.to_ascii_uppercase() as usize - 'A' as usize + 1)
+    });
+
+    (col <= MAX_COLS).then(|| col - 1)
+}
+
+// Zero based row of a one based row number
+pub fn row_index(digits: &str) -> Option<usize> {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    match digits.parse::<usize>().ok()? {
+        row @ 1..=MAX_ROWS => Some(row - 1),
+        _ => None,
+    }
+}
+
+pub fn column_name(col: usize) -> String {
+    let mut name = Vec::new();
+    let mut col = col + 1;
+
+    while col > 0 {
+        let rem = (col - 1) % 26;
+        name.push((b'A' + rem as u8) as char);
+        col = (col - 1) / 26;
+    }
+
+    name.iter().rev().collect()
+}
+
+pub fn cell_name((row, col): Position) -> String {
+    CellRef::new(row, col).to_string()
+}
+
+pub fn area_name(area: Area) -> String {
+    if area.start == area.end {
+        cell_name(area.start)
+    } else {
+        format!("{}:{}", cell_name(area.start), cell_name(area.end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn column_names() {
+        for (name, index) in [
+            ("A", 0),
+            ("Z", 25),
+            ("AA", 26),
+            ("AZ", 51),
+            ("BA", 52),
+            ("ZZ", 701),
+            ("AAA", 702),
+            ("XFD", MAX_COLS - 1),
+        ] {
+            assert_eq!(column_index(name), Some(index), "{}", name);
+            assert_eq!(column_name(index), name);
+        }
+        assert_eq!(column_index("aa"), Some(26));
+        for name in ["", "XFE", "AAAA", "A1", "$A"] {
+            assert_eq!(column_index(name), None, "{}", name);
+        }
+        for col in 0..MAX_COLS {
+            assert_eq!(column_index(&column_name(col)), Some(col));
+        }
+    }
+
+    #[test]
+    fn cell_references() {
+        let cell = CellRef::parse("$B$2").unwrap();
+        assert_eq!(
+            (cell.row, cell.col, cell.abs_row, cell.abs_col),
+            (1, 1, true, true)
+        );
+        let cell = CellRef::parse("aa$10").unwrap();
+        assert_eq!(
+            (cell.row, cell.col, cell.abs_row, cell.abs_col),
+            (9, 26, true, false)
+        );
+        assert_eq!(cell.to_string(), "AA$10");
+
+        let last = CellRef::parse("XFD1048576").unwrap();
+        assert_eq!(last.position(), (MAX_ROWS - 1, MAX_COLS - 1));
+        for reference in ["$A$1", "A$1", "$A1", "XFD1048576", "ZZ99"] {
+            assert_eq!(CellRef::parse(reference).unwrap().to_string(), reference);
+        }
+        for reference in [
+            "XFE1", "A0", "A1048577", "1A", "A", "1", "$$A1", "A1$", "A-1",
+        ] {
+            assert_eq!(CellRef::parse(reference), None, "{}", reference);
+        }
+    }
+
+    #[test]
+    fn range_references() {
+        let range = RangeRef::parse("C3", "A1").unwrap();
+        assert_eq!(range.area(), Area::new((0, 0), (2, 2)));
+        let columns = RangeRef::parse("$A", "C").unwrap();
+        assert_eq!(columns.kind, RangeKind::Columns);
+        assert_eq!(columns.area(), Area::new((0, 0), (MAX_ROWS - 1, 2)));
+        assert_eq!(columns.to_string(), "$A:C");
+        let rows = RangeRef::parse("3", "$5").unwrap();
+        assert_eq!(rows.kind, RangeKind::Rows);
+        assert_eq!(rows.area(), Area::new((2, 0), (4, MAX_COLS - 1)));
+        assert_eq!(rows.to_string(), "3:$5");
+        assert_eq!(RangeRef::parse("A1", "B"), None);
+        assert_eq!(RangeRef::parse("0", "3"), None);
+    }
+}
@@ -42,7 +42,7 @@ pub fn render_app(app: &mut AppState) -> Result<(), String> {
             if key.kind == KeyEventKind::Press {
                 match app.mode {
                     AppMode::Normal => {
-                        app.spreadsheet.focus(&mut stdout, key.code);
+                        app.spreadsheet.focus(&mut stdout, key.code, key.modifiers);
                     }
                     AppMode::Command => {
                         app.command.focus(&mut stdout, key.code);
@@ -53,7 +53,7 @@ pub fn render_app(app: &mut AppState) -> Result<(), String> {
                 }
                 match key.code {
                     KeyCode::Char(c) => match c {
-                        ':' => {
+                        ':' if !app.spreadsheet.text_edit => {
                             app.spreadsheet.select_color = Color::DarkGrey;
                             app.spreadsheet.draw(&mut stdout);
                             app.mode = AppMode::Command;
@@ -64,6 +64,8 @@ pub fn render_app(app: &mut AppState) -> Result<(), String> {
                             if app.mode == AppMode::Home {
                                 let sheet = app.home.sheets[app.home.selected].name.clone();
                                 app.database.delete_sheet(&sheet);
+                                // References to it from the open sheet become #REF!
+                                app.spreadsheet.sheet_changed(&sheet);
                                 let sheets = app.database.get_sheets().unwrap();
                                 app.home.sheets = sheets;
                                 app.home.draw(&mut stdout);
@@ -95,7 +97,9 @@ pub fn render_app(app: &mut AppState) -> Result<(), String> {
                         }
                         _ => {
                             if app.command.input.starts_with(":") {
-                                app.handle_command()
+                                app.handle_command(&mut stdout);
+                                app.spreadsheet.draw(&mut stdout);
+                                app.command.draw(&mut stdout);
                             } else if app.command.input.starts_with("/") {
                                 app.handle_search()
                             }
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+use crate::cell::Cell;
+
+// Other sheets read through cross-sheet references like Sheet2!A1, loaded
+// from the database the first time a formula needs them. Sheets that don't
+// exist (anymore) are kept as None so their references show #REF!
+#[derive(Default)]
+pub struct SheetCache {
+    sheets: HashMap<String, Option<Vec<Vec<Cell>>>>,
+}
+
+impl SheetCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[Vec<Cell>]> {
+        self.sheets.get(&key(name))?.as_deref()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.sheets.contains_key(&key(name))
+    }
+
+    pub fn insert(&mut self, name: &str, cells: Option<Vec<Vec<Cell>>>) {
+        self.sheets.insert(key(name), cells);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.sheets.remove(&key(name));
+    }
+
+    pub fn clear(&mut self) {
+        self.sheets.clear();
+    }
+}
+
+// Sheet names are case insensitive like in Excel
+fn key(name: &str) -> String {
+    name.to_lowercase()
+}
+
+// Every sheet a formula can read: the open one and the cached others
+#[derive(Clone, Copy)]
+pub struct Workbook<'a> {
+    pub name: &'a str,
+    pub cells: &'a [Vec<Cell>],
+    pub others: &'a SheetCache,
+}
+
+impl<'a> Workbook<'a> {
+    pub fn sheet(&self, name: &str) -> Option<&'a [Vec<Cell>]> {
+        if name.eq_ignore_ascii_case(self.name) {
+            Some(self.cells)
+        } else {
+            self.others.get(name)
+        }
+    }
+}
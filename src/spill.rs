@@ -0,0 +1,177 @@
+use crate::cell::{Cell, CellValue};
+use crate::dependency::{Area, Position};
+use crate::formulas::FormulaError;
+
+// Array results spill from the formula's cell (the anchor) into the cells
+// to the right and below. The anchor remembers the size of its array and
+// shows the top left value, the other cells keep their empty input and get
+// the rest of the values
+
+// Puts a formula result into its cell and spills arrays, returning what the
+// anchor shows. Blocked arrays make the anchor #SPILL!
+pub fn place(cells: &mut [Vec<Cell>], (row, col): Position, value: CellValue) -> CellValue {
+    clear(cells, (row, col));
+
+    let rows = match value {
+        CellValue::Array(rows) if rows.is_empty() || rows[0].is_empty() => {
+            return set(cells, (row, col), CellValue::Error(FormulaError::Calc))
+        }
+        CellValue::Array(rows) if rows.len() > 1 || rows[0].len() > 1 => rows,
+        CellValue::Array(mut rows) => return set(cells, (row, col), rows[0].swap_remove(0)),
+        // A lambda has to be called to give a value
+        CellValue::Lambda(_) => {
+            return set(cells, (row, col), CellValue::Error(FormulaError::Calc))
+        }
+        value => return set(cells, (row, col), value),
+    };
+
+    let size = (rows.len(), rows[0].len());
+    cells[row][col].spill = Some(size);
+    if is_blocked(cells, (row, col), size) {
+        return set(cells, (row, col), CellValue::Error(FormulaError::Spill));
+    }
+
+    for (i, values) in rows.into_iter().enumerate() {
+        for (j, value) in values.into_iter().enumerate() {
+            cells[row + i][col + j].computed = value;
+        }
+    }
+    cells[row][col].computed.clone()
+}
+
+fn set(cells: &mut [Vec<Cell>], (row, col): Position, value: CellValue) -> CellValue {
+    cells[row][col].computed = value.clone();
+    value
+}
+
+// Empties the cells the anchor's array spilled into
+pub fn clear(cells: &mut [Vec<Cell>], anchor: Position) {
+    if let Some(area) = area(cells, anchor) {
+        let rows = cells
+            .iter_mut()
+            .enumerate()
+            .take(area.end.0 + 1)
+            .skip(area.start.0);
+        for (row, cells) in rows {
+            let cols = cells
+                .iter_mut()
+                .enumerate()
+                .take(area.end.1 + 1)
+                .skip(area.start.1);
+            for (col, cell) in cols {
+                if (row, col) != anchor && cell.value.is_empty() {
+                    cell.computed = CellValue::Empty;
+                }
+            }
+        }
+    }
+    cells[anchor.0][anchor.1].spill = None;
+}
+
+// Cells the anchor's array fills, None when it isn't an array or is blocked
+pub fn area(cells: &[Vec<Cell>], (row, col): Position) -> Option<Area> {
+    let cell = cells.get(row)?.get(col)?;
+    let (rows, cols) = cell.spill?;
+    if cell.computed == CellValue::Error(FormulaError::Spill) {
+        return None;
+    }
+    Some(Area::new((row, col), (row + rows - 1, col + cols - 1)))
+}
+
+// Anchors whose arrays cover the cell or would if they weren't blocked,
+// they need to spill again when its input changes
+pub fn anchors_over(cells: &[Vec<Cell>], (row, col): Position) -> Vec<Position> {
+    let mut anchors = Vec::new();
+    for (i, cells) in cells.iter().enumerate().take(row + 1) {
+        for (j, cell) in cells.iter().enumerate().take(col + 1) {
+            if let Some((rows, cols)) = cell.spill {
+                if (i, j) != (row, col) && row < i + rows && col < j + cols {
+                    anchors.push((i, j));
+                }
+            }
+        }
+    }
+    anchors
+}
+
+// An array can't run off the sheet, cover input or overlap another array
+fn is_blocked(cells: &[Vec<Cell>], anchor: Position, (rows, cols): (usize, usize)) -> bool {
+    let end = (anchor.0 + rows - 1, anchor.1 + cols - 1);
+    if end.0 >= cells.len() || end.1 >= cells[0].len() {
+        return true;
+    }
+
+    let spill = Area::new(anchor, end);
+    let mut rows = cells.iter().enumerate().take(end.0 + 1).skip(anchor.0);
+    let covers_input = rows.any(|(row, cells)| {
+        let mut cols = cells.iter().enumerate().take(end.1 + 1).skip(anchor.1);
+        cols.any(|(col, cell)| (row, col) != anchor && !cell.value.is_empty())
+    });
+    if covers_input {
+        return true;
+    }
+
+    cells.iter().enumerate().any(|(row, line)| {
+        (0..line.len()).any(|col| {
+            (row, col) != anchor
+                && area(cells, (row, col)).is_some_and(|other| other.intersect(&spill).is_some())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(rows: usize, cols: usize) -> Vec<Vec<Cell>> {
+        vec![vec![Cell::empty(); cols]; rows]
+    }
+
+    fn array(rows: usize, cols: usize) -> CellValue {
+        let row = |i| {
+            (0..cols)
+                .map(|j| CellValue::Number((i * cols + j) as f64))
+                .collect()
+        };
+        CellValue::Array((0..rows).map(row).collect())
+    }
+
+    #[test]
+    fn spills_and_clears() {
+        let mut cells = sheet(4, 4);
+        assert_eq!(
+            place(&mut cells, (1, 1), array(2, 2)),
+            CellValue::Number(0.0)
+        );
+        assert_eq!(cells[2][2].computed, CellValue::Number(3.0));
+        assert_eq!(anchors_over(&cells, (2, 2)), vec![(1, 1)]);
+
+        place(&mut cells, (1, 1), CellValue::Number(7.0));
+        assert_eq!(cells[1][1].computed, CellValue::Number(7.0));
+        assert_eq!(cells[2][2].computed, CellValue::Empty);
+    }
+
+    #[test]
+    fn blocked() {
+        let spill = CellValue::Error(FormulaError::Spill);
+        let mut cells = sheet(4, 4);
+        // Off the sheet
+        assert_eq!(place(&mut cells, (3, 0), array(2, 1)), spill);
+
+        // Covering input
+        cells[1][1].value = "x".to_string();
+        assert_eq!(place(&mut cells, (0, 0), array(2, 2)), spill);
+        cells[1][1].value.clear();
+        assert_eq!(
+            place(&mut cells, (0, 0), array(2, 2)),
+            CellValue::Number(0.0)
+        );
+
+        // Overlapping another array
+        assert_eq!(place(&mut cells, (1, 1), array(2, 2)), spill);
+        assert_eq!(
+            place(&mut cells, (2, 2), array(2, 2)),
+            CellValue::Number(0.0)
+        );
+    }
+}
@@ -1,17 +1,32 @@
-use std::{io::Write, usize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::Write,
+    usize,
+};
 
 use crossterm::{
     cursor::MoveTo,
-    event::{self, Event, KeyCode, KeyEventKind, ModifierKeyCode},
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers, ModifierKeyCode},
     style::{Color, ResetColor, SetBackgroundColor, Stylize},
     terminal::{self, Clear, ClearType},
     ExecutableCommand,
 };
 
 use crate::{
-    cell::{self, Cell},
+    cell::{self, Cell, CellValue},
     database::Database,
-    formulas::{FormulaHandler, FormulaType},
+    date::{Clock, SystemClock},
+    dependency::{format_cycle, Area, DependencyGraph, Position},
+    editing::{self, ReferencePick, ReferenceSpan, Suggestion},
+    formulas::{
+        shift_formula, Evaluator, Expr, FormulaError, FormulaHandler, Iteration, NumericMode,
+    },
+    functions::FunctionRegistry,
+    names::{Name, NameManager},
+    reference::{area_name, cell_name, column_name, CellRef},
+    sheets::{SheetCache, Workbook},
+    spill,
+    trace::{self, Trace},
 };
 
 pub struct Spreadsheet {
@@ -24,30 +39,66 @@ pub struct Spreadsheet {
     pub text_edit: bool,
     pub cursor_pos: usize,
     pub database: Database,
+    pub numeric_mode: NumericMode,
+    pub dependencies: DependencyGraph,
+    pub cycles: Vec<Vec<Position>>,
+    pub iteration: Option<Iteration>,
+    pub status_message: Option<String>,
+    pub clock: Box<dyn Clock>,
+    pub sheets: SheetCache,
+    pub names: NameManager,
+    // Functions formulas can call, forks register their own here
+    pub functions: FunctionRegistry,
+    // Selected entry of the picker of names and functions shown while
+    // typing a formula, and whether Up/Down moved it there
+    pub picker: usize,
+    pub picker_moved: bool,
+    // Cell copied with y and its input, p pastes it
+    pub clipboard: Option<(Position, String)>,
+    // :trace explains the active cell's formula and marks the cells it
+    // reads and the formulas reading it
+    pub tracing: bool,
+    // Reference being picked with the arrow keys while typing a formula
+    pub pick: Option<ReferencePick>,
 }
 
 const AXIS_WIDTH: u16 = 5;
 const AXIS_HEIGHT: u16 = 2;
+// Bottom border, status line and command line
+const STATUS_HEIGHT: u16 = 3;
+const ERROR_COLOR: Color = Color::Magenta;
+// Most entries shown at once in the picker
+const PICKER_SIZE: usize = 5;
+// References in the formula being typed and the cells they point at
+const REFERENCE_COLORS: [Color; 6] = [
+    Color::Blue,
+    Color::Red,
+    Color::DarkMagenta,
+    Color::DarkGreen,
+    Color::DarkYellow,
+    Color::DarkCyan,
+];
+// Cells a traced formula reads and formulas reading it
+const PRECEDENT_COLOR: Color = Color::Cyan;
+const DEPENDENT_COLOR: Color = Color::Yellow;
 
 impl Spreadsheet {
     pub fn new(cell_width: usize, cell_height: usize) -> Self {
         let (width, height) = terminal::size().unwrap();
 
-        let rows = ((height - AXIS_HEIGHT) / cell_height as u16) as usize;
+        let rows = ((height - AXIS_HEIGHT - STATUS_HEIGHT) / cell_height as u16) as usize;
         let cols = ((width - AXIS_WIDTH) / cell_width as u16) as usize;
 
-        let cells = vec![
-            vec![
-                cell::Cell {
-                    value: "".to_string(),
-                    color: Color::Red,
-                    formula: false,
-                };
-                cols
-            ];
-            rows
-        ];
-        let database = Database::new().unwrap();
+        let cells = vec![vec![cell::Cell::empty(); cols]; rows];
+        Self::with_cells(cells, cell_width, cell_height, Database::new().unwrap())
+    }
+
+    fn with_cells(
+        cells: Vec<Vec<cell::Cell>>,
+        cell_width: usize,
+        cell_height: usize,
+        database: Database,
+    ) -> Self {
         Self {
             id: "Profits".to_string(),
             cells,
@@ -58,26 +109,240 @@ impl Spreadsheet {
             text_edit: false,
             cursor_pos: 0,
             database,
+            numeric_mode: NumericMode::Float,
+            dependencies: DependencyGraph::new(),
+            cycles: Vec::new(),
+            iteration: None,
+            status_message: None,
+            clock: Box::new(SystemClock),
+            sheets: SheetCache::new(),
+            names: NameManager::new(),
+            functions: FunctionRegistry::with_builtins(),
+            picker: 0,
+            picker_moved: false,
+            clipboard: None,
+            tracing: false,
+            pick: None,
         }
     }
 
     pub fn load_cells(&mut self, cells: Vec<(String, String)>) {
         let cells = self.fill_all_cells(cells);
         self.cells = cells;
+        // Other sheets may have changed while this one was closed
+        self.sheets.clear();
+        self.names = NameManager::from_names(self.database.get_names().unwrap_or_default());
+        self.recalculate_all();
     }
 
-    pub fn fill_all_cells(&mut self, cells: Vec<(String, String)>) -> Vec<Vec<Cell>> {
-        let mut result: Vec<Vec<Cell>> = vec![
-            vec![
-                Cell {
-                    value: String::new(),
-                    color: Color::Red,
-                    formula: false,
+    // Evaluates every formula once, in dependency order
+    pub fn recalculate_all(&mut self) {
+        self.dependencies.clear();
+        for row in 0..self.cells.len() {
+            for col in 0..self.cells[row].len() {
+                if self.cells[row][col].value.starts_with("=") {
+                    self.register_formula(row, col);
+                }
+            }
+        }
+
+        self.cycles = self.dependencies.find_cycles();
+        let order = self.dependencies.full_order();
+        self.evaluate_in_order(order);
+    }
+
+    fn register_formula(&mut self, row: usize, col: usize) {
+        let expr = self.parse_formula(&self.cells[row][col].value);
+        self.track_formula((row, col), expr.as_ref());
+    }
+
+    // Records what a formula reads, None when it doesn't parse
+    pub fn track_formula(&mut self, cell: Position, expr: Option<&Expr>) {
+        let Some(expr) = expr else {
+            self.dependencies.set_precedents(cell, Vec::new());
+            self.dependencies.set_volatile(cell, false);
+            self.dependencies.set_sheets(cell, Vec::new());
+            return;
+        };
+
+        // Formulas also read what the lambdas they call read
+        let exprs = self.names.with_called(expr, &self.functions);
+        let mut sheets = Vec::new();
+        for (sheet, _) in exprs.iter().flat_map(|expr| expr.sheet_references()) {
+            if !sheet.eq_ignore_ascii_case(&self.id) && !sheets.contains(&sheet) {
+                sheets.push(sheet);
+            }
+        }
+
+        self.dependencies
+            .set_precedents(cell, local_references(&exprs, &self.id));
+        self.dependencies
+            .set_volatile(cell, expr.is_volatile(&self.functions));
+        self.dependencies.set_sheets(cell, sheets);
+    }
+
+    pub fn workbook(&self) -> Workbook<'_> {
+        Workbook {
+            name: &self.id,
+            cells: &self.cells,
+            others: &self.sheets,
+        }
+    }
+
+    // Loads the sheets other than the given one an expression reads that aren't cached yet
+    pub fn load_sheets(&mut self, expr: &Expr, current: &str) {
+        let references: Vec<(String, Area)> = self
+            .names
+            .with_called(expr, &self.functions)
+            .iter()
+            .flat_map(|expr| expr.sheet_references())
+            .collect();
+        for (sheet, _) in references {
+            if !sheet.eq_ignore_ascii_case(current) && !self.sheets.contains(&sheet) {
+                self.load_other_sheet(&sheet);
+            }
+        }
+    }
+
+    // Reads another sheet from the database and calculates its formulas, so
+    // cross-sheet references see the same values as when that sheet is open
+    fn load_other_sheet(&mut self, name: &str) {
+        // Cached before loading, so sheets reading each other don't load forever
+        self.sheets.insert(name, None);
+
+        let sheets = self.database.get_sheets().unwrap_or_default();
+        let Some(sheet) = sheets
+            .into_iter()
+            .find(|sheet| sheet.name.eq_ignore_ascii_case(name))
+        else {
+            return;
+        };
+        let Ok(values) = self
+            .database
+            .get_cells(&format!("spreadsheet:{}", sheet.name))
+        else {
+            return;
+        };
+        let mut cells = self.fill_all_cells(values);
+
+        let mut graph = DependencyGraph::new();
+        let mut formulas = HashMap::new();
+        for (row, cells) in cells.iter_mut().enumerate() {
+            for (col, cell) in cells.iter_mut().enumerate() {
+                if !cell.value.starts_with("=") {
+                    continue;
+                }
+                match self.parse_formula(&cell.value) {
+                    Some(expr) => {
+                        self.load_sheets(&expr, &sheet.name);
+                        let exprs = self.names.with_called(&expr, &self.functions);
+                        graph.set_precedents((row, col), local_references(&exprs, &sheet.name));
+                        formulas.insert((row, col), expr);
+                    }
+                    None => cell.computed = CellValue::Error(FormulaError::Name),
+                }
+            }
+        }
+
+        let on_cycle: Vec<Position> = graph.find_cycles().into_iter().flatten().collect();
+        for (row, col) in graph.full_order() {
+            let value = if on_cycle.contains(&(row, col)) {
+                CellValue::Error(FormulaError::Cycle)
+            } else {
+                let workbook = Workbook {
+                    name: &sheet.name,
+                    cells: &cells,
+                    others: &self.sheets,
                 };
-                self.cells[0].len()
-            ];
-            self.cells.len()
-        ];
+                Evaluator::new(&cells, self.numeric_mode)
+                    .with_clock(self.clock.as_ref())
+                    .with_workbook(workbook)
+                    .with_functions(&self.functions)
+                    .with_names(&self.names)
+                    .eval(&formulas[&(row, col)])
+            };
+            spill::place(&mut cells, (row, col), value);
+        }
+
+        self.sheets.insert(name, Some(cells));
+    }
+
+    // Another sheet was deleted or changed, formulas reading it are
+    // recalculated from its current state
+    pub fn sheet_changed(&mut self, name: &str) {
+        self.sheets.remove(name);
+        let order = self.dependencies.sheet_recalc_order(name);
+        self.evaluate_in_order(order);
+    }
+
+    pub fn cycle_of(&self, cell: Position) -> Option<&Vec<Position>> {
+        self.cycles.iter().find(|cycle| cycle.contains(&cell))
+    }
+
+    // Formulas reading the cells of a spill area, in recalculation order
+    fn spill_dependents(&self, area: Area) -> Vec<Position> {
+        let cells: Vec<Position> = (area.start.0..=area.end.0)
+            .flat_map(|row| (area.start.1..=area.end.1).map(move |col| (row, col)))
+            .collect();
+        self.dependencies
+            .order_from(&cells)
+            .into_iter()
+            .filter(|cell| !area.contains(*cell))
+            .collect()
+    }
+
+    fn evaluate_in_order(&mut self, order: Vec<Position>) {
+        let on_cycle = order.iter().any(|cell| self.cycle_of(*cell).is_some());
+
+        match self.iteration {
+            Some(iteration) if on_cycle => {
+                // Cycles start again from blank cells rather than from #CYCLE!
+                for &(row, col) in &order {
+                    if self.cells[row][col].computed == CellValue::Error(FormulaError::Cycle) {
+                        self.cells[row][col].computed = CellValue::Empty;
+                    }
+                }
+
+                for _ in 0..iteration.max_iterations {
+                    let mut change: f64 = 0.0;
+                    for &(row, col) in &order {
+                        let before = self.cells[row][col].computed.clone();
+                        let after = self.enter_formula(row, col);
+                        change = change.max(value_change(&before, &after));
+                    }
+                    if change < iteration.epsilon {
+                        break;
+                    }
+                }
+            }
+            _ => {
+                let mut order = VecDeque::from(order);
+                let mut spilled = HashSet::new();
+                while let Some((row, col)) = order.pop_front() {
+                    if self.cycle_of((row, col)).is_some() {
+                        self.cells[row][col].computed = CellValue::Error(FormulaError::Cycle);
+                        continue;
+                    }
+
+                    let before = spill::area(&self.cells, (row, col));
+                    self.enter_formula(row, col);
+                    let after = spill::area(&self.cells, (row, col));
+                    // Formulas reading cells the array spills into, or used to,
+                    // come after it. Once per array, so an array sized by its own
+                    // values can't go on forever
+                    if (before.is_some() || after.is_some()) && spilled.insert((row, col)) {
+                        for area in [before, after].into_iter().flatten() {
+                            order.extend(self.spill_dependents(area));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn fill_all_cells(&mut self, cells: Vec<(String, String)>) -> Vec<Vec<Cell>> {
+        let mut result: Vec<Vec<Cell>> =
+            vec![vec![Cell::empty(); self.cells[0].len()]; self.cells.len()];
 
         for (key, value) in cells {
             let parts = key.split(":").collect::<Vec<&str>>();
@@ -88,8 +353,10 @@ impl Spreadsheet {
             if row < result.len() && col < result[0].len() {
                 let new_cell = Cell {
                     formula: is_formula,
+                    computed: CellValue::parse(&value),
                     value,
                     color: Color::Red,
+                    spill: None,
                 };
 
                 result[row][col] = new_cell;
@@ -99,13 +366,75 @@ impl Spreadsheet {
         result
     }
 
-    pub fn focus<W: Write>(&mut self, stdout: &mut W, code: KeyCode) {
+    pub fn focus<W: Write>(&mut self, stdout: &mut W, code: KeyCode, modifiers: KeyModifiers) {
+        // The Enter starting an edit shouldn't also pick a name
+        let editing = self.text_edit;
         self.handle_key_press(code, stdout);
-        if self.text_edit {
-            self.write_text(code, stdout)
+        if editing && self.text_edit {
+            if self.pick_reference(code, modifiers.contains(KeyModifiers::SHIFT)) {
+                self.draw(stdout);
+            } else {
+                self.write_text(code, stdout)
+            }
         }
     }
 
+    // Like in Excel, the arrow keys pick the cell for a reference when one
+    // can go at the cursor, e.g. after =SUM( and Shift+arrows stretch it
+    // into a range. Any other key ends picking. Returns whether the key
+    // was used for picking
+    fn pick_reference(&mut self, code: KeyCode, shift: bool) -> bool {
+        let step = match code {
+            KeyCode::Up => (-1, 0),
+            KeyCode::Down => (1, 0),
+            KeyCode::Left => (0, -1),
+            KeyCode::Right => (0, 1),
+            _ => {
+                self.pick = None;
+                return false;
+            }
+        };
+
+        let (row, col) = self.active();
+        let pick = match self.pick.take() {
+            Some(pick) => pick,
+            None if editing::accepts_reference(&self.cells[row][col].value, self.cursor_pos) => {
+                ReferencePick {
+                    start: self.cursor_pos,
+                    len: 0,
+                    anchor: (row, col),
+                    end: (row, col),
+                }
+            }
+            None => return false,
+        };
+
+        let end = (
+            pick.end
+                .0
+                .saturating_add_signed(step.0)
+                .min(self.cells.len() - 1),
+            pick.end
+                .1
+                .saturating_add_signed(step.1)
+                .min(self.cells[0].len() - 1),
+        );
+        let anchor = if shift { pick.anchor } else { end };
+        let text = area_name(Area::new(anchor, end));
+
+        let range = pick.start..pick.start + pick.len;
+        self.cells[row][col].value.replace_range(range, &text);
+        self.cursor_pos = pick.start + text.len();
+        self.pick = Some(ReferencePick {
+            len: text.len(),
+            anchor,
+            end,
+            ..pick
+        });
+        self.update_cell(row, col);
+        true
+    }
+
     pub fn clear_prev<W: Write>(&self, out: &mut W) {
         for line in 1..self.cell_height {
             out.execute(MoveTo(
@@ -120,6 +449,7 @@ impl Spreadsheet {
         // out.execute(terminal::Clear(ClearType::All)).unwrap();
         //
         if !self.text_edit {
+            self.status_message = None;
             match key {
                 KeyCode::Up => {
                     if self.active_cell.row > 0 {
@@ -152,6 +482,8 @@ impl Spreadsheet {
                     self.cursor_pos = cell_len;
                     self.enter_text();
                 }
+                KeyCode::Char('y') => self.copy(),
+                KeyCode::Char('p') => self.paste(),
                 _ => {}
             }
         }
@@ -182,21 +514,33 @@ impl Spreadsheet {
                     self.cursor_pos += 1;
                 }
 
-                let cell_value = self.cells[self.active_cell.row][self.active_cell.col]
-                    .value
-                    .clone();
+                self.picker = 0;
+                self.picker_moved = false;
+                self.update_cell(self.active_cell.row, self.active_cell.col);
+            }
+
+            KeyCode::Up => {
+                self.picker = self.picker.saturating_sub(1);
+                self.picker_moved = true;
+            }
 
-                if cell_value.starts_with("=") {
-                    // self.enter_formula(self.active_cell.row, self.active_cell.col);
-                    self.enter_formula(self.active_cell.row, self.active_cell.col);
+            KeyCode::Down => {
+                if self.picker + 1 < self.suggestions().len() {
+                    self.picker += 1;
                 }
+                self.picker_moved = true;
             }
 
+            KeyCode::Enter if self.completes() => self.complete(),
+
             KeyCode::Backspace => {
+                self.picker = 0;
+                self.picker_moved = false;
                 self.cursor_pos -= 1;
                 self.cells[self.active_cell.row][self.active_cell.col]
                     .value
                     .remove(self.cursor_pos);
+                self.update_cell(self.active_cell.row, self.active_cell.col);
             }
 
             KeyCode::Left => {
@@ -214,8 +558,12 @@ impl Spreadsheet {
                 }
             }
 
+            KeyCode::Tab if self.completes() => self.complete(),
+
             KeyCode::Tab => {
                 self.cursor_pos = 0;
+                self.picker = 0;
+                self.picker_moved = false;
                 self.text_edit = false;
             }
             _ => (),
@@ -273,7 +621,7 @@ impl Spreadsheet {
                 AXIS_HEIGHT / 2,
             ))
             .unwrap();
-            print!("{}", (col as u8 + b'A') as char);
+            print!("{}", column_name(col));
             for line in 1..AXIS_HEIGHT {
                 out.execute(MoveTo(
                     col as u16 * self.cell_width as u16 + AXIS_WIDTH,
@@ -332,17 +680,44 @@ impl Spreadsheet {
         }
         self.mark_selection(out);
         self.draw_axis(out);
+        let (precedents, dependents) = self.traced_cells();
+        let references = self.reference_colors();
         for row in 0..rows {
             for col in 0..cols {
-                let content = self.cells[row][col].value.clone();
+                let traced = if precedents.iter().any(|area| area.contains((row, col))) {
+                    Some(PRECEDENT_COLOR)
+                } else if dependents.contains(&(row, col)) {
+                    Some(DEPENDENT_COLOR)
+                } else {
+                    None
+                };
+
+                // Cells an array spills into have no input, they show their value
+                let cell = &self.cells[row][col];
+                let content = if cell.value.is_empty() {
+                    cell.computed.to_string()
+                } else {
+                    cell.value.clone()
+                };
 
                 out.execute(MoveTo(
                     (col * self.cell_width + 1) as u16 + AXIS_WIDTH,
                     (row * self.cell_height + 1) as u16 + AXIS_HEIGHT,
                 ))
                 .unwrap();
-                if self.active_cell.row == row && self.active_cell.col == col {
+                if let Some(color) = traced.filter(|_| (row, col) != self.active()) {
+                    self.mark_cell(out, (row, col), color);
+                    out.execute(MoveTo(
+                        (col * self.cell_width + 1) as u16 + AXIS_WIDTH,
+                        (row * self.cell_height + 1) as u16 + AXIS_HEIGHT,
+                    ))
+                    .unwrap();
+                    out.execute(SetBackgroundColor(color)).unwrap();
+                    print!("{}", self.cells[row][col].computed.to_string().black());
+                    out.execute(ResetColor).unwrap();
+                } else if self.active_cell.row == row && self.active_cell.col == col {
                     let lines = content.lines().collect::<Vec<&str>>();
+                    let mut offset = 0;
 
                     for (i, line) in lines.iter().enumerate() {
                         out.execute(ResetColor);
@@ -354,9 +729,18 @@ impl Spreadsheet {
                         //out.execute(SetBackgroundColor(self.select_color));
                         //print!("\x1b[7m{}\x1b[0m", line);
                         out.execute(SetBackgroundColor(self.select_color));
-                        print!("{}", line.black());
+                        print_references(line, offset, &references);
+                        offset += line.len() + 1;
                         //print!("{}", line.with(Color::Blue))
                     }
+                } else if self.cells[row][col].formula {
+                    out.execute(ResetColor);
+                    match self.cells[row][col].computed.clone() {
+                        CellValue::Error(error) => {
+                            print!("{}", error.to_string().with(ERROR_COLOR))
+                        }
+                        value => print!("{}", value),
+                    }
                 } else {
                     out.execute(ResetColor);
                     let lines = content.lines().collect::<Vec<&str>>();
@@ -368,18 +752,15 @@ impl Spreadsheet {
                         ))
                         .unwrap();
                         let color = cell.color;
-                        if cell.formula {
-                            print!(
-                                "{}",
-                                self.enter_formula(row, col).unwrap_or("Error".to_string())
-                            );
-                        } else {
-                            print!("{}", line.with(color))
-                        }
+                        print!("{}", line.with(color))
                     }
                 }
             }
         }
+        self.draw_outlines(out, &references);
+        self.draw_status(out);
+        self.draw_picker(out);
+        self.draw_trace(out);
         // out.execute(terminal::Clear(ClearType::UntilNewLine))
         //     .unwrap();
         // for _ in 0..=self.cell_height - 1 {
@@ -394,27 +775,818 @@ impl Spreadsheet {
         //self.draw_options(out);
     }
 
+    // Explains the error in the active cell on the line above the command line
+    pub fn draw_status<W: Write>(&self, out: &mut W) {
+        let (_width, height) = terminal::size().unwrap();
+        out.execute(MoveTo(0, height - 2)).unwrap();
+        out.execute(ResetColor).unwrap();
+        out.execute(Clear(ClearType::CurrentLine)).unwrap();
+
+        if let Some(message) = &self.status_message {
+            print!("{}", message);
+        } else if let Some(pick) = &self.pick {
+            print!(
+                "Picking {}, Shift+arrows select a range",
+                area_name(Area::new(pick.anchor, pick.end))
+            );
+        } else if let CellValue::Error(error) = self.get_active_cell().computed {
+            print!(
+                "{}: {} {}",
+                cell_name((self.active_cell.row, self.active_cell.col)),
+                error.to_string().with(ERROR_COLOR),
+                error.description()
+            );
+            if let Some(cycle) = self.cycle_of((self.active_cell.row, self.active_cell.col)) {
+                print!(": {}", format_cycle(cycle));
+            }
+        }
+    }
+
     pub fn mark_selection<W: Write>(&self, out: &mut W) {
+        self.mark_cell(out, self.active(), self.select_color);
+    }
+
+    fn mark_cell<W: Write>(&self, out: &mut W, (row, col): Position, color: Color) {
         for line in 1..self.cell_height {
             out.execute(MoveTo(
-                ((self.active_cell.col * self.cell_width) + 1) as u16 + AXIS_WIDTH,
-                ((self.active_cell.row * self.cell_height) + line) as u16 + AXIS_HEIGHT,
+                ((col * self.cell_width) + 1) as u16 + AXIS_WIDTH,
+                ((row * self.cell_height) + line) as u16 + AXIS_HEIGHT,
             ))
             .unwrap();
-            out.execute(SetBackgroundColor(self.select_color));
-            println!(
-                "{}",
-                "x".repeat(self.cell_width - 1).with(self.select_color)
-            );
+            out.execute(SetBackgroundColor(color));
+            println!("{}", "x".repeat(self.cell_width - 1).with(color));
             out.execute(ResetColor);
         }
     }
 
+    fn active(&self) -> Position {
+        (self.active_cell.row, self.active_cell.col)
+    }
+
     pub fn set_value(&mut self, row: usize, col: usize, value: &str) {
         self.cells[row][col].value = value.to_string();
+        self.update_cell(row, col);
+    }
+
+    // Refresh the cached typed value after the raw input changed, then
+    // recalculate only the formulas depending on this cell
+    pub fn update_cell(&mut self, row: usize, col: usize) {
+        // Arrays spilling over the cell are blocked or freed by its input
+        let mut order = spill::anchors_over(&self.cells, (row, col));
+        if self.cells[row][col].value.starts_with("=") {
+            self.cells[row][col].formula = true;
+            self.register_formula(row, col);
+            order.push((row, col));
+        } else {
+            self.dependencies.remove((row, col));
+            if let Some(area) = spill::area(&self.cells, (row, col)) {
+                spill::clear(&mut self.cells, (row, col));
+                order.extend(self.spill_dependents(area));
+            }
+            let cell = &mut self.cells[row][col];
+            cell.formula = false;
+            cell.computed = CellValue::parse(&cell.value);
+        }
+
+        self.cycles = self.dependencies.find_cycles();
+        order.extend(self.dependencies.recalc_order((row, col)));
+        self.evaluate_in_order(order);
+    }
+
+    pub fn goto<W: Write>(&mut self, out: &mut W, reference: &str) {
+        match CellRef::parse(reference) {
+            Some(cell) if cell.row < self.cells.len() && cell.col < self.cells[0].len() => {
+                self.clear_prev(out);
+                self.active_cell = cell::ActiveCell::set(cell.row, cell.col);
+            }
+            _ => self.status_message = Some(format!("Can't go to {}", reference)),
+        }
+    }
+
+    pub fn copy(&mut self) {
+        let cell = (self.active_cell.row, self.active_cell.col);
+        self.clipboard = Some((cell, self.cells[cell.0][cell.1].value.clone()));
+        self.status_message = Some(format!("Copied {}", cell_name(cell)));
+    }
+
+    pub fn paste(&mut self) {
+        let Some((from, value)) = self.clipboard.clone() else {
+            self.status_message = Some("Nothing copied".to_string());
+            return;
+        };
+        self.copy_to(from, &value, (self.active_cell.row, self.active_cell.col));
+    }
+
+    // Copies the active cell into the next cells down (rows) or to the
+    // right (cols), stopping at the edge of the grid
+    pub fn fill(&mut self, rows: usize, cols: usize) {
+        let from = (self.active_cell.row, self.active_cell.col);
+        let value = self.cells[from.0][from.1].value.clone();
+        let end = (
+            (from.0 + rows).min(self.cells.len() - 1),
+            (from.1 + cols).min(self.cells[0].len() - 1),
+        );
+        for row in from.0..=end.0 {
+            for col in from.1..=end.1 {
+                if (row, col) != from {
+                    self.copy_to(from, &value, (row, col));
+                }
+            }
+        }
+    }
+
+    // Puts a copy of a cell's input into another cell. Formulas get their
+    // relative references moved as far as the cell moved, like Excel
+    fn copy_to(&mut self, from: Position, value: &str, to: Position) {
+        let rows = to.0 as isize - from.0 as isize;
+        let cols = to.1 as isize - from.1 as isize;
+        let value = shift_formula(value, rows, cols).unwrap_or_else(|| value.to_string());
+        self.set_value(to.0, to.1, &value);
     }
 
     pub fn enter_text(&mut self) {
         self.text_edit = true;
     }
+
+    pub fn define_name(&mut self, name: &str, definition: &str) -> Result<(), String> {
+        let definition = definition.strip_prefix("=").unwrap_or(definition);
+        if self.parse_formula(&format!("={}", definition)).is_none() {
+            return Err(format!("Can't parse {}", definition));
+        }
+
+        let name = Name {
+            name: name.to_string(),
+            sheet: self.id.clone(),
+            definition: definition.to_string(),
+        };
+        self.names.define(name.clone())?;
+        self.database.write_name(&name).unwrap();
+        self.recalculate_all();
+        Ok(())
+    }
+
+    // Renames a name everywhere it's used, in the formulas of every sheet and
+    // the definitions of other names, and saves all of that together
+    pub fn rename_name(&mut self, old: &str, new: &str) -> Result<(), String> {
+        let old = self
+            .names
+            .get(old)
+            .ok_or(format!("{} isn't defined", old))?;
+        let old = old.name.clone();
+        let mut names = vec![self.names.rename(&old, new)?];
+
+        let others: Vec<Name> = self.names.list().cloned().collect();
+        for name in others {
+            let definition = rename_in_formula(&name.definition, &old, new, &self.functions);
+            if definition != name.definition {
+                let name = Name { definition, ..name };
+                self.names.define(name.clone())?;
+                names.push(name);
+            }
+        }
+
+        // Formulas of the open sheet as they are now, the others as saved
+        let mut formulas = Vec::new();
+        for (row, cells) in self.cells.iter_mut().enumerate() {
+            for (col, cell) in cells.iter_mut().enumerate() {
+                let formula = rename_in_formula(&cell.value, &old, new, &self.functions);
+                if cell.value.starts_with("=") && formula != cell.value {
+                    cell.value = formula.clone();
+                    formulas.push((self.id.clone(), format!("{}:{}", row, col), formula));
+                }
+            }
+        }
+        for sheet in self.database.get_sheets().unwrap_or_default() {
+            if sheet.name == self.id {
+                continue;
+            }
+            let cells = self
+                .database
+                .get_cells(&format!("spreadsheet:{}", sheet.name))
+                .unwrap_or_default();
+            for (key, value) in cells {
+                let formula = rename_in_formula(&value, &old, new, &self.functions);
+                if value.starts_with("=") && formula != value {
+                    formulas.push((sheet.name.clone(), key, formula));
+                }
+            }
+        }
+        self.database.rename_name(&old, &names, &formulas).unwrap();
+
+        self.sheets.clear();
+        self.recalculate_all();
+        Ok(())
+    }
+
+    pub fn delete_name(&mut self, name: &str) -> Result<(), String> {
+        let name = self.names.delete(name)?;
+        self.database.delete_name(&name.name).unwrap();
+        self.recalculate_all();
+        Ok(())
+    }
+
+    // Start and text of the name being typed before the cursor of a formula
+    fn name_prefix(&self) -> Option<(usize, &str)> {
+        let value = &self.cells[self.active_cell.row][self.active_cell.col].value;
+        if !self.text_edit || !value.starts_with("=") || !value.is_char_boundary(self.cursor_pos) {
+            return None;
+        }
+
+        let before = &value[..self.cursor_pos];
+        let start = before
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .map_or(0, |i| i + 1);
+        let prefix = &before[start..];
+        let starts_well = prefix
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || c == '_');
+        // Sheet2!Rev is a reference on another sheet, not a name
+        (starts_well && !before[..start].ends_with('!')).then_some((start, prefix))
+    }
+
+    // Names, then functions, the picker offers for what's being typed
+    pub fn suggestions(&self) -> Vec<Suggestion> {
+        let Some((_, prefix)) = self.name_prefix() else {
+            return Vec::new();
+        };
+
+        let mut suggestions: Vec<Suggestion> = self
+            .names
+            .matching(prefix)
+            .into_iter()
+            .map(|name| Suggestion {
+                insert: name.name.clone(),
+                label: format!("{} = {}", name.name, name.definition),
+            })
+            .collect();
+
+        let mut functions: Vec<&str> = self
+            .functions
+            .names()
+            .chain(["LET", "LAMBDA"])
+            .filter(|function| {
+                function
+                    .get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+            })
+            .collect();
+        functions.sort();
+        for function in functions {
+            let params = self.signature(function).unwrap_or_default();
+            suggestions.push(Suggestion {
+                insert: format!("{}(", function),
+                label: format!("{}({})", function, params.join(", ")),
+            });
+        }
+        suggestions
+    }
+
+    // Whether Tab and Enter insert the picker's entry. Not when the name is
+    // already typed in full, like Rate for =A1*Rate, unless Up/Down chose one
+    fn completes(&self) -> bool {
+        let Some((_, prefix)) = self.name_prefix() else {
+            return false;
+        };
+        let suggestions = self.suggestions();
+        let typed = suggestions.iter().any(|suggestion| {
+            let name = suggestion.insert.trim_end_matches('(');
+            name.eq_ignore_ascii_case(prefix)
+        });
+        !suggestions.is_empty() && (self.picker_moved || !typed)
+    }
+
+    // Replaces the typed prefix with the entry selected in the picker
+    fn complete(&mut self) {
+        let Some(insert) = self
+            .suggestions()
+            .into_iter()
+            .nth(self.picker)
+            .map(|suggestion| suggestion.insert)
+        else {
+            return;
+        };
+        let (start, prefix) = self.name_prefix().unwrap();
+        let end = start + prefix.len();
+
+        let (row, col) = self.active();
+        let value = &mut self.cells[row][col].value;
+        let (insert, skip) = match insert.strip_suffix('(') {
+            // A function keeps the parenthesis already typed after it
+            Some(function) if value[end..].starts_with('(') => (function.to_string(), 1),
+            _ => (insert, 0),
+        };
+        value.replace_range(start..end, &insert);
+        self.cursor_pos = start + insert.len() + skip;
+        self.picker = 0;
+        self.picker_moved = false;
+        self.update_cell(row, col);
+    }
+
+    // Parameters of a function or a name defined as a LAMBDA, for the hint
+    // shown while typing a call to it
+    fn signature(&self, name: &str) -> Option<Vec<String>> {
+        let special: &[&str] = match name.to_ascii_uppercase().as_str() {
+            "LET" => &["name1", "name_value1", "calculation_or_name2", "..."],
+            "LAMBDA" => &["[parameter1]", "...", "calculation"],
+            _ => &[],
+        };
+        if !special.is_empty() {
+            return Some(special.iter().map(|param| param.to_string()).collect());
+        }
+        if let Some(function) = self.functions.get(name) {
+            return Some(function.signature());
+        }
+
+        let definition = &self.names.get(name)?.definition;
+        match self.parse_formula(&format!("={}", definition))? {
+            Expr::Lambda(params, _) => Some(params),
+            _ => None,
+        }
+    }
+
+    // Function call the cursor is in, its parameters and the one being typed
+    fn signature_hint(&self) -> Option<(String, Vec<String>, usize)> {
+        let value = &self.cells[self.active_cell.row][self.active_cell.col].value;
+        if !self.text_edit || !value.starts_with("=") {
+            return None;
+        }
+        let (name, arg) = editing::call_at(value, self.cursor_pos)?;
+        let params = self.signature(name)?;
+        let current = arg.min(params.len().saturating_sub(1));
+        Some((name.to_ascii_uppercase(), params, current))
+    }
+
+    // References in the formula being typed, each with its colour. The same
+    // reference written twice gets the same colour
+    fn reference_colors(&self) -> Vec<(ReferenceSpan, Color)> {
+        let value = &self.cells[self.active_cell.row][self.active_cell.col].value;
+        if !self.text_edit || !value.starts_with("=") {
+            return Vec::new();
+        }
+
+        let mut seen: Vec<String> = Vec::new();
+        editing::reference_spans(value)
+            .into_iter()
+            .map(|span| {
+                let text = value[span.range.clone()].replace('$', "").to_uppercase();
+                let index = match seen.iter().position(|other| *other == text) {
+                    Some(index) => index,
+                    None => {
+                        seen.push(text);
+                        seen.len() - 1
+                    }
+                };
+                (span, REFERENCE_COLORS[index % REFERENCE_COLORS.len()])
+            })
+            .collect()
+    }
+
+    // Outlines the cells the references being typed point at in their colours
+    fn draw_outlines<W: Write>(&self, out: &mut W, references: &[(ReferenceSpan, Color)]) {
+        let grid = Area::new((0, 0), (self.cells.len() - 1, self.cells[0].len() - 1));
+        for (span, color) in references {
+            let other_sheet = span
+                .sheet
+                .as_ref()
+                .is_some_and(|sheet| !sheet.eq_ignore_ascii_case(&self.id));
+            let Some(area) = span.area.intersect(&grid).filter(|_| !other_sheet) else {
+                continue;
+            };
+
+            let left = (area.start.1 * self.cell_width) as u16 + AXIS_WIDTH;
+            let right = ((area.end.1 + 1) * self.cell_width) as u16 + AXIS_WIDTH;
+            let top = (area.start.0 * self.cell_height) as u16 + AXIS_HEIGHT;
+            let bottom = ((area.end.0 + 1) * self.cell_height) as u16 + AXIS_HEIGHT;
+            out.execute(ResetColor).unwrap();
+            for y in [top, bottom] {
+                out.execute(MoveTo(left, y)).unwrap();
+                let edge = format!("+{}+", "-".repeat((right - left - 1) as usize));
+                print!("{}", edge.with(*color));
+            }
+            for x in [left, right] {
+                for y in top + 1..bottom {
+                    out.execute(MoveTo(x, y)).unwrap();
+                    print!("{}", "|".with(*color));
+                }
+            }
+        }
+    }
+
+    fn trace_evaluator(&self) -> Evaluator<'_> {
+        Evaluator::new(&self.cells, self.numeric_mode)
+            .with_clock(self.clock.as_ref())
+            .with_workbook(self.workbook())
+            .with_functions(&self.functions)
+            .with_names(&self.names)
+    }
+
+    // Areas the traced formula reads on this sheet and the formulas reading it
+    fn traced_cells(&self) -> (Vec<Area>, Vec<Position>) {
+        if !self.tracing {
+            return (Vec::new(), Vec::new());
+        }
+        let cell = self.active();
+        (
+            self.dependencies.precedents(cell).to_vec(),
+            self.dependencies.dependents(cell),
+        )
+    }
+
+    // What :trace shows for the active cell: its formula broken into parts
+    // with their values, the cells it reads and the formulas reading it
+    pub fn trace_lines(&self) -> Vec<String> {
+        let cell = self.active();
+        let value = &self.cells[cell.0][cell.1].value;
+        let Some(expr) = self.parse_formula(value) else {
+            return vec![format!(
+                "{} = {}, not a formula",
+                cell_name(cell),
+                trace::show(&self.cells[cell.0][cell.1].computed)
+            )];
+        };
+
+        let mut lines = Trace::new(&self.trace_evaluator(), &expr).lines();
+        lines[0] = format!("{} = {}", cell_name(cell), lines[0]);
+
+        let mut precedents = Vec::new();
+        for expr in self.names.with_called(&expr, &self.functions) {
+            precedents.extend(expr.references().into_iter().map(area_name));
+            for (sheet, area) in expr.sheet_references() {
+                precedents.push(format!("{}!{}", sheet, area_name(area)));
+            }
+        }
+        let dependents: Vec<String> = self
+            .dependencies
+            .dependents(cell)
+            .into_iter()
+            .map(cell_name)
+            .collect();
+        let list = |cells: Vec<String>| match cells.is_empty() {
+            true => "none".to_string(),
+            false => cells.join(", "),
+        };
+        lines.push(format!("Precedents: {}", list(precedents)));
+        lines.push(format!("Dependents: {}", list(dependents)));
+        lines
+    }
+
+    // The trace below the active cell, or above it when there's no room
+    fn draw_trace<W: Write>(&self, out: &mut W) {
+        if !self.tracing || self.text_edit {
+            return;
+        }
+        let (width, height) = terminal::size().unwrap();
+        let bottom = height.saturating_sub(STATUS_HEIGHT);
+        let lines = self.trace_lines();
+        let x = (self.active_cell.col * self.cell_width) as u16 + AXIS_WIDTH;
+        let below = ((self.active_cell.row + 1) * self.cell_height) as u16 + AXIS_HEIGHT + 1;
+        let y = if below + lines.len() as u16 <= bottom {
+            below
+        } else {
+            bottom.saturating_sub(lines.len() as u16).max(AXIS_HEIGHT)
+        };
+        let max_width = width.saturating_sub(x) as usize;
+
+        for (i, line) in lines.iter().enumerate().take((bottom - y) as usize) {
+            out.execute(MoveTo(x, y + i as u16)).unwrap();
+            out.execute(ResetColor).unwrap();
+            let line: String = format!(" {} ", line).chars().take(max_width).collect();
+            print!("{}", line.on_dark_grey());
+        }
+    }
+
+    // The hint for the call being typed and the matching names and functions,
+    // below the cell being edited
+    fn draw_picker<W: Write>(&self, out: &mut W) {
+        let x = (self.active_cell.col * self.cell_width) as u16 + AXIS_WIDTH;
+        let mut y = ((self.active_cell.row + 1) * self.cell_height) as u16 + AXIS_HEIGHT + 1;
+
+        if let Some((name, params, current)) = self.signature_hint() {
+            out.execute(MoveTo(x, y)).unwrap();
+            out.execute(ResetColor).unwrap();
+            print!("{}", format!(" {}(", name).on_dark_grey());
+            for (i, param) in params.iter().enumerate() {
+                if i > 0 {
+                    print!("{}", ", ".on_dark_grey());
+                }
+                if i == current {
+                    print!("{}", param.as_str().on_dark_grey().bold().underlined());
+                } else {
+                    print!("{}", param.as_str().on_dark_grey());
+                }
+            }
+            print!("{}", ") ".on_dark_grey());
+            y += 1;
+        }
+
+        // The list scrolls to keep the selected entry in view
+        let first = self.picker.saturating_sub(PICKER_SIZE - 1);
+        let suggestions = self.suggestions();
+        for (i, suggestion) in suggestions.iter().enumerate().skip(first).take(PICKER_SIZE) {
+            out.execute(MoveTo(x, y + (i - first) as u16)).unwrap();
+            out.execute(ResetColor).unwrap();
+            let entry = format!(" {} ", suggestion.label);
+            if i == self.picker {
+                print!("{}", entry.on_grey().black());
+            } else {
+                print!("{}", entry.on_dark_grey());
+            }
+        }
+    }
+}
+
+// Prints a line of the formula being edited with its references in their
+// colours, offset is where the line starts in the formula
+fn print_references(line: &str, offset: usize, references: &[(ReferenceSpan, Color)]) {
+    let color_at = |i: usize| {
+        references
+            .iter()
+            .find(|(span, _)| span.range.contains(&(offset + i)))
+            .map(|(_, color)| *color)
+    };
+
+    let mut start = 0;
+    for (i, _) in line.char_indices().skip(1).chain([(line.len(), ' ')]) {
+        if i < line.len() && color_at(i) == color_at(start) {
+            continue;
+        }
+        match color_at(start) {
+            Some(color) => print!("{}", line[start..i].with(color)),
+            None => print!("{}", line[start..i].black()),
+        }
+        start = i;
+    }
+}
+
+// Parenthesis the rename is in. LET and LAMBDA calls declare variables,
+// each with the argument it can be used from
+struct Scope {
+    binder: Option<Binder>,
+    arg: usize,
+    variables: Vec<(String, usize)>,
+}
+
+enum Binder {
+    Let,
+    Lambda,
+}
+
+// Replaces whole identifiers outside text literals and quoted sheet names,
+// so renaming Rate leaves Rates, RATE(, "Rate" and 'Rate'!A1 alone, and
+// LET variables and LAMBDA parameters called Rate too. Calls like Tax(A1)
+// are renamed when they call the name, i.e. there's no function called that
+pub fn rename_in_formula(
+    formula: &str,
+    old: &str,
+    new: &str,
+    functions: &FunctionRegistry,
+) -> String {
+    let calls_name = functions.get(old).is_none();
+    let mut result = String::new();
+    let mut chars = formula.char_indices().peekable();
+    let mut quote = None;
+    let mut scopes: Vec<Scope> = Vec::new();
+    let mut last_ident = "";
+
+    while let Some((start, c)) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => {
+                let binder = match last_ident.to_uppercase().as_str() {
+                    "LET" => Some(Binder::Let),
+                    "LAMBDA" => Some(Binder::Lambda),
+                    _ => None,
+                };
+                scopes.push(Scope {
+                    binder,
+                    arg: 0,
+                    variables: Vec::new(),
+                });
+            }
+            (None, ')') => {
+                scopes.pop();
+            }
+            (None, ',') => {
+                if let Some(scope) = scopes.last_mut() {
+                    scope.arg += 1;
+                }
+            }
+            _ => {}
+        }
+        if quote.is_some() || !(c.is_alphabetic() || c == '_') {
+            if !c.is_whitespace() {
+                last_ident = "";
+            }
+            result.push(c);
+            continue;
+        }
+
+        let mut end = start + c.len_utf8();
+        while let Some(&(i, c)) = chars.peek() {
+            if !(c.is_alphanumeric() || c == '_' || c == '.') {
+                break;
+            }
+            end = i + c.len_utf8();
+            chars.next();
+        }
+        let ident = &formula[start..end];
+        last_ident = ident;
+
+        // A variable where it's declared, a whole argument before a comma
+        let declares = formula[..start].trim_end().ends_with(['(', ','])
+            && formula[end..].trim_start().starts_with(',');
+        if let Some(scope) = scopes.last_mut().filter(|_| declares) {
+            let visible_from = match scope.binder {
+                Some(Binder::Let) if scope.arg % 2 == 0 => Some(scope.arg + 2),
+                Some(Binder::Lambda) => Some(scope.arg + 1),
+                _ => None,
+            };
+            if let Some(visible_from) = visible_from {
+                scope.variables.push((ident.to_string(), visible_from));
+                result.push_str(ident);
+                continue;
+            }
+        }
+
+        let is_variable = scopes.iter().any(|scope| {
+            scope
+                .variables
+                .iter()
+                .any(|(name, from)| scope.arg >= *from && name.eq_ignore_ascii_case(ident))
+        });
+        let is_function = formula[end..].trim_start().starts_with('(') && !calls_name;
+        let is_sheet = formula[end..].starts_with('!') || formula[..start].ends_with('!');
+        if ident.eq_ignore_ascii_case(old) && !is_function && !is_sheet && !is_variable {
+            result.push_str(new);
+        } else {
+            result.push_str(ident);
+        }
+    }
+    result
+}
+
+// Areas a formula and the lambdas it calls read on its own sheet, including
+// references naming the sheet like Profits!A1 in Profits
+fn local_references(exprs: &[&Expr], sheet: &str) -> Vec<Area> {
+    let mut references = Vec::new();
+    for expr in exprs {
+        references.extend(expr.references());
+        for (name, area) in expr.sheet_references() {
+            if name.eq_ignore_ascii_case(sheet) {
+                references.push(area);
+            }
+        }
+    }
+    references
+}
+
+// How much a value moved between two iterations of a circular calculation
+fn value_change(before: &CellValue, after: &CellValue) -> f64 {
+    match (before.as_number(), after.as_number()) {
+        _ if before == after => 0.0,
+        (Some(before), Some(after)) => (after - before).abs(),
+        _ => f64::INFINITY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rename_names_in_formulas() {
+        let functions = FunctionRegistry::builtins();
+        let rename = |formula, old, new| rename_in_formula(formula, old, new, functions);
+
+        assert_eq!(
+            rename("=SUM(Revenue)*VAT", "vat", "Tax"),
+            "=SUM(Revenue)*Tax"
+        );
+        assert_eq!(
+            rename("=Rates+Rate&\"Rate\"", "Rate", "R"),
+            "=Rates+R&\"Rate\""
+        );
+        assert_eq!(rename("=Rate!A1+Rate", "Rate", "R"), "=Rate!A1+R");
+        // Lambdas are called by name, functions keep theirs
+        assert_eq!(rename("=Tax(A1)+tax (2)", "Tax", "Vat"), "=Vat(A1)+Vat (2)");
+        assert_eq!(
+            rename("=RATE(48, -200, 8000)*Rate", "Rate", "R"),
+            "=RATE(48, -200, 8000)*R"
+        );
+        // Quoted sheet names are left alone, '' is a quote inside one
+        assert_eq!(
+            rename("='Rate'!A1+'My Rate'!B2+Rate", "Rate", "R"),
+            "='Rate'!A1+'My Rate'!B2+R"
+        );
+        assert_eq!(
+            rename("='It''s Rate'!A1*Rate", "Rate", "R"),
+            "='It''s Rate'!A1*R"
+        );
+    }
+
+    #[test]
+    fn rename_skips_variables() {
+        let functions = FunctionRegistry::builtins();
+        let rename = |formula| rename_in_formula(formula, "Rate", "R", functions);
+
+        assert_eq!(
+            rename("=LET(Rate, 2, Rate*A1)+Rate"),
+            "=LET(Rate, 2, Rate*A1)+R"
+        );
+        // A variable can't be used in its own value, that's still the name
+        assert_eq!(
+            rename("=LET(Rate, Rate*2, x, Rate, x)"),
+            "=LET(Rate, R*2, x, Rate, x)"
+        );
+        assert_eq!(
+            rename("=LET(x, 1, Rate, x, Rate)"),
+            "=LET(x, 1, Rate, x, Rate)"
+        );
+        assert_eq!(
+            rename("=LAMBDA(rate, n, rate*n)(Rate, 2)"),
+            "=LAMBDA(rate, n, rate*n)(R, 2)"
+        );
+        assert_eq!(
+            rename("=LET(f, LAMBDA(x, x*Rate), f(Rate))"),
+            "=LET(f, LAMBDA(x, x*R), f(R))"
+        );
+        // Variables end with their LET
+        assert_eq!(
+            rename("=SUM(LET(Rate, 1, Rate), Rate)"),
+            "=SUM(LET(Rate, 1, Rate), R)"
+        );
+        assert_eq!(rename("=let (Rate, 1, Rate)"), "=let (Rate, 1, Rate)");
+    }
+
+    // Sheet of 6x6 cells typing the formula into C3, | marks the cursor
+    fn typing(formula: &str) -> Spreadsheet {
+        let mut sheet = Spreadsheet::with_cells(
+            vec![vec![cell::Cell::empty(); 6]; 6],
+            10,
+            1,
+            Database::offline(),
+        );
+        sheet.set_value(3, 2, "5");
+        sheet.active_cell = cell::ActiveCell::set(2, 2);
+        sheet.text_edit = true;
+        sheet.cursor_pos = formula.find('|').unwrap();
+        sheet.set_value(2, 2, &formula.replacen('|', "", 1));
+        sheet
+    }
+
+    // The formula with | at the cursor
+    fn typed(sheet: &Spreadsheet) -> String {
+        let mut formula = sheet.cells[2][2].value.clone();
+        formula.insert(sheet.cursor_pos, '|');
+        formula
+    }
+
+    #[test]
+    fn pick_references() {
+        let mut sheet = typing("=1+|");
+        assert!(sheet.pick_reference(KeyCode::Down, false));
+        assert_eq!(typed(&sheet), "=1+C4|");
+        assert_eq!(sheet.cells[2][2].computed, CellValue::Number(6.0));
+        assert!(sheet.pick_reference(KeyCode::Right, false));
+        assert_eq!(typed(&sheet), "=1+D4|");
+        // Shift stretches the reference into a range from where it was
+        assert!(sheet.pick_reference(KeyCode::Down, true));
+        assert_eq!(typed(&sheet), "=1+D4:D5|");
+        assert!(sheet.pick_reference(KeyCode::Left, true));
+        assert!(sheet.pick_reference(KeyCode::Left, true));
+        assert_eq!(typed(&sheet), "=1+B4:D5|");
+        // Without shift it's a single cell again, stopping at the edge
+        for _ in 0..9 {
+            assert!(sheet.pick_reference(KeyCode::Down, false));
+        }
+        assert_eq!(typed(&sheet), "=1+B6|");
+
+        // The picked reference replaces the one picked before it, not the rest
+        let mut sheet = typing("=SUM(|)*2");
+        assert!(sheet.pick_reference(KeyCode::Up, false));
+        assert_eq!(typed(&sheet), "=SUM(C2|)*2");
+        assert!(sheet.pick_reference(KeyCode::Up, false));
+        assert_eq!(typed(&sheet), "=SUM(C1|)*2");
+        assert!(sheet.pick_reference(KeyCode::Up, false));
+        assert_eq!(typed(&sheet), "=SUM(C1|)*2");
+
+        // Any other key ends the pick, the arrows then move the cursor again
+        assert!(!sheet.pick_reference(KeyCode::Char(','), false));
+        assert!(sheet.pick.is_none());
+        assert!(!sheet.pick_reference(KeyCode::Down, false));
+        assert_eq!(typed(&sheet), "=SUM(C1|)*2");
+    }
+
+    #[test]
+    fn no_pick_where_references_cant_go() {
+        for formula in ["=SUM(A1|", "=A1|+1", "=\"(|\"", "5+|", "=SU|M("] {
+            let mut sheet = typing(formula);
+            assert!(!sheet.pick_reference(KeyCode::Down, false), "{}", formula);
+            assert!(sheet.pick.is_none());
+            assert_eq!(typed(&sheet), formula);
+        }
+    }
 }
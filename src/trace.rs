@@ -0,0 +1,168 @@
+use crate::cell::CellValue;
+use crate::formulas::{Evaluator, Expr};
+
+// Arrays with more values than this show their size instead
+const MAX_SHOWN_VALUES: usize = 12;
+
+// A formula broken into its parts with the value of each, for :trace
+pub struct Trace {
+    pub text: String,
+    pub value: CellValue,
+    pub children: Vec<Trace>,
+}
+
+impl Trace {
+    pub fn new(evaluator: &Evaluator, expr: &Expr) -> Self {
+        let children = match expr {
+            Expr::Negate(inner) | Expr::Percent(inner) => parts(evaluator, [&**inner]),
+            Expr::Binary(_, left, right)
+            | Expr::Compare(_, left, right)
+            | Expr::Concat(left, right)
+            | Expr::Intersect(left, right) => parts(evaluator, [&**left, &**right]),
+            Expr::Call(_, args) => parts(evaluator, args),
+            Expr::Apply(callee, args) => parts(evaluator, std::iter::once(&**callee).chain(args)),
+            // Variables are traced with the ones before them in scope
+            Expr::Let(bindings, body) => {
+                let mut locals = evaluator.locals().to_vec();
+                let mut children = Vec::new();
+                for (name, value) in bindings {
+                    let mut trace = Trace::new(&evaluator.scope(&locals), value);
+                    trace.text = format!("{} = {}", name, trace.text);
+                    locals.push((name.clone(), trace.value.clone()));
+                    children.push(trace);
+                }
+                children.push(Trace::new(&evaluator.scope(&locals), body));
+                children
+            }
+            _ => Vec::new(),
+        };
+
+        Self {
+            text: expr.to_string(),
+            value: evaluator.eval(expr),
+            children,
+        }
+    }
+
+    // The tree as lines, each part indented under the one using it
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        self.push_lines(0, &mut lines);
+        lines
+    }
+
+    fn push_lines(&self, depth: usize, lines: &mut Vec<String>) {
+        lines.push(format!(
+            "{}{} → {}",
+            "  ".repeat(depth),
+            self.text,
+            show(&self.value)
+        ));
+        for child in &self.children {
+            child.push_lines(depth + 1, lines);
+        }
+    }
+}
+
+// Traces of the parts, leaving out numbers and text that are their own value
+fn parts<'e>(evaluator: &Evaluator, exprs: impl IntoIterator<Item = &'e Expr>) -> Vec<Trace> {
+    exprs
+        .into_iter()
+        .filter(|expr| !matches!(expr, Expr::Number(_) | Expr::Text(_) | Expr::Bool(_)))
+        .map(|expr| Trace::new(evaluator, expr))
+        .collect()
+}
+
+// Values like in a formula, so text is quoted and arrays show their elements
+pub fn show(value: &CellValue) -> String {
+    match value {
+        CellValue::Array(rows) if rows.iter().map(Vec::len).sum::<usize>() > MAX_SHOWN_VALUES => {
+            format!("{}x{} array", rows.len(), rows[0].len())
+        }
+        CellValue::Array(rows) => {
+            let rows: Vec<String> = rows
+                .iter()
+                .map(|row| row.iter().map(show).collect::<Vec<String>>().join(","))
+                .collect();
+            format!("{{{}}}", rows.join(";"))
+        }
+        CellValue::Text(text) => format!("\"{}\"", text),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::Cell;
+    use crate::formulas::{tokenize, FormulaError, NumericMode, Parser};
+
+    #[test]
+    fn nested_formula() {
+        let cells: Vec<Vec<Cell>> = [["3", ""], ["4", "x"]]
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|value| Cell {
+                        value: value.to_string(),
+                        computed: CellValue::parse(value),
+                        ..Cell::empty()
+                    })
+                    .collect()
+            })
+            .collect();
+        let expr = Parser::new(tokenize("SUM(A1:A2)*2+IFERROR(A1/B1, LEN(B2))").unwrap())
+            .parse()
+            .unwrap();
+        let trace = Trace::new(&Evaluator::new(&cells, NumericMode::Float), &expr);
+
+        assert_eq!(trace.value, CellValue::Number(15.0));
+        let [product, caught] = &trace.children[..] else {
+            panic!("two parts expected");
+        };
+        assert_eq!(product.text, "SUM(A1:A2)*2");
+        assert_eq!(product.value, CellValue::Number(14.0));
+        // The 2 is its own value, so only the sum is a part
+        assert_eq!(product.children.len(), 1);
+        assert_eq!(product.children[0].value, CellValue::Number(7.0));
+
+        // The error inside IFERROR keeps its own value
+        assert_eq!(caught.value, CellValue::Number(1.0));
+        let division = &caught.children[0];
+        assert_eq!(division.text, "A1/B1");
+        assert_eq!(division.value, CellValue::Error(FormulaError::DivZero));
+        assert_eq!(division.children[1].value, CellValue::Empty);
+
+        assert_eq!(
+            trace.lines(),
+            [
+                "SUM(A1:A2)*2+IFERROR(A1/B1,LEN(B2)) → 15",
+                "  SUM(A1:A2)*2 → 14",
+                "    SUM(A1:A2) → 7",
+                "      A1:A2 → {3;4}",
+                "  IFERROR(A1/B1,LEN(B2)) → 1",
+                "    A1/B1 → #DIV/0!",
+                "      A1 → 3",
+                "      B1 → ",
+                "    LEN(B2) → 1",
+                "      B2 → \"x\"",
+            ]
+        );
+
+        // LET variables are traced with the value they got
+        let expr = Parser::new(tokenize("LET(x, A1*2, x+1)").unwrap())
+            .parse()
+            .unwrap();
+        let trace = Trace::new(&Evaluator::new(&cells, NumericMode::Float), &expr);
+        assert_eq!(
+            trace.lines(),
+            [
+                "LET(x,A1*2,x+1) → 7",
+                "  x = A1*2 → 6",
+                "    A1 → 3",
+                "  x+1 → 7",
+                "    x → 6"
+            ]
+        );
+    }
+}